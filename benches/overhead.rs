@@ -0,0 +1,85 @@
+//! Measures what the instrumentation costs compared to `std::sync`.
+//! Run with `cargo bench`, or `cargo bench -- <filter>` to select benchmarks by name.
+
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion};
+use no_deadlocks::lock_manager::LockManager;
+use no_deadlocks::Mutex;
+
+/// Lock/unlock cycles on a mutex no other thread touches.
+fn uncontended(c: &mut Criterion) {
+    let mut group = c.benchmark_group("uncontended");
+    let std_mutex = std::sync::Mutex::new(0u64);
+    group.bench_function("std", |b| {
+        b.iter(|| *std_mutex.lock().unwrap() += 1);
+    });
+    let mutex = Mutex::with_manager(Arc::new(LockManager::new()), 0u64);
+    group.bench_function("no_deadlocks", |b| {
+        b.iter(|| *mutex.lock().unwrap() += 1);
+    });
+    group.finish();
+}
+
+/// Two threads incrementing a shared counter, `iters` times each.
+fn contended(c: &mut Criterion) {
+    fn run<L: Send + Sync + 'static>(lock: Arc<L>, iters: u64, increment: fn(&L)) -> Duration {
+        let start = Instant::now();
+        let threads: Vec<_> = (0..2)
+            .map(|_| {
+                let lock = lock.clone();
+                std::thread::spawn(move || {
+                    for _ in 0..iters {
+                        increment(&lock);
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        start.elapsed()
+    }
+    let mut group = c.benchmark_group("contended");
+    group.bench_function("std", |b| {
+        b.iter_custom(|iters| {
+            run(Arc::new(std::sync::Mutex::new(0u64)), iters, |lock| {
+                *lock.lock().unwrap() += 1
+            })
+        });
+    });
+    group.bench_function("no_deadlocks", |b| {
+        b.iter_custom(|iters| {
+            let manager = Arc::new(LockManager::new());
+            run(
+                Arc::new(Mutex::with_manager(manager, 0u64)),
+                iters,
+                |lock| *lock.lock().unwrap() += 1,
+            )
+        });
+    });
+    group.finish();
+}
+
+/// One analysis of a graph where the current thread holds `count` registered locks.
+fn analysis(c: &mut Criterion) {
+    let mut group = c.benchmark_group("analysis");
+    for count in [10usize, 100, 1000].iter() {
+        let manager = Arc::new(LockManager::new());
+        // Registers every lock on its first acquisition, as contention would.
+        manager.enable_lock_order_recording();
+        let mutexes: Vec<_> = (0..*count)
+            .map(|_| Mutex::with_manager(manager.clone(), ()))
+            .collect();
+        let guards: Vec<_> = mutexes.iter().map(|mutex| mutex.lock()).collect();
+        group.bench_with_input(BenchmarkId::from_parameter(count), count, |b, _| {
+            b.iter(|| black_box(manager.find_any_deadlock()));
+        });
+        std::mem::drop(guards);
+    }
+    group.finish();
+}
+
+criterion_group!(benches, uncontended, contended, analysis);
+criterion_main!(benches);
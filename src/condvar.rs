@@ -1,25 +1,81 @@
 use std::{
-    sync::{LockResult, PoisonError, WaitTimeoutResult},
-    time::Duration,
+    sync::{Arc, LockResult, PoisonError, WaitTimeoutResult, Weak},
+    time::{Duration, Instant},
 };
 
-use crate::MutexGuard;
+use crate::lock_manager::RepresentationCell;
+use crate::{Mutex, MutexGuard};
 
 #[derive(Default)]
 pub struct Condvar {
     condvar: std::sync::Condvar,
     mutex: std::sync::Mutex<()>,
+    /// The lock this condition variable is meant for, see `Condvar::new_for`.
+    paired: Option<Weak<RepresentationCell>>,
 }
 impl Condvar {
     pub fn new() -> Self {
         Self::default()
     }
+    /// A condition variable meant to be waited on with `mutex` only: in debug builds,
+    /// waiting with a guard of any other lock panics, as notifications would then be missed.
+    pub fn new_for<T: ?Sized>(mutex: &Mutex<T>) -> Self {
+        Condvar {
+            paired: Some(Arc::downgrade(mutex.representation())),
+            ..Self::default()
+        }
+    }
+    /// Panics if `guard` belongs to another mutex than the paired one, releasing it first so that it isn't poisoned.
+    fn check_pairing<'l, T: ?Sized>(&self, guard: MutexGuard<'l, T>) -> MutexGuard<'l, T> {
+        let paired = match &self.paired {
+            Some(paired) if cfg!(debug_assertions) => paired,
+            _ => return guard,
+        };
+        let mutex = guard.mutex();
+        if paired.as_ptr() != Arc::as_ptr(mutex.representation()) {
+            std::mem::drop(guard);
+            panic!(
+                "CONDVAR MISUSE: waiting with a guard of {}, but this Condvar was created for another mutex with `Condvar::new_for`. Waiters on different mutexes miss each other's notifications.",
+                match (mutex.name(), mutex.key()) {
+                    (Some(name), _) => format!("'{}'", name),
+                    (None, Some(key)) => format!("lock {}", key),
+                    (None, None) => "a mutex".to_owned(),
+                }
+            );
+        }
+        guard
+    }
+    /// The internal mutex is taken before `guard` is released, and notifications take it too:
+    /// a notification sent after the caller's lock was released can't be missed.
+    fn internal_lock(&self) -> std::sync::MutexGuard<'_, ()> {
+        self.mutex.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+    /// Panics if the wait lasts longer than the manager's analysis timeout while no other thread ever used
+    /// the manager's locks: it's then likely that no other thread knows about this condition variable either.
     pub fn wait<'l, T>(&self, guard: MutexGuard<'l, T>) -> LockResult<MutexGuard<'l, T>> {
-        let mutex = guard.unlock();
-        #[allow(unused_must_use)]
-        {
-            self.condvar.wait(self.mutex.lock().unwrap());
+        let guard = self.check_pairing(guard);
+        let manager = guard.manager();
+        let mut internal = self.internal_lock();
+        let mutex = MutexGuard::unlock(guard);
+        loop {
+            let (next, result) = self
+                .condvar
+                .wait_timeout(internal, manager.analysis_timeout())
+                .unwrap_or_else(PoisonError::into_inner);
+            internal = next;
+            if !result.timed_out() {
+                break;
+            }
+            if manager.used_by_current_thread_only() {
+                std::mem::drop(internal);
+                panic!(
+                    "LIKELY SELF-DEADLOCK: {:?} waits on a Condvar, but no other thread ever used its manager's locks to notify it. Waiting at:\n{:?}",
+                    std::thread::current().id(),
+                    backtrace::Backtrace::new()
+                );
+            }
         }
+        std::mem::drop(internal);
         mutex.lock()
     }
     pub fn wait_timeout<'l, T>(
@@ -27,74 +83,190 @@ impl Condvar {
         guard: MutexGuard<'l, T>,
         dur: Duration,
     ) -> LockResult<(MutexGuard<'l, T>, WaitTimeoutResult)> {
-        let mutex = guard.unlock();
-        #[allow(unused_must_use)]
+        let guard = self.check_pairing(guard);
+        let internal = self.internal_lock();
+        let mutex = MutexGuard::unlock(guard);
         let result = self
             .condvar
-            .wait_timeout(self.mutex.lock().unwrap(), dur)
-            .unwrap()
+            .wait_timeout(internal, dur)
+            .unwrap_or_else(PoisonError::into_inner)
             .1;
         match mutex.lock() {
             Ok(guard) => Ok((guard, result)),
             Err(e) => Err(PoisonError::new((e.into_inner(), result))),
         }
     }
+    /// Blocks while `condition` returns `true`, for at most `dur`, as `std` does.
+    /// The returned `WaitTimeoutResult` times out only if `condition` still held at the deadline.
     pub fn wait_timeout_while<'l, T, F: FnMut(&mut T) -> bool>(
         &self,
-        guard: MutexGuard<'l, T>,
+        mut guard: MutexGuard<'l, T>,
         dur: Duration,
         mut condition: F,
     ) -> LockResult<(MutexGuard<'l, T>, WaitTimeoutResult)> {
-        use std::time::Instant;
-        let mut mutex = guard.unlock();
         let expiry = Instant::now() + dur;
         loop {
-            let timedout = self
-                .condvar
-                .wait_timeout(self.mutex.lock().unwrap(), expiry - Instant::now())
-                .unwrap()
-                .1;
-            let guard = mutex.lock();
-            match guard {
-                Ok(mut guard) => {
-                    if condition(&mut *guard) {
-                        return Ok((guard, timedout));
-                    } else {
-                        mutex = guard.unlock();
-                    }
-                }
-                Err(e) => return Err(PoisonError::new((e.into_inner(), timedout))),
+            if !condition(&mut *guard) {
+                return Ok((guard, wait_timeout_result(false)));
             }
+            let remaining = match expiry.checked_duration_since(Instant::now()) {
+                Some(remaining) if remaining > Duration::from_secs(0) => remaining,
+                _ => return Ok((guard, wait_timeout_result(true))),
+            };
+            guard = match self.wait_timeout(guard, remaining) {
+                Ok((guard, _)) => guard,
+                Err(e) => {
+                    let (mut guard, _) = e.into_inner();
+                    let timed_out = condition(&mut *guard) && Instant::now() >= expiry;
+                    return Err(PoisonError::new((guard, wait_timeout_result(timed_out))));
+                }
+            };
         }
     }
+    /// Blocks until `condition` returns `false`, checking it before the first wait as `std` does.
     pub fn wait_while<'l, T, F: FnMut(&mut T) -> bool>(
         &self,
-        guard: MutexGuard<'l, T>,
+        mut guard: MutexGuard<'l, T>,
         mut condition: F,
     ) -> LockResult<MutexGuard<'l, T>> {
-        let mut mutex = guard.unlock();
-        loop {
-            #[allow(unused_must_use)]
-            {
-                self.condvar.wait(self.mutex.lock().unwrap());
-            }
-            let guard = mutex.lock();
-            match guard {
-                Ok(mut guard) => {
-                    if condition(&mut *guard) {
-                        return Ok(guard);
-                    } else {
-                        mutex = guard.unlock();
-                    }
-                }
-                Err(_) => return guard,
-            }
+        while condition(&mut *guard) {
+            guard = self.wait(guard)?;
         }
+        Ok(guard)
     }
     pub fn notify_one(&self) {
+        let _internal = self.internal_lock();
         self.condvar.notify_one()
     }
     pub fn notify_all(&self) {
+        let _internal = self.internal_lock();
         self.condvar.notify_all()
     }
 }
+
+/// `WaitTimeoutResult` can't be built directly: this obtains one from `std` without blocking.
+fn wait_timeout_result(timed_out: bool) -> WaitTimeoutResult {
+    let mutex = std::sync::Mutex::new(());
+    let guard = mutex.lock().unwrap_or_else(PoisonError::into_inner);
+    let (_guard, result) = std::sync::Condvar::new()
+        .wait_timeout_while(guard, Duration::from_secs(0), |_| timed_out)
+        .unwrap_or_else(PoisonError::into_inner);
+    result
+}
+
+#[test]
+fn wait_while_ping_pong() {
+    use crate::Mutex;
+    use std::sync::{mpsc, Arc};
+    const ROUNDS: usize = 1000;
+    let state = Arc::new((Mutex::new(0), Condvar::new()));
+    let (done, finished) = mpsc::channel();
+    let players: Vec<_> = (0..2)
+        .map(|player| {
+            let state = state.clone();
+            let done = done.clone();
+            std::thread::spawn(move || {
+                let (mutex, condvar) = &*state;
+                for round in 0..ROUNDS {
+                    let turn = 2 * round + player;
+                    let mut guard = condvar
+                        .wait_while(mutex.lock().unwrap(), |current| *current != turn)
+                        .unwrap();
+                    *guard += 1;
+                    std::mem::drop(guard);
+                    condvar.notify_all();
+                }
+                done.send(()).unwrap();
+            })
+        })
+        .collect();
+    for _ in 0..2 {
+        finished
+            .recv_timeout(Duration::from_secs(30))
+            .expect("a wakeup was lost");
+    }
+    for player in players {
+        player.join().unwrap();
+    }
+    assert_eq!(*state.0.lock().unwrap(), 2 * ROUNDS);
+}
+
+#[test]
+fn wait_timeout_while_deadline() {
+    use crate::Mutex;
+    use std::sync::Arc;
+    let state = Arc::new((Mutex::new(false), Condvar::new()));
+    let (mutex, condvar) = &*state;
+    let start = Instant::now();
+    let (guard, result) = condvar
+        .wait_timeout_while(mutex.lock().unwrap(), Duration::from_millis(30), |ready| {
+            !*ready
+        })
+        .unwrap();
+    assert!(result.timed_out());
+    assert!(!*guard);
+    assert!(start.elapsed() >= Duration::from_millis(30));
+    std::mem::drop(guard);
+    let notifier = std::thread::spawn({
+        let state = state.clone();
+        move || {
+            std::thread::sleep(Duration::from_millis(20));
+            *state.0.lock().unwrap() = true;
+            state.1.notify_all();
+        }
+    });
+    let (guard, result) = condvar
+        .wait_timeout_while(mutex.lock().unwrap(), Duration::from_secs(30), |ready| {
+            !*ready
+        })
+        .unwrap();
+    assert!(!result.timed_out());
+    assert!(*guard);
+    std::mem::drop(guard);
+    notifier.join().unwrap();
+}
+
+#[test]
+fn lonely_wait_panics() {
+    use crate::lock_manager::LockManager;
+    use crate::Mutex;
+    use std::sync::Arc;
+    let manager = Arc::new(LockManager::with_analysis_timeout(Duration::from_millis(
+        20,
+    )));
+    let mutex = Mutex::with_manager(manager, ());
+    let condvar = Condvar::new();
+    let start = Instant::now();
+    let wait = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        std::mem::drop(condvar.wait(mutex.lock().unwrap()));
+    }));
+    let message = wait.unwrap_err();
+    assert!(message
+        .downcast_ref::<String>()
+        .unwrap()
+        .starts_with("LIKELY SELF-DEADLOCK"));
+    assert!(start.elapsed() < Duration::from_secs(5));
+    // The mutex was released before waiting, and stays usable.
+    assert!(mutex.try_lock().is_ok());
+}
+
+#[cfg(debug_assertions)]
+#[test]
+fn paired_condvar_misuse() {
+    use crate::Mutex;
+    let paired = Mutex::new(0);
+    let other = Mutex::new(0);
+    other.set_name("other");
+    let condvar = Condvar::new_for(&paired);
+    let (_, result) = condvar
+        .wait_timeout(paired.lock().unwrap(), Duration::from_millis(1))
+        .unwrap();
+    assert!(result.timed_out());
+    let payload = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        std::mem::drop(condvar.wait(other.lock().unwrap()));
+    }))
+    .unwrap_err();
+    let message = payload.downcast_ref::<String>().unwrap();
+    assert!(message.starts_with("CONDVAR MISUSE: waiting with a guard of 'other'"));
+    assert!(!other.is_poisoned());
+}
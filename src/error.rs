@@ -0,0 +1,191 @@
+use std::sync::{PoisonError, TryLockError};
+
+use backtrace::Backtrace;
+
+use crate::lock_manager::{AcquireError, DeadlockReport};
+
+/// A single error type for every lock operation, returned by the `_checked` methods.
+pub enum LockError<Guard> {
+    /// The lock was acquired, but a thread panicked while holding it.
+    Poisoned(PoisonError<Guard>),
+    /// The lock is held elsewhere, and the operation wasn't allowed to block.
+    WouldBlock,
+    /// The lock couldn't be acquired before the deadline.
+    TimedOut,
+    /// Waiting for the lock would never end.
+    Deadlock(DeadlockReport),
+    /// The wait was given up by the caller.
+    Cancelled,
+    /// The current thread already holds the lock, which was taken at the enclosed backtrace.
+    WouldDeadlockReentrant(Backtrace),
+}
+
+pub type CheckedResult<Guard> = Result<Guard, LockError<Guard>>;
+
+/// Why `shutdown` left the global manager in place.
+#[derive(Clone, Debug)]
+pub struct ShutdownError {
+    /// References to the global manager besides its own: one per live lock, and any held elsewhere.
+    pub references: usize,
+    /// The registered locks, by key and name. Locks that were never contended aren't registered.
+    pub live_locks: Vec<String>,
+}
+
+impl std::fmt::Display for ShutdownError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "the global lock manager is still referenced {} times",
+            self.references
+        )?;
+        if !self.live_locks.is_empty() {
+            write!(f, ", live locks: {}", self.live_locks.join(", "))?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for ShutdownError {}
+
+impl<Guard> LockError<Guard> {
+    pub(crate) fn check_poison(guard: Guard, poisoned: bool) -> CheckedResult<Guard> {
+        if poisoned {
+            Err(LockError::Poisoned(PoisonError::new(guard)))
+        } else {
+            Ok(guard)
+        }
+    }
+}
+
+impl<Guard> std::fmt::Debug for LockError<Guard> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LockError::Poisoned(_) => f.write_str("Poisoned(..)"),
+            LockError::WouldBlock => f.write_str("WouldBlock"),
+            LockError::TimedOut => f.write_str("TimedOut"),
+            LockError::Cancelled => f.write_str("Cancelled"),
+            LockError::Deadlock(report) => f.debug_tuple("Deadlock").field(report).finish(),
+            LockError::WouldDeadlockReentrant(trace) => f
+                .debug_tuple("WouldDeadlockReentrant")
+                .field(trace)
+                .finish(),
+        }
+    }
+}
+
+impl<Guard> std::fmt::Display for LockError<Guard> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LockError::Poisoned(e) => e.fmt(f),
+            LockError::WouldBlock => {
+                f.write_str("try_lock failed because the operation would block")
+            }
+            LockError::TimedOut => f.write_str("the lock couldn't be acquired in time"),
+            LockError::Cancelled => f.write_str("waiting for the lock was cancelled"),
+            LockError::Deadlock(report) => {
+                write!(f, "waiting for the lock would deadlock: {:?}", report.cycle)
+            }
+            LockError::WouldDeadlockReentrant(_) => {
+                f.write_str("the current thread already holds the lock")
+            }
+        }
+    }
+}
+
+impl<Guard> std::error::Error for LockError<Guard> {}
+
+impl<Guard> From<PoisonError<Guard>> for LockError<Guard> {
+    fn from(error: PoisonError<Guard>) -> Self {
+        LockError::Poisoned(error)
+    }
+}
+
+impl<Guard> From<TryLockError<Guard>> for LockError<Guard> {
+    fn from(error: TryLockError<Guard>) -> Self {
+        match error {
+            TryLockError::Poisoned(error) => LockError::Poisoned(error),
+            TryLockError::WouldBlock => LockError::WouldBlock,
+        }
+    }
+}
+
+impl<Guard> From<AcquireError> for LockError<Guard> {
+    fn from(error: AcquireError) -> Self {
+        match error {
+            AcquireError::TimedOut => LockError::TimedOut,
+            AcquireError::Deadlock(report) => LockError::Deadlock(report),
+            AcquireError::Cancelled => LockError::Cancelled,
+        }
+    }
+}
+
+#[test]
+fn checked_errors() {
+    use crate::lock_manager::LockManager;
+    use crate::Mutex;
+    use std::sync::Arc;
+    use std::time::Duration;
+    let manager = Arc::new(LockManager::with_analysis_timeout(Duration::from_millis(
+        10,
+    )));
+    let mutex = Arc::new(Mutex::with_manager(manager, 0));
+    let guard = mutex.lock_checked().unwrap();
+    assert!(matches!(
+        mutex.try_lock_checked(),
+        Err(LockError::WouldBlock)
+    ));
+    match mutex.lock_checked() {
+        Err(LockError::Deadlock(report)) => assert_eq!(report.cycle.len(), 2),
+        other => panic!("expected a reentrance deadlock, got {:?}", other.err()),
+    }
+    let th = std::thread::spawn({
+        let mutex = mutex.clone();
+        move || {
+            matches!(
+                mutex.try_lock_for_checked(Duration::from_millis(50)),
+                Err(LockError::TimedOut)
+            )
+        }
+    });
+    assert!(th.join().unwrap());
+    std::mem::drop(guard);
+    let _ = std::thread::spawn({
+        let mutex = mutex.clone();
+        move || {
+            let _guard = mutex.lock();
+            panic!("poisoning the mutex");
+        }
+    })
+    .join();
+    assert!(matches!(mutex.lock_checked(), Err(LockError::Poisoned(_))));
+}
+
+#[test]
+fn reentrant_try_lock() {
+    use crate::lock_manager::LockManager;
+    use crate::{Mutex, RwLock};
+    use std::sync::Arc;
+    let manager = Arc::new(LockManager::new());
+    manager.set_try_lock_reentrance_detection(true);
+    let mutex = Mutex::with_manager(manager.clone(), 0);
+    let _guard = mutex.lock().unwrap();
+    match mutex.try_lock_checked() {
+        Err(LockError::WouldDeadlockReentrant(trace)) => {
+            assert!(format!("{:?}", trace).contains("reentrant_try_lock"))
+        }
+        other => panic!("expected a reentrance, got {:?}", other.err()),
+    }
+    let rwlock = RwLock::with_manager(manager, 0);
+    let read = rwlock.read().unwrap();
+    assert!(rwlock.try_read_checked().is_ok());
+    assert!(matches!(
+        rwlock.try_write_checked(),
+        Err(LockError::WouldDeadlockReentrant(_))
+    ));
+    std::mem::drop(read);
+    let _write = rwlock.write().unwrap();
+    assert!(matches!(
+        rwlock.try_read_checked(),
+        Err(LockError::WouldDeadlockReentrant(_))
+    ));
+}
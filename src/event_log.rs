@@ -0,0 +1,275 @@
+use std::fs::File;
+use std::io::{BufRead, BufReader, Write};
+use std::path::Path;
+use std::thread::ThreadId;
+use std::time::{Duration, Instant};
+
+use crate::graphs::Graph;
+use crate::lock_manager::RequestType;
+use crate::Map;
+
+#[derive(Eq, PartialEq, Copy, Clone, Debug)]
+pub(crate) enum LockEvent {
+    Create,
+    Remove,
+    Subscribe(RequestType),
+    Unsubscribe,
+    Acquire(RequestType),
+    Release,
+}
+
+impl LockEvent {
+    fn as_str(&self) -> &'static str {
+        match self {
+            LockEvent::Create => "create",
+            LockEvent::Remove => "remove",
+            LockEvent::Subscribe(RequestType::Read) => "subscribe-read",
+            LockEvent::Subscribe(RequestType::Write) => "subscribe-write",
+            LockEvent::Unsubscribe => "unsubscribe",
+            LockEvent::Acquire(RequestType::Read) => "acquire-read",
+            LockEvent::Acquire(RequestType::Write) => "acquire-write",
+            LockEvent::Release => "release",
+        }
+    }
+
+    fn parse(s: &str) -> Option<Self> {
+        Some(match s {
+            "create" => LockEvent::Create,
+            "remove" => LockEvent::Remove,
+            "subscribe-read" => LockEvent::Subscribe(RequestType::Read),
+            "subscribe-write" => LockEvent::Subscribe(RequestType::Write),
+            "unsubscribe" => LockEvent::Unsubscribe,
+            "acquire-read" => LockEvent::Acquire(RequestType::Read),
+            "acquire-write" => LockEvent::Acquire(RequestType::Write),
+            "release" => LockEvent::Release,
+            _ => return None,
+        })
+    }
+}
+
+/// Appends one `<nanoseconds> <thread> <event> <lock>` line per lock state transition.
+pub(crate) struct EventLog {
+    start: Instant,
+    file: File,
+}
+
+impl EventLog {
+    pub(crate) fn open<P: AsRef<Path>>(path: P) -> std::io::Result<Self> {
+        let file = std::fs::OpenOptions::new()
+            .append(true)
+            .create(true)
+            .open(path)?;
+        Ok(EventLog {
+            start: Instant::now(),
+            file,
+        })
+    }
+
+    pub(crate) fn record(
+        &mut self,
+        thread: u64,
+        key: usize,
+        event: LockEvent,
+    ) -> std::io::Result<()> {
+        let line = format!(
+            "{} {} {} {}\n",
+            self.start.elapsed().as_nanos(),
+            thread,
+            event.as_str(),
+            key
+        );
+        self.file.write_all(line.as_bytes())
+    }
+}
+
+/// `ThreadId` has no stable numeric accessor, but its `Debug` output is `ThreadId(N)`.
+pub(crate) fn thread_number(id: ThreadId) -> u64 {
+    format!("{:?}", id)
+        .chars()
+        .filter(|c| c.is_ascii_digit())
+        .collect::<String>()
+        .parse()
+        .unwrap_or(0)
+}
+
+/// A node of a replayed dependency graph.
+#[derive(Eq, PartialEq, Copy, Clone, Debug, Hash)]
+pub enum ReplayNode {
+    Thread(u64),
+    Lock(usize),
+}
+
+/// The first dependence cycle found while replaying an event log.
+#[derive(Clone, Debug)]
+pub struct ReplayedDeadlock {
+    /// Time since the log was enabled at which the cycle appeared.
+    pub elapsed: Duration,
+    /// 1-based line of the event that closed the cycle.
+    pub line: usize,
+    pub cycle: Vec<ReplayNode>,
+}
+
+#[derive(Default)]
+struct ReplayedLock {
+    write_locked: bool,
+    readers: Vec<u64>,
+    requests: Map<u64, RequestType>,
+}
+
+/// Replays an event log written by `LockManager::enable_event_log`, returning the first moment a dependence cycle existed.
+pub fn replay<P: AsRef<Path>>(path: P) -> std::io::Result<Option<ReplayedDeadlock>> {
+    let invalid = |line: usize| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("malformed event log record at line {}", line),
+        )
+    };
+    let mut locks: Map<usize, ReplayedLock> = Map::new();
+    for (index, line) in BufReader::new(File::open(path)?).lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let mut fields = line.split_whitespace();
+        let (nanos, thread, event, key) = match (
+            fields.next().and_then(|f| f.parse::<u64>().ok()),
+            fields.next().and_then(|f| f.parse::<u64>().ok()),
+            fields.next().and_then(LockEvent::parse),
+            fields.next().and_then(|f| f.parse::<usize>().ok()),
+        ) {
+            (Some(nanos), Some(thread), Some(event), Some(key)) => (nanos, thread, event, key),
+            _ => return Err(invalid(index + 1)),
+        };
+        // Mirrors the transitions of `LockRepresentation`.
+        match event {
+            LockEvent::Create => {
+                locks.insert(key, ReplayedLock::default());
+            }
+            LockEvent::Remove => {
+                locks.remove(&key);
+            }
+            LockEvent::Subscribe(request) => {
+                if let Some(lock) = locks.get_mut(&key) {
+                    lock.requests.insert(thread, request);
+                }
+            }
+            LockEvent::Unsubscribe => {
+                if let Some(lock) = locks.get_mut(&key) {
+                    lock.requests.remove(&thread);
+                }
+            }
+            LockEvent::Acquire(request) => {
+                if let Some(lock) = locks.get_mut(&key) {
+                    lock.write_locked = request == RequestType::Write;
+                    lock.readers.push(thread);
+                    lock.requests.remove(&thread);
+                }
+            }
+            LockEvent::Release => {
+                if let Some(lock) = locks.get_mut(&key) {
+                    lock.write_locked = false;
+                    if let Some(position) = lock.readers.iter().position(|t| *t == thread) {
+                        lock.readers.swap_remove(position);
+                    }
+                }
+            }
+        }
+        // Mirrors the graph built by `LockManagerInner::analyse`.
+        let mut graph = Graph::new();
+        for (id, lock) in locks.iter() {
+            let lock_node = ReplayNode::Lock(*id);
+            for reader in lock.readers.iter() {
+                graph.add_edge_and_nodes(lock_node, ReplayNode::Thread(*reader));
+            }
+            for (requester, request) in lock.requests.iter() {
+                if lock.write_locked || *request == RequestType::Write {
+                    graph.add_edge_and_nodes(ReplayNode::Thread(*requester), lock_node);
+                }
+            }
+        }
+        if let Some(cycle) = graph.find_loop() {
+            return Ok(Some(ReplayedDeadlock {
+                elapsed: Duration::from_nanos(nanos),
+                line: index + 1,
+                cycle: cycle.into_iter().cloned().collect(),
+            }));
+        }
+    }
+    Ok(None)
+}
+
+#[test]
+fn event_log_round_trip() {
+    use crate::lock_manager::LockManager;
+    use crate::Mutex;
+    use std::sync::{Arc, Barrier};
+    let path =
+        std::env::temp_dir().join(format!("no_deadlocks_event_log_{}.log", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let manager = Arc::new(LockManager::with_analysis_timeout(Duration::from_millis(
+        50,
+    )));
+    manager.enable_event_log(&path).unwrap();
+    let mut1 = Arc::new(Mutex::with_manager(manager.clone(), 0));
+    let mut2 = Arc::new(Mutex::with_manager(manager.clone(), 0));
+    let barrier = Arc::new(Barrier::new(2));
+    let threads: Vec<_> = vec![(mut1.clone(), mut2.clone()), (mut2, mut1)]
+        .into_iter()
+        .map(|(first, second)| {
+            let barrier = barrier.clone();
+            std::thread::spawn(move || {
+                let _first = first.lock();
+                barrier.wait();
+                let _second = second.lock();
+            })
+        })
+        .collect();
+    let panics = threads
+        .into_iter()
+        .filter_map(|thread| thread.join().err())
+        .count();
+    assert!(panics > 0);
+    manager.disable_event_log();
+    let deadlock = replay(&path)
+        .unwrap()
+        .expect("the replay should find the cycle");
+    let _ = std::fs::remove_file(&path);
+    assert_eq!(deadlock.cycle.len(), 4);
+    assert!(deadlock.cycle.contains(&ReplayNode::Lock(0)));
+    assert!(deadlock.cycle.contains(&ReplayNode::Lock(1)));
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn failed_writes_disable_the_log() {
+    use crate::lock_manager::LockManager;
+    use crate::Mutex;
+    use std::sync::Arc;
+    let manager = Arc::new(LockManager::with_analysis_timeout(Duration::from_secs(5)));
+    let warnings = Arc::new(std::sync::Mutex::new(Vec::new()));
+    manager.set_warning_hook({
+        let warnings = warnings.clone();
+        move |message| warnings.lock().unwrap().push(message.to_owned())
+    });
+    // Every write to `/dev/full` fails with `ENOSPC`.
+    manager.enable_event_log("/dev/full").unwrap();
+    let mutex = Arc::new(Mutex::with_manager(manager.clone(), ()));
+    let guard = mutex.lock().unwrap();
+    let waiter = std::thread::spawn({
+        let mutex = mutex.clone();
+        move || std::mem::drop(mutex.lock())
+    });
+    let start = Instant::now();
+    while mutex.key().is_none() {
+        assert!(start.elapsed() < Duration::from_secs(5));
+        std::thread::sleep(Duration::from_millis(1));
+    }
+    std::mem::drop(guard);
+    waiter.join().unwrap();
+    let warnings = warnings.lock().unwrap();
+    let failures = warnings
+        .iter()
+        .filter(|warning| warning.contains("event log"))
+        .count();
+    assert_eq!(failures, 1, "{:?}", warnings);
+}
@@ -1,36 +1,44 @@
+use crate::lock_manager::DependencyNode;
 use crate::{Map, Set};
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct Graph<NodeType: std::cmp::PartialEq + std::cmp::Eq + std::hash::Hash> {
-    nodes: Map<NodeType, Set<NodeType>>
+    nodes: Map<NodeType, Set<NodeType>>,
+}
+
+impl<NodeType: std::cmp::PartialEq + std::cmp::Eq + std::hash::Hash> Default for Graph<NodeType> {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl<NodeType: std::cmp::PartialEq + std::cmp::Eq + std::hash::Hash> Graph<NodeType> {
     pub fn new() -> Self {
-        Graph {nodes: Map::new()}
+        Graph { nodes: Map::new() }
     }
-    #[allow(dead_code)]
     pub fn add_node(&mut self, node: NodeType) {
-        if !self.nodes.contains_key(&node) {
-            self.nodes.insert(node, Set::new());
-        }
+        self.nodes.entry(node).or_default();
     }
     #[allow(dead_code)]
     pub fn add_edge(&mut self, from: &NodeType, to: NodeType) {
         self.nodes.get_mut(from).unwrap().insert(to, ());
     }
 
-    pub fn add_edge_and_nodes(&mut self, from: NodeType, to: NodeType) where NodeType: Clone {
-        if !self.nodes.contains_key(&to) {
-            self.nodes.insert(to.clone(), Set::new());
-        }
-        if !self.nodes.contains_key(&from) {
-            let mut set = Set::new();
-            set.insert(to, ());
-            self.nodes.insert(from, set);
-        } else {
-            self.nodes.get_mut(&from).unwrap().insert(to, ());
-        }
+    pub fn add_edge_and_nodes(&mut self, from: NodeType, to: NodeType)
+    where
+        NodeType: Clone,
+    {
+        self.nodes.entry(to.clone()).or_default();
+        self.nodes.entry(from).or_default().insert(to, ());
+    }
+
+    pub fn nodes(&self) -> impl Iterator<Item = &NodeType> {
+        self.nodes.keys()
+    }
+
+    /// The nodes `node` has an edge toward.
+    pub fn successors<'l>(&'l self, node: &NodeType) -> impl Iterator<Item = &'l NodeType> {
+        self.nodes.get(node).into_iter().flat_map(|set| set.keys())
     }
 
     pub fn find_loop(&self) -> Option<Vec<&NodeType>> {
@@ -45,6 +53,30 @@ impl<NodeType: std::cmp::PartialEq + std::cmp::Eq + std::hash::Hash> Graph<NodeT
         None
     }
 
+    /// Loops that share no node, found one after the other by leaving out the nodes of those found before.
+    pub fn find_all_loops(&self) -> Vec<Vec<NodeType>>
+    where
+        NodeType: Clone,
+    {
+        let mut remaining = self.clone();
+        let mut loops = Vec::new();
+        while let Some(found) = remaining.find_loop() {
+            let found: Vec<NodeType> = found.into_iter().cloned().collect();
+            for node in found.iter() {
+                remaining.remove_node(node);
+            }
+            loops.push(found);
+        }
+        loops
+    }
+
+    fn remove_node(&mut self, node: &NodeType) {
+        self.nodes.remove(node);
+        for (_, successors) in self.nodes.iter_mut() {
+            successors.remove(node);
+        }
+    }
+
     fn loop_backtracker<'l>(&'l self, history: &mut Vec<&'l NodeType>) -> bool {
         let current_node = *history.last().unwrap();
         for node in self.nodes.get(current_node).unwrap().keys() {
@@ -65,6 +97,121 @@ impl<NodeType: std::cmp::PartialEq + std::cmp::Eq + std::hash::Hash> Graph<NodeT
     }
 }
 
+/// A strategy for finding a dependence cycle in the dependency graph, see `LockManager::set_cycle_detector`.
+pub trait CycleDetector {
+    /// Returns a cycle, each node having an edge toward the next one, and the last one toward the first one.
+    fn find(&self, graph: &Graph<DependencyNode>) -> Option<Vec<DependencyNode>>;
+}
+
+/// The default detector: a depth-first search returning the first cycle it runs into.
+pub struct DepthFirstDetector;
+
+impl CycleDetector for DepthFirstDetector {
+    fn find(&self, graph: &Graph<DependencyNode>) -> Option<Vec<DependencyNode>> {
+        Some(graph.find_loop()?.into_iter().cloned().collect())
+    }
+}
+
+/// Finds strongly connected components with Tarjan's algorithm,
+/// and returns the shortest cycle through a node of the first non-trivial one.
+pub struct TarjanDetector;
+
+struct Tarjan<'l> {
+    graph: &'l Graph<DependencyNode>,
+    index: Map<DependencyNode, (usize, usize)>,
+    stack: Vec<DependencyNode>,
+    components: Vec<Vec<DependencyNode>>,
+}
+
+impl<'l> Tarjan<'l> {
+    fn connect(&mut self, node: DependencyNode) {
+        let index = self.index.len();
+        self.index.insert(node, (index, index));
+        self.stack.push(node);
+        for next in self.graph.successors(&node).cloned() {
+            match self.index.get(&next).cloned() {
+                None => {
+                    self.connect(next);
+                    let next_low = self.index.get(&next).unwrap().1;
+                    let entry = self.index.get_mut(&node).unwrap();
+                    entry.1 = entry.1.min(next_low);
+                }
+                Some((next_index, _)) if self.stack.contains(&next) => {
+                    let entry = self.index.get_mut(&node).unwrap();
+                    entry.1 = entry.1.min(next_index);
+                }
+                Some(_) => {}
+            }
+        }
+        let (index, low) = *self.index.get(&node).unwrap();
+        if index == low {
+            let start = self.stack.iter().position(|n| *n == node).unwrap();
+            self.components.push(self.stack.split_off(start));
+        }
+    }
+}
+
+impl CycleDetector for TarjanDetector {
+    fn find(&self, graph: &Graph<DependencyNode>) -> Option<Vec<DependencyNode>> {
+        let mut tarjan = Tarjan {
+            graph,
+            index: Map::new(),
+            stack: Vec::new(),
+            components: Vec::new(),
+        };
+        for node in graph.nodes() {
+            if !tarjan.index.contains_key(node) {
+                tarjan.connect(*node);
+            }
+        }
+        let component = tarjan.components.into_iter().find(|component| {
+            component.len() > 1 || graph.successors(&component[0]).any(|n| *n == component[0])
+        })?;
+        // Breadth-first search from `start` back to itself, staying inside the component.
+        let start = component[0];
+        let mut parents: Map<DependencyNode, DependencyNode> = Map::new();
+        let mut queue = std::collections::VecDeque::new();
+        queue.push_back(start);
+        while let Some(node) = queue.pop_front() {
+            for next in graph.successors(&node).cloned() {
+                if next == start {
+                    let mut cycle = vec![node];
+                    let mut current = node;
+                    while current != start {
+                        current = *parents.get(&current).unwrap();
+                        cycle.push(current);
+                    }
+                    cycle.reverse();
+                    return Some(cycle);
+                }
+                if component.contains(&next) && !parents.contains_key(&next) {
+                    parents.insert(next, node);
+                    queue.push_back(next);
+                }
+            }
+        }
+        None
+    }
+}
+
+#[test]
+fn tarjan_finds_shortest_cycle() {
+    let mut graph = Graph::new();
+    let lock = DependencyNode::Lock;
+    graph.add_edge_and_nodes(lock(0), lock(1));
+    graph.add_edge_and_nodes(lock(1), lock(2));
+    graph.add_edge_and_nodes(lock(2), lock(3));
+    assert!(TarjanDetector.find(&graph).is_none());
+    graph.add_edge_and_nodes(lock(3), lock(0));
+    graph.add_edge_and_nodes(lock(2), lock(0));
+    let cycle = TarjanDetector.find(&graph).unwrap();
+    assert_eq!(cycle.len(), 3);
+    for (i, node) in cycle.iter().enumerate() {
+        let next = &cycle[(i + 1) % cycle.len()];
+        assert!(graph.successors(node).any(|n| n == next));
+    }
+}
+
 #[test]
 fn graph_loop_detection() {
     let mut graph = Graph::new();
@@ -79,7 +226,7 @@ fn graph_loop_detection() {
     graph.add_edge_and_nodes(2, 4);
     if let Some(r#loop) = graph.find_loop() {
         println!("Found loop! {:?}", r#loop)
-    } else{
+    } else {
         panic!("There should be a loop here !")
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,89 @@
+pub(crate) fn lock_acquired() {
+    #[cfg(feature = "metrics")]
+    {
+        ::metrics::counter!("no_deadlocks.locks.acquired").increment(1);
+        ::metrics::gauge!("no_deadlocks.locks.held").increment(1.0);
+    }
+}
+
+pub(crate) fn lock_released() {
+    #[cfg(feature = "metrics")]
+    ::metrics::gauge!("no_deadlocks.locks.held").decrement(1.0);
+}
+
+pub(crate) fn lock_contended() {
+    #[cfg(feature = "metrics")]
+    ::metrics::counter!("no_deadlocks.locks.contended").increment(1);
+}
+
+pub(crate) fn analysis() {
+    #[cfg(feature = "metrics")]
+    ::metrics::counter!("no_deadlocks.analyses").increment(1);
+}
+
+pub(crate) fn deadlock() {
+    #[cfg(feature = "metrics")]
+    ::metrics::counter!("no_deadlocks.deadlocks").increment(1);
+}
+
+#[cfg(feature = "metrics")]
+#[test]
+fn contention_is_counted() {
+    use crate::Mutex;
+    use metrics::{Counter, Gauge, Histogram, Key, KeyName, Metadata, SharedString, Unit};
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    #[derive(Default)]
+    struct Recorder {
+        values: std::sync::Mutex<std::collections::HashMap<String, Arc<AtomicU64>>>,
+    }
+    impl Recorder {
+        fn value(&self, name: &str) -> Arc<AtomicU64> {
+            self.values
+                .lock()
+                .unwrap()
+                .entry(name.to_owned())
+                .or_default()
+                .clone()
+        }
+    }
+    impl metrics::Recorder for Recorder {
+        fn describe_counter(&self, _: KeyName, _: Option<Unit>, _: SharedString) {}
+        fn describe_gauge(&self, _: KeyName, _: Option<Unit>, _: SharedString) {}
+        fn describe_histogram(&self, _: KeyName, _: Option<Unit>, _: SharedString) {}
+        fn register_counter(&self, key: &Key, _: &Metadata<'_>) -> Counter {
+            Counter::from_arc(self.value(key.name()))
+        }
+        fn register_gauge(&self, key: &Key, _: &Metadata<'_>) -> Gauge {
+            Gauge::from_arc(self.value(key.name()))
+        }
+        fn register_histogram(&self, _: &Key, _: &Metadata<'_>) -> Histogram {
+            Histogram::noop()
+        }
+    }
+
+    let recorder: &'static Recorder = Box::leak(Box::default());
+    metrics::set_global_recorder(recorder).unwrap();
+    let contended = recorder.value("no_deadlocks.locks.contended");
+    let mutex = Arc::new(Mutex::new(()));
+    let guard = mutex.lock().unwrap();
+    let waiter = std::thread::spawn({
+        let mutex = mutex.clone();
+        move || std::mem::drop(mutex.lock())
+    });
+    let start = std::time::Instant::now();
+    while contended.load(Ordering::Relaxed) == 0 {
+        assert!(start.elapsed() < Duration::from_secs(5));
+        std::thread::sleep(Duration::from_millis(1));
+    }
+    std::mem::drop(guard);
+    waiter.join().unwrap();
+    assert!(
+        recorder
+            .value("no_deadlocks.locks.acquired")
+            .load(Ordering::Relaxed)
+            >= 2
+    );
+}
@@ -0,0 +1,102 @@
+use std::time::Duration;
+
+/// Sub-buckets per power of two: estimates are at most 25% above the actual durations.
+const SUB_BUCKETS: u32 = 4;
+
+/// A fixed-size histogram of wait times, in nanoseconds, with logarithmically growing buckets.
+/// Buckets are only allocated once the first wait is recorded.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub(crate) struct LatencyHistogram {
+    buckets: Vec<u64>,
+    count: u64,
+    max: Duration,
+}
+
+/// Percentiles of the time threads waited to acquire a lock, see `LockManager::lock_latency_percentiles`.
+/// Percentiles are estimated by the upper bound of their histogram bucket, capped to `max`.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct LatencyStats {
+    /// How many waits were measured.
+    pub count: u64,
+    pub p50: Duration,
+    pub p90: Duration,
+    pub p99: Duration,
+    pub max: Duration,
+}
+
+fn bucket(nanos: u64) -> usize {
+    if nanos < SUB_BUCKETS as u64 {
+        return nanos as usize;
+    }
+    let msb = 63 - nanos.leading_zeros();
+    let sub = (nanos >> (msb - 2)) & (SUB_BUCKETS as u64 - 1);
+    ((msb - 1) * SUB_BUCKETS) as usize + sub as usize
+}
+
+/// The largest duration falling in `bucket`.
+fn upper_bound(bucket: usize) -> Duration {
+    let bucket = bucket as u64;
+    let sub_buckets = SUB_BUCKETS as u64;
+    if bucket < sub_buckets {
+        return Duration::from_nanos(bucket);
+    }
+    let shift = bucket / sub_buckets - 1;
+    let lower = (sub_buckets + bucket % sub_buckets) << shift;
+    Duration::from_nanos(lower.saturating_add((1 << shift) - 1))
+}
+
+impl LatencyHistogram {
+    pub(crate) fn record(&mut self, wait: Duration) {
+        if self.buckets.is_empty() {
+            self.buckets = vec![0; bucket(u64::MAX) + 1];
+        }
+        let nanos = wait.as_nanos().min(u64::MAX as u128) as u64;
+        self.buckets[bucket(nanos)] += 1;
+        self.count += 1;
+        self.max = self.max.max(wait);
+    }
+
+    /// Bytes allocated for the buckets.
+    pub(crate) fn heap_size(&self) -> usize {
+        self.buckets.capacity() * std::mem::size_of::<u64>()
+    }
+
+    fn percentile(&self, fraction: f64) -> Duration {
+        let rank = ((self.count as f64 * fraction).ceil() as u64).max(1);
+        let mut seen = 0;
+        for (bucket, count) in self.buckets.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                return upper_bound(bucket).min(self.max);
+            }
+        }
+        self.max
+    }
+
+    pub(crate) fn stats(&self) -> LatencyStats {
+        if self.count == 0 {
+            return LatencyStats::default();
+        }
+        LatencyStats {
+            count: self.count,
+            p50: self.percentile(0.5),
+            p90: self.percentile(0.9),
+            p99: self.percentile(0.99),
+            max: self.max,
+        }
+    }
+}
+
+#[test]
+fn bucket_bounds() {
+    for nanos in [0u64, 1, 3, 4, 7, 8, 1000, 123_456_789, u64::MAX]
+        .iter()
+        .cloned()
+    {
+        let bucket = bucket(nanos);
+        assert!(upper_bound(bucket) >= Duration::from_nanos(nanos));
+        if bucket > 0 {
+            assert!(upper_bound(bucket - 1) < Duration::from_nanos(nanos));
+        }
+    }
+}
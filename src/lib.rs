@@ -1,11 +1,41 @@
 mod condvar;
+mod error;
+mod event_log;
 mod graphs;
+mod instrumentation;
+mod latency;
 pub mod lock_manager;
 mod mutex;
+mod once;
+pub mod parking_lot_compat;
+#[cfg(feature = "lock_api")]
+mod raw;
 mod rwlock;
+#[cfg(feature = "shared_memory")]
+mod shared;
+pub mod testing;
+mod wait_for_graph;
 pub use condvar::Condvar;
-pub use mutex::{Mutex, MutexGuard};
+pub use error::{CheckedResult, LockError, ShutdownError};
+pub use event_log::{replay, ReplayNode, ReplayedDeadlock};
+pub use graphs::{CycleDetector, DepthFirstDetector, Graph, TarjanDetector};
+pub use latency::LatencyStats;
+pub use lock_manager::{
+    analyze_graph, assert_no_locks_held, clear_context, exclude_current_thread, prewarm,
+    set_backtrace_capture, set_context, shutdown, suppress_detection, BlockedThread, DeadlockKind,
+    DeadlockReport, LockStats, MemoryUsage, ReentrancePolicy, ReentranceReport, ReportFormat,
+    RequestType, WaitReason, WatchdogHandle,
+};
+pub use mutex::{
+    lock_all_or_retry, lock_two, DeadlineGuard, LockTransaction, Mutex, MutexGuard, WarnAfterGuard,
+};
+pub use once::{Once, OnceState};
+#[cfg(feature = "lock_api")]
+pub use raw::RawNoDeadlocks;
 pub use rwlock::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+#[cfg(feature = "shared_memory")]
+pub use shared::{SharedLockError, SharedLockManager, SharedMutex, SharedMutexGuard, SharedNode};
+pub use wait_for_graph::{GraphDiff, WaitForEdge, WaitForGraph, WaitForNode};
 
 /// A convenience import: imports all lock and guard types from `no_deadlock`.
 /// Replace `prelude` by `prelude_std` to import their equivalent types from `std::sync` instead.
@@ -29,6 +59,55 @@ pub(crate) type Map<K, V> = vector_map::VecMap<K, V>;
 #[cfg(not(feature = "use_vecmap"))]
 pub(crate) type Map<K, V> = std::collections::HashMap<K, V>;
 
+/// Locks and guards follow `std`'s `Send` and `Sync` bounds.
+///
+/// ```
+/// fn assert_send_sync<T: Send + Sync>() {}
+/// assert_send_sync::<no_deadlocks::Mutex<std::cell::Cell<u8>>>();
+/// assert_send_sync::<no_deadlocks::RwLock<u8>>();
+/// ```
+///
+/// `RwLock` readers share `&T` across threads:
+/// ```compile_fail
+/// fn assert_sync<T: Sync>() {}
+/// assert_sync::<no_deadlocks::RwLock<std::cell::Cell<u8>>>();
+/// ```
+/// ```compile_fail
+/// let lock = no_deadlocks::RwLock::new(std::cell::Cell::new(0u32));
+/// std::thread::scope(|scope| {
+///     scope.spawn(|| lock.read().unwrap().set(1));
+///     scope.spawn(|| lock.read().unwrap().set(2));
+/// });
+/// ```
+///
+/// Guards must be released by the thread that acquired them:
+/// ```compile_fail
+/// fn assert_send<T: Send>() {}
+/// assert_send::<no_deadlocks::MutexGuard<'static, u8>>();
+/// ```
+/// ```compile_fail
+/// fn assert_send<T: Send>() {}
+/// assert_send::<no_deadlocks::RwLockReadGuard<'static, u8>>();
+/// ```
+///
+/// Sharing a guard shares `&T`:
+/// ```compile_fail
+/// fn assert_sync<T: Sync>() {}
+/// assert_sync::<no_deadlocks::MutexGuard<'static, std::cell::Cell<u8>>>();
+/// ```
+/// ```compile_fail
+/// fn assert_sync<T: Sync>() {}
+/// assert_sync::<no_deadlocks::RwLockWriteGuard<'static, std::cell::Cell<u8>>>();
+/// ```
+///
+/// Only `Send` data can be handed over through a lock:
+/// ```compile_fail
+/// fn assert_sync<T: Sync>() {}
+/// assert_sync::<no_deadlocks::Mutex<std::rc::Rc<u8>>>();
+/// ```
+#[cfg(doctest)]
+pub struct SendSyncBounds;
+
 #[test]
 fn reported_issue_5() {
     use crate::Mutex;
@@ -1,16 +1,31 @@
 use std::cell::UnsafeCell;
-use std::ops::Deref;
+use std::ops::{ControlFlow, Deref};
+use std::sync::atomic::AtomicBool;
 use std::sync::atomic::AtomicI32 as AtomicCount;
 use std::sync::atomic::AtomicPtr;
+use std::sync::atomic::AtomicU8;
+use std::sync::atomic::AtomicUsize;
 use std::sync::atomic::Ordering;
-use std::sync::Arc;
+use std::sync::{Arc, MutexGuard, PoisonError};
 use std::thread::ThreadId;
+use std::time::{Duration, Instant};
 
 use backtrace::Backtrace;
 
-use crate::Map;
+use crate::event_log::{thread_number, EventLog, LockEvent};
+use crate::graphs::{CycleDetector, DepthFirstDetector, Graph};
+use crate::instrumentation;
+use crate::latency::{LatencyHistogram, LatencyStats};
+use crate::wait_for_graph::WaitForGraph;
+use crate::ShutdownError;
+use crate::{Map, Set};
 
 static GLOBAL_MANAGER: AtomicPtr<Arc<LockManager>> = AtomicPtr::new(std::ptr::null_mut());
+/// Calls to `get_global_manager` that may still dereference the global manager, which `shutdown` waits out.
+static GLOBAL_MANAGER_USERS: AtomicUsize = AtomicUsize::new(0);
+/// Serializes setting up the global manager with `shutdown`, so that neither frees what the other uses.
+static GLOBAL_MANAGER_LIFECYCLE: std::sync::Mutex<()> = std::sync::Mutex::new(());
+static NEXT_MANAGER_ID: AtomicUsize = AtomicUsize::new(0);
 
 pub struct LockManagerReadGuard<'l> {
     inner: &'l LockManagerInner,
@@ -18,7 +33,7 @@ pub struct LockManagerReadGuard<'l> {
 
 impl<'l> Drop for LockManagerReadGuard<'l> {
     fn drop(&mut self) {
-        self.inner.lock.fetch_sub(1, Ordering::Relaxed);
+        self.inner.lock.fetch_sub(1, Ordering::Release);
     }
 }
 
@@ -36,7 +51,7 @@ pub struct LockManagerWriteGuard<'l> {
 impl<'l> Drop for LockManagerWriteGuard<'l> {
     fn drop(&mut self) {
         unsafe {
-            (*self.inner.get()).lock.store(0, Ordering::Relaxed);
+            (*self.inner.get()).lock.store(0, Ordering::Release);
         }
     }
 }
@@ -54,85 +69,444 @@ impl<'l> std::ops::DerefMut for LockManagerWriteGuard<'l> {
     }
 }
 
+/// A node of the dependency graph: locks point toward the threads holding them,
+/// and threads point toward the lock they're waiting for.
 #[derive(Eq, PartialEq, Copy, Clone, Debug, Hash)]
-enum DependencyNode {
+pub enum DependencyNode {
     Thread(ThreadId),
     Lock(usize),
 }
 
-#[derive(Eq, PartialEq, Copy, Clone, Debug)]
-pub(crate) enum RequestType {
+/// Threads are shown by their process-wide number: reports, which know the manager, show its `#index` instead.
+impl std::fmt::Display for DependencyNode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DependencyNode::Thread(id) => write!(f, "thread {}", thread_number(*id)),
+            DependencyNode::Lock(id) => write!(f, "lock {}", id),
+        }
+    }
+}
+
+/// How reports refer to a node: `thread #1`, numbered like `ThreadIndices::short`, or `lock 0`.
+fn node_label(node: &DependencyNode, threads: &ThreadIndices) -> String {
+    match node {
+        DependencyNode::Thread(id) => format!("thread {}", threads.short(*id)),
+        DependencyNode::Lock(id) => format!("lock {}", id),
+    }
+}
+
+/// Formats a cycle as `[thread #1, lock 0]`.
+fn display_cycle(cycle: &[&DependencyNode], threads: &ThreadIndices) -> String {
+    let nodes: Vec<String> = cycle.iter().map(|node| node_label(node, threads)).collect();
+    format!("[{}]", nodes.join(", "))
+}
+
+/// The threads a manager has seen, numbered from 0 in the order it first saw them, with their names at the time.
+#[derive(Default)]
+pub(crate) struct ThreadIndices {
+    threads: Vec<(ThreadId, Option<String>)>,
+    /// Takes precedence over the captured names, see `LockManager::set_thread_name_resolver`.
+    resolver: Option<ThreadNameResolver>,
+}
+
+impl ThreadIndices {
+    fn index(&self, id: ThreadId) -> Option<usize> {
+        self.threads.iter().position(|(thread, _)| *thread == id)
+    }
+
+    /// Numbers the current thread if it wasn't already.
+    fn insert_current(&mut self) {
+        let current = std::thread::current();
+        self.insert(current.id(), current.name().map(str::to_owned));
+    }
+
+    fn insert(&mut self, id: ThreadId, name: Option<String>) {
+        if self.index(id).is_none() {
+            self.threads.push((id, name));
+        }
+    }
+
+    /// The resolved name of the thread, or the one captured when the manager first saw it.
+    fn name(&self, id: ThreadId) -> Option<String> {
+        if let Some(name) = self.resolver.as_ref().and_then(|resolver| resolver(id)) {
+            return Some(name);
+        }
+        self.threads
+            .iter()
+            .find(|(thread, _)| *thread == id)
+            .and_then(|(_, name)| name.clone())
+    }
+
+    /// `#2 (io-worker)`, or `#2` for unnamed threads, as reports show threads.
+    fn label(&self, id: ThreadId) -> String {
+        let number = match self.index(id) {
+            Some(index) => format!("#{}", index),
+            None => format!("{:?}", id),
+        };
+        match self.name(id) {
+            Some(name) => format!("{} ({})", number, name),
+            None => number,
+        }
+    }
+
+    /// `#2`, for single-line reports that prefer the thread's name when known.
+    fn short(&self, id: ThreadId) -> String {
+        match self.index(id) {
+            Some(index) => format!("#{}", index),
+            None => format!("{:?}", id),
+        }
+    }
+}
+
+/// The kind of access a thread requested.
+#[derive(Eq, PartialEq, Copy, Clone, Debug, Hash)]
+pub enum RequestType {
     Read,
     Write,
 }
 
+impl std::fmt::Display for RequestType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            RequestType::Read => "read",
+            RequestType::Write => "write",
+        })
+    }
+}
+
+/// Why a request couldn't be granted, as of the last time its thread checked the lock.
+#[derive(Eq, PartialEq, Copy, Clone, Debug, Hash)]
+pub enum WaitReason {
+    /// The lock is held exclusively: by a writer, or a mutex's holder.
+    WaitingForWriter,
+    /// A write request waits for this many readers to release the lock.
+    WaitingForReaders(usize),
+    /// A read request waits for one of the lock's readers to leave, as it has as many as `RwLock::new_bounded` allows.
+    ReaderLimit(usize),
+}
+
+impl std::fmt::Display for WaitReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            WaitReason::WaitingForWriter => write!(f, "waiting for the writer to release it"),
+            WaitReason::WaitingForReaders(1) => write!(f, "waiting for 1 reader to drain"),
+            WaitReason::WaitingForReaders(readers) => {
+                write!(f, "waiting for {} readers to drain", readers)
+            }
+            WaitReason::ReaderLimit(readers) => {
+                write!(f, "waiting for one of its {} readers to leave", readers)
+            }
+        }
+    }
+}
+
+/// A thread's pending request for a lock.
+pub struct Request {
+    pub(crate) kind: RequestType,
+    pub(crate) trace: Backtrace,
+    pub(crate) since: Instant,
+    pub(crate) thread_name: Option<String>,
+    /// The requesting thread's context when it started waiting, see `set_context`.
+    pub(crate) context: Option<Arc<str>>,
+    /// Where the request was made, see `LockManager::set_location_capture`.
+    pub(crate) location: Site,
+    pub(crate) reason: WaitReason,
+}
+
+/// The call site of an acquisition, recorded when `LockManager::set_location_capture` is enabled.
+pub(crate) type Site = Option<&'static std::panic::Location<'static>>;
+
+/// A thread holding a lock, with where and when it took it, and its context at that time.
+pub(crate) type Holder = (ThreadId, Backtrace, Instant, Option<Arc<str>>, Site);
+
+/// A thread waiting for a lock, as listed by `LockManager::blocked_threads`.
+#[derive(Debug)]
+pub struct BlockedThread {
+    pub thread: ThreadId,
+    pub thread_name: Option<String>,
+    pub lock: usize,
+    pub request: RequestType,
+    pub waiting_for: Duration,
+    pub reason: WaitReason,
+    pub backtrace: Backtrace,
+}
+
+/// Counters accumulated by a lock since its creation or its last reset.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct LockStats {
+    pub acquisitions: u64,
+    /// Acquisitions that couldn't succeed on the first attempt.
+    pub contentions: u64,
+    /// Number of completed critical sections, from acquisition to release.
+    pub holds: u64,
+    pub total_hold_time: Duration,
+    pub max_hold_time: Duration,
+    /// The most locks the acquiring thread already held when acquiring this one.
+    /// Locks often taken deep in a nest are prime suspects for ordering bugs.
+    pub max_nesting_depth: usize,
+    /// Time from the first attempt to the acquisition, see `LockManager::lock_latency_percentiles`.
+    pub(crate) waits: LatencyHistogram,
+}
+
+impl LockStats {
+    /// The average duration of a critical section, or zero if none completed yet.
+    pub fn avg_hold_time(&self) -> Duration {
+        if self.holds == 0 {
+            Duration::from_secs(0)
+        } else {
+            Duration::from_nanos((self.total_hold_time.as_nanos() / self.holds as u128) as u64)
+        }
+    }
+}
+
+/// An estimate of the memory retained by a manager, see `LockManager::memory_usage`.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct MemoryUsage {
+    /// Bytes taken by the registered locks' state, excluding backtraces and names.
+    pub locks: usize,
+    /// How many backtraces are retained for holders and waiters.
+    pub backtraces: usize,
+    pub backtrace_bytes: usize,
+    /// Bytes taken by lock and thread names, and contexts.
+    pub names: usize,
+}
+
+impl MemoryUsage {
+    pub fn total(&self) -> usize {
+        self.locks + self.backtrace_bytes + self.names
+    }
+}
+
 pub struct LockRepresentation {
     write_locked: bool,
-    pub(crate) readers: Vec<(ThreadId, Backtrace)>,
-    pub(crate) requests: Map<ThreadId, (RequestType, Backtrace)>,
+    /// Holders of the lock: the writer, or every reader.
+    pub(crate) readers: Vec<Holder>,
+    pub(crate) requests: Map<ThreadId, Request>,
+    /// Overrides the manager's analysis timeout for this lock only.
+    pub(crate) analysis_timeout: Option<Duration>,
+    pub(crate) name: Option<String>,
+    pub(crate) stats: LockStats,
+    /// Mirrors the lock's own poison flag, so that the manager can list poisoned locks.
+    pub(crate) poisoned: bool,
+    /// The last time the lock was acquired, released or waited for.
+    pub(crate) last_activity: Instant,
+    pub(crate) created: Instant,
+    /// The id of the manager the lock was registered with.
+    pub(crate) manager: Option<usize>,
+    /// Leaf locks must never be held while acquiring another lock.
+    pub(crate) leaf: bool,
+    /// Overrides `set_backtrace_capture` for this lock only.
+    pub(crate) capture_traces: Option<bool>,
+    /// Deadlocks are broken by the holder of their lowest priority lock.
+    pub(crate) priority: u32,
+    pub(crate) convoy: Convoy,
+    /// Read requests beyond this many concurrent readers wait, see `RwLock::new_bounded`.
+    pub(crate) max_readers: Option<usize>,
+}
+
+/// The contended acquisitions of a lock within the current convoy detection window.
+#[derive(Default)]
+pub(crate) struct Convoy {
+    since: Option<Instant>,
+    handoffs: usize,
+    /// Each participating thread, with where it last acquired the lock.
+    threads: Vec<(ThreadId, Backtrace)>,
+    warned: bool,
 }
 
 impl LockRepresentation {
     pub fn new() -> Self {
+        let now = Instant::now();
         LockRepresentation {
             write_locked: false,
             readers: Vec::new(),
             requests: Map::new(),
+            analysis_timeout: None,
+            name: None,
+            stats: LockStats::default(),
+            poisoned: false,
+            last_activity: now,
+            created: now,
+            manager: None,
+            leaf: false,
+            capture_traces: None,
+            priority: 0,
+            convoy: Convoy::default(),
+            max_readers: None,
         }
     }
 
+    pub(crate) fn is_write_locked(&self) -> bool {
+        self.write_locked
+    }
+
     /// Returns `true` if write_lock succeeded
     pub fn try_write_lock(&mut self) -> bool {
         if self.readers.is_empty() {
             self.write_locked = true;
-            self.readers
-                .push((std::thread::current().id(), Backtrace::new_unresolved()));
+            self.last_activity = Instant::now();
+            self.readers.push((
+                std::thread::current().id(),
+                self.capture_trace(),
+                self.last_activity,
+                current_context(),
+                current_caller(),
+            ));
             self.unsubscribe();
+            self.count_acquisition();
             true
         } else {
             false
         }
     }
 
+    /// An unresolved trace of the current thread, or an empty one if capture is disabled for this lock.
+    fn capture_trace(&self) -> Backtrace {
+        if self
+            .capture_traces
+            .unwrap_or_else(|| CAPTURE_TRACES.load(Ordering::Relaxed))
+        {
+            Backtrace::new_unresolved()
+        } else {
+            Vec::new().into()
+        }
+    }
+
+    fn count_acquisition(&mut self) {
+        self.stats.acquisitions += 1;
+        let depth = with_held(|held| held.borrow().len());
+        self.stats.max_nesting_depth = self.stats.max_nesting_depth.max(depth);
+    }
+
     pub fn unsubscribe(&mut self) {
         self.requests.remove(&std::thread::current().id());
     }
 
-    pub fn subscribe_write(&mut self) {
+    /// Returns `true` if the write request wasn't already registered
+    pub fn subscribe_write(&mut self) -> bool {
         let id = std::thread::current().id();
-        if let Some((RequestType::Write, _)) = self.requests.get(&id) {
-            return;
+        if let Some(Request {
+            kind: RequestType::Write,
+            ..
+        }) = self.requests.get(&id)
+        {
+            return false;
         }
-        self.requests
-            .insert(id, (RequestType::Write, Backtrace::new_unresolved()));
+        self.requests.insert(
+            id,
+            Request {
+                kind: RequestType::Write,
+                trace: self.capture_trace(),
+                since: Instant::now(),
+                thread_name: std::thread::current().name().map(str::to_owned),
+                context: current_context(),
+                location: current_caller(),
+                reason: self.wait_reason(RequestType::Write),
+            },
+        );
+        true
     }
 
     /// Returns `true` if read_lock succeeded
     pub fn try_read_lock(&mut self) -> bool {
-        if self.write_locked {
+        if self.write_locked || self.readers_capped() {
             false
         } else {
-            self.readers
-                .push((std::thread::current().id(), Backtrace::new_unresolved()));
+            self.last_activity = Instant::now();
+            self.readers.push((
+                std::thread::current().id(),
+                self.capture_trace(),
+                self.last_activity,
+                current_context(),
+                current_caller(),
+            ));
             self.unsubscribe();
+            self.count_acquisition();
             true
         }
     }
 
-    pub fn subscribe_read(&mut self) {
+    /// Whether readers are kept out because the lock has as many as it may have.
+    pub(crate) fn readers_capped(&self) -> bool {
+        matches!(self.max_readers, Some(max) if self.readers.len() >= max)
+    }
+
+    /// Why a `request` can't be granted in the lock's current state.
+    pub(crate) fn wait_reason(&self, request: RequestType) -> WaitReason {
+        match request {
+            _ if self.write_locked => WaitReason::WaitingForWriter,
+            RequestType::Read => WaitReason::ReaderLimit(self.readers.len()),
+            RequestType::Write => WaitReason::WaitingForReaders(self.readers.len()),
+        }
+    }
+
+    /// Returns `true` if the read request wasn't already registered
+    pub fn subscribe_read(&mut self) -> bool {
         let id = std::thread::current().id();
-        if let Some((RequestType::Read, _)) = self.requests.get(&id) {
-            return;
+        if let Some(Request {
+            kind: RequestType::Read,
+            ..
+        }) = self.requests.get(&id)
+        {
+            return false;
+        }
+        self.requests.insert(
+            id,
+            Request {
+                kind: RequestType::Read,
+                trace: self.capture_trace(),
+                since: Instant::now(),
+                thread_name: std::thread::current().name().map(str::to_owned),
+                context: current_context(),
+                location: current_caller(),
+                reason: self.wait_reason(RequestType::Read),
+            },
+        );
+        true
+    }
+
+    pub(crate) fn try_lock(&mut self, request: RequestType) -> bool {
+        match request {
+            RequestType::Read => self.try_read_lock(),
+            RequestType::Write => self.try_write_lock(),
+        }
+    }
+
+    /// Subscribes the current thread, recording that it has been waiting `since`.
+    pub(crate) fn subscribe(&mut self, request: RequestType, since: Instant) -> bool {
+        let subscribed = match request {
+            RequestType::Read => self.subscribe_read(),
+            RequestType::Write => self.subscribe_write(),
+        };
+        let reason = self.wait_reason(request);
+        if let Some(pending) = self.requests.get_mut(&std::thread::current().id()) {
+            pending.since = since;
+            pending.reason = reason;
         }
-        self.requests
-            .insert(id, (RequestType::Read, Backtrace::new_unresolved()));
+        self.last_activity = Instant::now();
+        subscribed
+    }
+
+    /// Turns the current thread's write lock into a read lock, letting other readers in.
+    pub(crate) fn downgrade(&mut self) {
+        self.write_locked = false;
+        self.last_activity = Instant::now();
     }
 
     pub fn unlock(&mut self) {
+        self.unlock_for(std::thread::current().id())
+    }
+
+    /// Releases the lock on behalf of `id`, which may be another thread than the current one.
+    pub(crate) fn unlock_for(&mut self, id: ThreadId) {
         self.write_locked = false;
-        let id = std::thread::current().id();
-        if let Some(index) = self.readers.iter().position(|(i, _trace)| i == &id) {
-            self.readers.swap_remove(index);
+        self.last_activity = Instant::now();
+        if let Some(index) = self.readers.iter().position(|(i, _, _, _, _)| i == &id) {
+            let (_, _, since, _, _) = self.readers.swap_remove(index);
+            let held = since.elapsed();
+            self.stats.holds += 1;
+            self.stats.total_hold_time += held;
+            self.stats.max_hold_time = self.stats.max_hold_time.max(held);
         }
     }
 }
@@ -143,222 +517,2733 @@ impl Default for LockRepresentation {
     }
 }
 
+/// A lock's state, owned by the lock itself and shared with its manager once registered.
+#[derive(Default)]
+pub struct RepresentationCell(std::sync::Mutex<LockRepresentation>);
+
+impl RepresentationCell {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A deadlock report panics while holding representations, which shouldn't make them unusable.
+    pub fn lock(&self) -> MutexGuard<'_, LockRepresentation> {
+        self.0.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Locks the representation only if no other thread is using it.
+    pub(crate) fn try_lock(&self) -> Option<MutexGuard<'_, LockRepresentation>> {
+        match self.0.try_lock() {
+            Ok(guard) => Some(guard),
+            Err(std::sync::TryLockError::Poisoned(poisoned)) => Some(poisoned.into_inner()),
+            Err(std::sync::TryLockError::WouldBlock) => None,
+        }
+    }
+}
+
+/// A dependence cycle involving the current thread, as found by the analysis.
+#[derive(Clone, Debug)]
+pub struct DeadlockReport {
+    pub cycle: Vec<DependencyNode>,
+    /// The human-readable report, with resolved backtraces.
+    pub text: String,
+    /// The same report on a single line, for alerting:
+    /// `DEADLOCK cycle_len=2 locks=[cache(2),5] threads=[io-worker,#3] at [frame,frame]`.
+    pub summary: String,
+    /// One line of `key=value` fields per thread of the cycle, for line-oriented log pipelines:
+    /// `deadlock lock=2 name=cache thread=io-worker request=write frame="src/io.rs:42 io::flush"`.
+    pub records: Vec<String>,
+    pub kind: DeadlockKind,
+}
+
+/// What kind of deadlock a `DeadlockReport` describes, for hooks to handle reentrances specially.
+#[derive(Clone, Debug)]
+pub enum DeadlockKind {
+    /// A thread requested a lock it already holds.
+    Reentrance(Box<ReentranceReport>),
+    /// Threads wait for each other in a cycle of two or more locks.
+    Cycle,
+}
+
+/// A thread requesting a lock it already holds, with resolved backtraces, empty if they weren't captured.
+#[derive(Clone, Debug)]
+pub struct ReentranceReport {
+    pub lock_id: usize,
+    pub lock_name: Option<String>,
+    pub original_acquire: Backtrace,
+    pub reentrant_attempt: Backtrace,
+}
+
+/// How a manager handles a thread requesting a lock it already holds, see `LockManager::set_reentrance_policy`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ReentrancePolicy {
+    /// Reported as a deadlock.
+    Panic,
+    /// Reported through the warning hook, after which `_checked` acquisitions fail instead of panicking.
+    /// Plain acquisitions, which can only report poisoning, still panic after the warning.
+    Warn,
+    /// Not reported: the thread waits forever, as it would with `std`'s locks.
+    Ignore,
+}
+
+/// How a manager writes its reports out, see `LockManager::set_report_format`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ReportFormat {
+    /// The full text, with headers and backtraces.
+    Text,
+    /// Only `DeadlockReport::summary`, for alerting.
+    Summary,
+    /// Only `DeadlockReport::records`, for line-oriented log pipelines.
+    Records,
+}
+
+/// Tweaks how `LockManager::acquire` waits for a lock.
+#[derive(Default)]
+pub(crate) struct WaitOptions<'a> {
+    /// Give up with `AcquireError::TimedOut` once this instant is past.
+    pub(crate) deadline: Option<Instant>,
+    /// Return detected deadlocks as `AcquireError::Deadlock` instead of reporting them and panicking.
+    pub(crate) return_deadlocks: bool,
+    /// Called every `interval` while waiting; breaking gives up with `AcquireError::Cancelled`.
+    pub(crate) progress: Option<Progress<'a>>,
+    /// Checked on every attempt; once set, gives up with `AcquireError::Cancelled`.
+    pub(crate) cancel: Option<&'a AtomicBool>,
+}
+
+pub(crate) struct Progress<'a> {
+    pub(crate) interval: Duration,
+    pub(crate) callback: std::cell::RefCell<&'a mut dyn FnMut() -> ControlFlow<()>>,
+}
+
+pub(crate) enum AcquireError {
+    TimedOut,
+    Deadlock(DeadlockReport),
+    Cancelled,
+}
+
+const UNREGISTERED: usize = usize::MAX;
+
+/// A lock's key within its manager, which is only assigned once the lock sees contention.
+pub(crate) struct LazyKey(AtomicUsize);
+
+impl LazyKey {
+    pub(crate) const fn new() -> Self {
+        LazyKey(AtomicUsize::new(UNREGISTERED))
+    }
+
+    /// A copy of an already registered key, for code that can't borrow the lock's own.
+    pub(crate) fn registered(key: usize) -> Self {
+        LazyKey(AtomicUsize::new(key))
+    }
+
+    pub(crate) fn get(&self) -> Option<usize> {
+        match self.0.load(Ordering::Relaxed) {
+            UNREGISTERED => None,
+            key => Some(key),
+        }
+    }
+}
+
 pub struct LockManagerInner {
     lock: AtomicCount,
     next_key: usize,
     analysis_timeout: std::time::Duration,
-    pub(crate) locks: Map<usize, LockRepresentation>,
+    panic_with_full_report: AtomicBool,
+    dry_run: AtomicBool,
+    /// Whether read-to-write upgrades only warn, see `LockManager::set_upgrade_warning`.
+    upgrade_warning: AtomicBool,
+    /// Whether acquisitions record their call site, see `LockManager::set_location_capture`.
+    capture_locations: AtomicBool,
+    /// A `ReentrancePolicy`, see `LockManager::set_reentrance_policy`.
+    reentrance_policy: AtomicU8,
+    first_failure_only: AtomicBool,
+    /// The sorted lock ids of the cycles reported so far, see `set_first_failure_only`.
+    reported_cycles: std::sync::Mutex<Set<Vec<usize>>>,
+    cycle_detector: Box<dyn CycleDetector + Send + Sync>,
+    deadlock_hook: Option<DeadlockHook>,
+    /// Only locks active within this window are analysed, see `LockManager::set_analysis_window`.
+    analysis_window: Option<Duration>,
+    report_header: String,
+    report_footer: String,
+    report_prefix: Option<String>,
+    /// Threads left out of the analysed graph, see `LockManager::exclude_thread`.
+    excluded_threads: Vec<ThreadId>,
+    /// Whether reports list every lock the reporting thread holds, see `LockManager::set_report_held_locks`.
+    report_held_locks: AtomicBool,
+    report_format: ReportFormat,
+    /// Where reports go instead of the `NO_DEADLOCKS` destination, see `LockManager::with_output`.
+    output: Option<std::sync::Mutex<Box<dyn std::io::Write + Send>>>,
+    /// Never held while locking anything else, so that it can be taken with or without the manager and representations.
+    thread_indices: std::sync::Mutex<ThreadIndices>,
+    /// Counted for `prometheus_metrics`.
+    analyses: AtomicUsize,
+    deadlocks: AtomicUsize,
+    pub(crate) locks: Map<usize, Arc<RepresentationCell>>,
 }
 
-pub struct LockManager(UnsafeCell<LockManagerInner>);
-unsafe impl Send for LockManager {}
-unsafe impl Sync for LockManager {}
-impl LockManagerInner {
-    fn new() -> Self {
-        LockManagerInner {
-            lock: AtomicCount::new(0),
-            next_key: 0,
-            locks: Map::new(),
-            analysis_timeout: std::time::Duration::from_secs(1),
-        }
+/// Stops the watchdog started by `LockManager::start_watchdog` when stopped or dropped.
+pub struct WatchdogHandle {
+    stop: Arc<AtomicBool>,
+    thread: Option<std::thread::JoinHandle<()>>,
+}
+
+impl WatchdogHandle {
+    /// Stops the watchdog and waits for its thread to exit.
+    pub fn stop(self) {
+        std::mem::drop(self)
     }
+}
 
-    fn with_analysis_timeout(analysis_timeout: std::time::Duration) -> Self {
-        LockManagerInner {
-            lock: AtomicCount::new(0),
-            next_key: 0,
-            locks: Map::new(),
-            analysis_timeout,
+impl Drop for WatchdogHandle {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            thread.thread().unpark();
+            let _ = thread.join();
         }
     }
-    pub fn analyse(&mut self) {
-        let mut graph = crate::graphs::Graph::new();
-        for (id, representation) in self.locks.iter() {
-            let lock_node = DependencyNode::Lock(*id);
-            for (reader, _trace) in representation.readers.iter() {
-                graph.add_edge_and_nodes(lock_node, DependencyNode::Thread(*reader));
-            }
-            for (requester, (request, _trace)) in representation.requests.iter() {
-                if representation.write_locked || *request == RequestType::Write {
-                    graph.add_edge_and_nodes(DependencyNode::Thread(*requester), lock_node);
-                }
-            }
-        }
-        if let Some(result) = graph.find_loop() {
-            self.handle_deadlock(&result);
-        }
+}
+
+pub struct LockManager {
+    id: usize,
+    inner: UnsafeCell<LockManagerInner>,
+    event_log_enabled: AtomicBool,
+    event_log: std::sync::Mutex<Option<EventLog>>,
+    warning_hook: std::sync::Mutex<Option<WarningHook>>,
+    try_lock_reentrance_detection: AtomicBool,
+    lock_order_enabled: AtomicBool,
+    lock_order: std::sync::Mutex<Graph<usize>>,
+    /// Orderings between named locks observed in previous runs, see `import_order_graph`.
+    historical_order: std::sync::Mutex<Graph<String>>,
+    /// While a watchdog runs, waiting threads subscribe immediately so that it can see them.
+    watchdogs: AtomicUsize,
+    /// Bumped by `cancel_all_waiters`: threads that started waiting before give up.
+    drains: AtomicUsize,
+    /// How many threads ever acquired one of this manager's locks.
+    threads: AtomicUsize,
+    /// Whether locks are registered as soon as they're created, see `enable_creation_tracking`.
+    creation_tracking: AtomicBool,
+    /// How many contended acquisitions within how long make a convoy, see `enable_convoy_detection`.
+    convoy_detection: std::sync::Mutex<Option<(usize, Duration)>>,
+    cross_thread_release_warning: AtomicBool,
+    /// The total time contended acquisitions waited, by call stack, see `folded_wait_stacks`.
+    wait_stacks: std::sync::Mutex<Map<Vec<usize>, (Backtrace, Duration)>>,
+    /// Sleeps taken right after acquiring a lock, see `inject_delay`.
+    delays_injected: AtomicBool,
+    injected_delays: std::sync::Mutex<Map<usize, Duration>>,
+}
+type WarningHook = Box<dyn Fn(&str) + Send + Sync>;
+type DeadlockHook = Box<dyn Fn(&DeadlockReport) + Send + Sync>;
+type ThreadNameResolver = Box<dyn Fn(ThreadId) -> Option<String> + Send + Sync>;
+
+static CAPTURE_TRACES: AtomicBool = AtomicBool::new(true);
+
+/// Live threads left out of every manager's analyses, see `exclude_current_thread`.
+static EXCLUDED_THREADS: std::sync::Mutex<Vec<ThreadId>> = std::sync::Mutex::new(Vec::new());
+/// The length of `EXCLUDED_THREADS`, so that analyses only lock it if some thread is excluded.
+static EXCLUDED_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+/// Removes the current thread from `EXCLUDED_THREADS` when it exits.
+struct Exclusion(ThreadId);
+
+impl Drop for Exclusion {
+    fn drop(&mut self) {
+        let mut excluded = EXCLUDED_THREADS
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        excluded.retain(|thread| *thread != self.0);
+        EXCLUDED_COUNT.store(excluded.len(), Ordering::Release);
     }
+}
 
-    #[allow(unused_must_use)]
-    fn handle_deadlock(&mut self, dependence_cycle: &[&DependencyNode]) {
-        let this_thread = DependencyNode::Thread(std::thread::current().id());
-        if !dependence_cycle.iter().any(|node| *node == &this_thread) {
+thread_local! {
+    static EXCLUSION: std::cell::RefCell<Option<Exclusion>> = const { std::cell::RefCell::new(None) };
+}
+
+/// Leaves the current thread out of every manager's analyses for the rest of its life, see `LockManager::exclude_thread`.
+pub fn exclude_current_thread() {
+    EXCLUSION.with(|exclusion| {
+        let mut exclusion = exclusion.borrow_mut();
+        if exclusion.is_some() {
             return;
         }
-        let (mut output, path): (Box<dyn std::io::Write>, _) =
-            if let Some(path) = std::env::var_os("NO_DEADLOCKS") {
-                match std::fs::OpenOptions::new()
-                    .append(true)
-                    .create(true)
-                    .open(&path)
-                {
-                    Ok(file) => (Box::new(file), path.to_str().unwrap().to_owned()),
-                    Err(_) => (Box::new(std::io::stderr()), "stderr".to_owned()),
-                }
-            } else {
-                (Box::new(std::io::stderr()), "stderr".to_owned())
-            };
-        writeln!(output, "=========== REPORT START ===========");
-        if dependence_cycle.len() == 2 {
-            writeln!(output, "A reentrance has been attempted, but `std::sync`'s locks are not reentrant. This results in a deadlock. dependence cycle: {:?}", dependence_cycle);
-            let lock_id = match dependence_cycle[0] {
-                DependencyNode::Lock(id) => id,
-                _ => {
-                    if let DependencyNode::Lock(id) = dependence_cycle[1] {
-                        id
-                    } else {
-                        unreachable!()
-                    }
-                }
-            };
-            let lock = self.locks.get(lock_id).unwrap();
-            let locked_trace = resolve_and_trim(&lock.readers[0].1);
-            let reentrance_trace =
-                resolve_and_trim(&lock.requests.get(&std::thread::current().id()).unwrap().1);
-            writeln!(
-                output,
-                "Lock taken at:\r\n{:?}\r\nReentrace at:\r\n{:?}",
-                locked_trace, reentrance_trace
-            );
-        } else {
-            writeln!(
-                output,
-                "A deadlock has been detected, here's the dependence cycle: {:?}",
-                dependence_cycle
-            );
-            for lock_id in dependence_cycle.iter().filter_map(|val| match *val {
-                DependencyNode::Lock(id) => Some(id),
-                _ => None,
-            }) {
-                let representation = self.locks.get(lock_id).unwrap();
-                writeln!(output, "LOCK {}:", lock_id);
-                writeln!(output, "BLOCKING:");
-                for (thread_id, (request, trace)) in representation.requests.iter() {
-                    writeln!(
-                        output,
-                        " THREAD {:?} requesting {} rights at:",
-                        thread_id,
-                        match request {
-                            RequestType::Read => "read",
-                            RequestType::Write => "write",
-                        }
-                    );
-                    writeln!(output, "{:?}", resolve_and_trim(trace));
-                }
-                writeln!(output, "BLOCKED BY:");
-                for (thread_id, trace) in representation.readers.iter() {
-                    writeln!(output, " THREAD {:?} blocked at:", thread_id);
-                    writeln!(output, "{:?}", resolve_and_trim(trace));
-                }
-            }
+        let thread = std::thread::current().id();
+        let mut excluded = EXCLUDED_THREADS
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        excluded.push(thread);
+        EXCLUDED_COUNT.store(excluded.len(), Ordering::Release);
+        *exclusion = Some(Exclusion(thread));
+    })
+}
+
+/// Enables or disables capturing backtraces on acquisitions and waits, which dominates the cost of uncontended locking.
+/// Reports then show where traces are missing. Locks created with `Mutex::new_traced` or `Mutex::new_untraced` ignore this.
+pub fn set_backtrace_capture(enabled: bool) {
+    CAPTURE_TRACES.store(enabled, Ordering::Relaxed);
+}
+
+thread_local! {
+    static CALLER: std::cell::Cell<Site> = const { std::cell::Cell::new(None) };
+}
+
+/// Runs `f`, recording `caller` as the call site of the acquisitions it makes if `manager` captures locations.
+pub(crate) fn at_caller<R, F: FnOnce() -> R>(
+    manager: &LockManager,
+    caller: &'static std::panic::Location<'static>,
+    f: F,
+) -> R {
+    struct Restore(Site);
+    impl Drop for Restore {
+        fn drop(&mut self) {
+            CALLER.with(|caller| caller.set(self.0));
         }
-        writeln!(output, "=========== REPORT END ===========");
-        writeln!(output);
-        panic!("DEADLOCK DETECTED! See {} for details", path);
     }
+    if !manager.capture_locations.load(Ordering::Relaxed) {
+        return f();
+    }
+    let _restore = Restore(CALLER.with(|current| current.replace(Some(caller))));
+    f()
 }
 
-impl Deref for LockManager {
-    type Target = LockManagerInner;
+fn current_caller() -> Site {
+    CALLER.with(|caller| caller.get())
+}
 
-    fn deref(&self) -> &Self::Target {
-        unsafe { &*self.0.get() }
-    }
+thread_local! {
+    static CONTEXT: std::cell::RefCell<Option<Arc<str>>> = const { std::cell::RefCell::new(None) };
 }
-impl LockManager {
-    pub fn new() -> Self {
-        LockManager(UnsafeCell::new(LockManagerInner::new()))
-    }
 
-    pub fn with_analysis_timeout(analysis_timeout: std::time::Duration) -> Self {
-        LockManager(UnsafeCell::new(LockManagerInner::with_analysis_timeout(
-            analysis_timeout,
-        )))
-    }
+/// Tags the current thread's subsequent lock operations with `id`, such as the id of the request it handles,
+/// so that deadlock reports show it next to the thread: `THREAD #2 (context: request-abc123)`.
+pub fn set_context<S: AsRef<str>>(id: S) {
+    CONTEXT.with(|context| *context.borrow_mut() = Some(id.as_ref().into()));
+}
 
-    pub fn analysis_timeout(&self) -> std::time::Duration {
-        unsafe { (*self.0.get()).analysis_timeout }
-    }
+/// Removes the current thread's context, see `set_context`.
+pub fn clear_context() {
+    CONTEXT.with(|context| *context.borrow_mut() = None);
+}
 
-    pub fn get_global_manager() -> Arc<Self> {
-        let manager = GLOBAL_MANAGER.load(Ordering::Relaxed);
-        if !manager.is_null() {
-            return unsafe { (*manager).clone() };
-        }
-        let new_manager = Box::into_raw(Box::new(Arc::new(LockManager::new())));
-        match GLOBAL_MANAGER.compare_exchange(
-            manager,
-            new_manager,
-            Ordering::Relaxed,
-            Ordering::Relaxed,
-        ) {
-            Err(manager) => unsafe {
-                _ = Box::from_raw(new_manager);
-                (*manager).clone()
-            },
-            Ok(_) => unsafe { (*new_manager).clone() },
+thread_local! {
+    static SUPPRESSED: std::cell::Cell<usize> = const { std::cell::Cell::new(0) };
+}
+
+/// Runs `f` without reporting deadlocks involving the current thread, silencing false positives
+/// from code paths known to be safe, such as carefully ordered handoffs.
+/// Real deadlocks within `f` are missed too: the thread then hangs silently, like with `std`'s locks.
+pub fn suppress_detection<R, F: FnOnce() -> R>(f: F) -> R {
+    struct Restore;
+    impl Drop for Restore {
+        fn drop(&mut self) {
+            SUPPRESSED.with(|suppressed| suppressed.set(suppressed.get() - 1));
         }
     }
+    SUPPRESSED.with(|suppressed| suppressed.set(suppressed.get() + 1));
+    let _restore = Restore;
+    f()
+}
 
-    pub fn create_lock(&self) -> usize {
-        let mut guard = self.write_lock();
-        let key = guard.next_key;
-        guard.next_key += 1;
-        guard.locks.insert(key, LockRepresentation::new());
-        key
-    }
+fn detection_suppressed() -> bool {
+    SUPPRESSED.with(|suppressed| suppressed.get() > 0)
+}
 
-    pub fn remove_lock(&self, key: &usize) {
-        let mut guard = self.write_lock();
-        guard.locks.remove(key);
+fn current_context() -> Option<Arc<str>> {
+    CONTEXT.with(|context| context.borrow().clone())
+}
+
+fn context_suffix(context: &Option<Arc<str>>) -> String {
+    match context {
+        Some(context) => format!(" (context: {})", context),
+        None => String::new(),
     }
+}
 
-    #[allow(dead_code)]
-    pub(crate) fn read_lock(&self) -> LockManagerReadGuard {
-        let mut state = self.lock.load(Ordering::Relaxed);
-        loop {
-            if state >= 0 {
-                match self.lock.compare_exchange(
-                    state,
-                    state + 1,
-                    Ordering::Relaxed,
-                    Ordering::Relaxed,
-                ) {
-                    Ok(_) => break,
-                    Err(new_state) => state = new_state,
+/// Makes guards `!Send` but `Sync`, like `std`'s: locks must be released by the thread that acquired them.
+pub(crate) type NotSend = std::marker::PhantomData<MutexGuard<'static, ()>>;
+
+/// A lock held by the current thread.
+struct Held {
+    /// The acquiring manager's `LockManager::id`.
+    manager: usize,
+    representation: Arc<RepresentationCell>,
+    /// Only known if the lock was registered when acquired, which lock order recording ensures.
+    key: Option<usize>,
+}
+
+thread_local! {
+    /// The ids of the managers whose `threads` count the current thread.
+    static COUNTED: std::cell::RefCell<Vec<usize>> = const { std::cell::RefCell::new(Vec::new()) };
+}
+
+thread_local! {
+    /// Every lock the current thread holds, up to the cross-thread releases it hasn't caught up with yet.
+    static HELD: std::cell::RefCell<Vec<Held>> = const { std::cell::RefCell::new(Vec::new()) };
+}
+
+/// Locks released by other threads than their holders, which must drop them from their `HELD` set.
+/// Guards are `!Send`, so these only come from guards unsafely smuggled across threads.
+static CROSS_THREAD_RELEASES: std::sync::Mutex<Vec<(ThreadId, Arc<RepresentationCell>)>> =
+    std::sync::Mutex::new(Vec::new());
+/// The length of `CROSS_THREAD_RELEASES`, checked without locking it.
+static PENDING_RELEASES: AtomicUsize = AtomicUsize::new(0);
+
+/// Runs `f` on the current thread's held locks, once the releases other threads made on its behalf are applied.
+fn with_held<R, F: FnOnce(&std::cell::RefCell<Vec<Held>>) -> R>(f: F) -> R {
+    if PENDING_RELEASES.load(Ordering::Acquire) != 0 {
+        let thread = std::thread::current().id();
+        let released: Vec<Arc<RepresentationCell>> = {
+            let mut pending = CROSS_THREAD_RELEASES
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner);
+            let mut released = Vec::new();
+            pending.retain(|(holder, representation)| {
+                let mine = *holder == thread;
+                if mine {
+                    released.push(representation.clone());
                 }
-            } else {
-                std::hint::spin_loop();
-                state = self.lock.load(Ordering::Relaxed);
-            }
+                !mine
+            });
+            PENDING_RELEASES.store(pending.len(), Ordering::Release);
+            released
+        };
+        for representation in released {
+            forget_held(&representation);
         }
-        LockManagerReadGuard { inner: self }
     }
+    HELD.with(f)
+}
 
-    pub(crate) fn write_lock(&self) -> LockManagerWriteGuard {
-        while self
-            .lock
-            .compare_exchange_weak(0, -1, Ordering::Relaxed, Ordering::Relaxed)
-            != Ok(0)
-        {}
-        LockManagerWriteGuard { inner: &self.0 }
+/// Whether the current thread holds `representation`, without locking it unless the thread-local set says so.
+pub(crate) fn held_by_current_thread(
+    representation: &Arc<RepresentationCell>,
+    request: RequestType,
+) -> bool {
+    let listed = with_held(|held| {
+        held.borrow()
+            .iter()
+            .any(|entry| Arc::ptr_eq(&entry.representation, representation))
+    });
+    listed && {
+        let state = representation.lock();
+        let id = std::thread::current().id();
+        (request == RequestType::Write || state.write_locked)
+            && state
+                .readers
+                .iter()
+                .any(|(reader, _, _, _, _)| *reader == id)
     }
 }
 
-impl Default for LockManager {
-    fn default() -> Self {
-        Self::new()
-    }
+fn forget_held(representation: &Arc<RepresentationCell>) {
+    HELD.with(|held| {
+        let mut held = held.borrow_mut();
+        if let Some(position) = held
+            .iter()
+            .rposition(|entry| Arc::ptr_eq(&entry.representation, representation))
+        {
+            held.remove(position);
+        }
+    });
+}
+
+/// Panics if the current thread holds a leaf lock other than `representation`, which it's about to acquire.
+fn check_leaves(representation: &Arc<RepresentationCell>) {
+    let held: Vec<Arc<RepresentationCell>> = with_held(|held| {
+        held.borrow()
+            .iter()
+            .filter(|entry| !Arc::ptr_eq(&entry.representation, representation))
+            .map(|entry| entry.representation.clone())
+            .collect()
+    });
+    let thread = std::thread::current().id();
+    for leaf in held {
+        let leaf = leaf.lock();
+        if !leaf.leaf {
+            continue;
+        }
+        let acquired = leaf
+            .readers
+            .iter()
+            .find(|(reader, _, _, _, _)| *reader == thread)
+            .map(|(_, trace, _, _, _)| resolve_and_trim(trace));
+        let name = leaf
+            .name
+            .clone()
+            .unwrap_or_else(|| "a leaf lock".to_owned());
+        std::mem::drop(leaf);
+        panic!(
+            "LOCK DISCIPLINE VIOLATION: {:?} acquires another lock while holding {}.\nLeaf lock taken at:\n{:?}\nOther lock requested at:\n{:?}",
+            thread,
+            name,
+            acquired,
+            Backtrace::new()
+        );
+    }
+}
+
+/// Panics if the current thread holds any instrumented lock, listing them.
+/// This only consults a thread-local set, so it's cheap enough for hot paths such as before blocking I/O.
+pub fn assert_no_locks_held() {
+    let held: Vec<String> = with_held(|held| {
+        held.borrow()
+            .iter()
+            .map(
+                |entry| match (&entry.representation.lock().name, entry.key) {
+                    (Some(name), _) => name.clone(),
+                    (None, Some(key)) => format!("lock {}", key),
+                    (None, None) => "an unregistered lock".to_owned(),
+                },
+            )
+            .collect()
+    });
+    if !held.is_empty() {
+        panic!(
+            "{:?} still holds {} lock(s): {}",
+            std::thread::current().id(),
+            held.len(),
+            held.join(", ")
+        );
+    }
+}
+unsafe impl Send for LockManager {}
+unsafe impl Sync for LockManager {}
+impl LockManagerInner {
+    fn thread_indices(&self) -> MutexGuard<'_, ThreadIndices> {
+        self.thread_indices
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn new() -> Self {
+        Self::with_analysis_timeout(std::time::Duration::from_secs(1))
+    }
+
+    fn with_analysis_timeout(analysis_timeout: std::time::Duration) -> Self {
+        LockManagerInner {
+            lock: AtomicCount::new(0),
+            next_key: 0,
+            locks: Map::new(),
+            analysis_timeout,
+            panic_with_full_report: AtomicBool::new(false),
+            dry_run: AtomicBool::new(false),
+            upgrade_warning: AtomicBool::new(false),
+            capture_locations: AtomicBool::new(false),
+            reentrance_policy: AtomicU8::new(ReentrancePolicy::Panic as u8),
+            first_failure_only: AtomicBool::new(false),
+            reported_cycles: std::sync::Mutex::new(Set::new()),
+            cycle_detector: Box::new(DepthFirstDetector),
+            deadlock_hook: None,
+            analysis_window: None,
+            report_header: "=========== REPORT START ===========".to_owned(),
+            report_footer: "=========== REPORT END ===========".to_owned(),
+            report_prefix: None,
+            excluded_threads: Vec::new(),
+            report_held_locks: AtomicBool::new(false),
+            report_format: ReportFormat::Text,
+            output: None,
+            thread_indices: std::sync::Mutex::new(ThreadIndices::default()),
+            analyses: AtomicUsize::new(0),
+            deadlocks: AtomicUsize::new(0),
+        }
+    }
+    /// Returns whether a deadlock was reported, which only returns in dry run mode.
+    pub fn analyse(&mut self) -> bool {
+        match self.find_deadlock() {
+            Some(report) => {
+                self.handle_deadlock(&report);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Builds the dependency graph, returning a report if the current thread is part of a cycle,
+    /// unless it's within `suppress_detection`.
+    pub fn find_deadlock(&mut self) -> Option<DeadlockReport> {
+        if detection_suppressed() {
+            return None;
+        }
+        self.find_cycle(Some(std::thread::current().id()))
+    }
+
+    /// Builds the dependency graph, returning a report if `thread` is part of a cycle,
+    /// or if there is any cycle when `thread` is `None`.
+    pub(crate) fn find_cycle(&mut self, thread: Option<ThreadId>) -> Option<DeadlockReport> {
+        instrumentation::analysis();
+        self.analyses.fetch_add(1, Ordering::Relaxed);
+        let representations = self.lock_representations();
+        let graph = self.dependency_graph(&representations);
+        let cycle = self.cycle_detector.find(&graph)?;
+        if let Some(thread) = thread {
+            // Other threads of the cycle keep analysing: the one holding its lowest priority lock reports it.
+            if !cycle.contains(&DependencyNode::Thread(thread))
+                || !victims(&cycle, &representations).contains(&thread)
+            {
+                return None;
+            }
+        }
+        instrumentation::deadlock();
+        self.deadlocks.fetch_add(1, Ordering::Relaxed);
+        let held_locks = self.report_held_locks.load(Ordering::Relaxed)
+            && thread == Some(std::thread::current().id());
+        Some(self.deadlock_report(cycle, &representations, held_locks))
+    }
+
+    /// Locks the representations of the registered locks, leaving out those idle for longer than the analysis window.
+    fn lock_representations(&self) -> Map<usize, MutexGuard<'_, LockRepresentation>> {
+        // Representations are locked in key order, so that the graph is built from a consistent snapshot.
+        let mut keys: Vec<usize> = self.locks.keys().cloned().collect();
+        keys.sort_unstable();
+        let mut representations = Map::new();
+        let now = Instant::now();
+        for key in keys {
+            let representation = self.locks.get(&key).unwrap().lock();
+            let idle = matches!(self.analysis_window, Some(window) if now.saturating_duration_since(representation.last_activity) > window);
+            if !idle {
+                representations.insert(key, representation);
+            }
+        }
+        representations
+    }
+
+    fn dependency_graph(
+        &self,
+        representations: &Map<usize, MutexGuard<'_, LockRepresentation>>,
+    ) -> Graph<DependencyNode> {
+        let excluded = if EXCLUDED_COUNT.load(Ordering::Acquire) == 0 {
+            Vec::new()
+        } else {
+            EXCLUDED_THREADS
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .clone()
+        };
+        let included = |thread: &ThreadId| {
+            !self.excluded_threads.contains(thread) && !excluded.contains(thread)
+        };
+        let mut graph = Graph::new();
+        for (id, representation) in representations.iter() {
+            let lock_node = DependencyNode::Lock(*id);
+            for (reader, _trace, _, _, _) in representation
+                .readers
+                .iter()
+                .filter(|reader| included(&reader.0))
+            {
+                graph.add_edge_and_nodes(lock_node, DependencyNode::Thread(*reader));
+            }
+            for (requester, request) in representation
+                .requests
+                .iter()
+                .filter(|(requester, _)| included(requester))
+            {
+                if representation.write_locked
+                    || request.kind == RequestType::Write
+                    || representation.readers_capped()
+                {
+                    graph.add_edge_and_nodes(DependencyNode::Thread(*requester), lock_node);
+                }
+            }
+        }
+        graph
+    }
+
+    /// With `held_locks`, the report also lists the locks held by the current thread.
+    fn deadlock_report(
+        &self,
+        cycle: Vec<DependencyNode>,
+        representations: &Map<usize, MutexGuard<'_, LockRepresentation>>,
+        held_locks: bool,
+    ) -> DeadlockReport {
+        let dependence_cycle: Vec<&DependencyNode> = cycle.iter().collect();
+        let threads = self.thread_indices();
+        let mut text = write_report(&dependence_cycle, representations, &threads);
+        if held_locks {
+            let snapshot: Vec<(usize, &Arc<RepresentationCell>)> = representations
+                .keys()
+                .map(|key| (*key, self.locks.get(key).unwrap()))
+                .collect();
+            write_held_locks(&mut text, &snapshot, representations, &threads);
+        }
+        let summary = write_summary(&dependence_cycle, representations, &threads);
+        let records = write_records(&dependence_cycle, representations, &threads);
+        std::mem::drop(threads);
+        let kind = deadlock_kind(&cycle, representations);
+        DeadlockReport {
+            cycle,
+            text,
+            summary,
+            records,
+            kind,
+        }
+    }
+
+    fn handle_deadlock(&self, report: &DeadlockReport) {
+        if self.first_failure_only.load(Ordering::Relaxed) {
+            let mut signature: Vec<usize> = report
+                .cycle
+                .iter()
+                .filter_map(|node| match node {
+                    DependencyNode::Lock(id) => Some(*id),
+                    DependencyNode::Thread(_) => None,
+                })
+                .collect();
+            signature.sort_unstable();
+            let mut reported = self
+                .reported_cycles
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner);
+            if reported.insert(signature, ()).is_some() {
+                std::mem::drop(reported);
+                if self.dry_run.load(Ordering::Relaxed) {
+                    return;
+                }
+                panic!(
+                    "DEADLOCK DETECTED! The same locks already deadlocked, see their first report"
+                );
+            }
+        }
+        if let Some(hook) = &self.deadlock_hook {
+            hook(report);
+        }
+        let path = self.write_to_output(report);
+        if self.dry_run.load(Ordering::Relaxed) {
+            return;
+        }
+        if self.panic_with_full_report.load(Ordering::Relaxed) {
+            panic!("DEADLOCK DETECTED!\n{}", report.text);
+        }
+        panic!("DEADLOCK DETECTED! See {} for details", path);
+    }
+
+    /// Writes the report to `testing::capture_deadlock_report` if it's running, to the manager's output if it has one,
+    /// otherwise to the `NO_DEADLOCKS` file if set, or to stderr, and returns where it went.
+    fn write_to_output(&self, report: &DeadlockReport) -> String {
+        if crate::testing::capturing() {
+            let mut captured = Vec::new();
+            self.emit_report(&mut captured, report);
+            crate::testing::capture(String::from_utf8_lossy(&captured).into_owned());
+            return "the captured report".to_owned();
+        }
+        if let Some(output) = &self.output {
+            let mut output = output.lock().unwrap_or_else(PoisonError::into_inner);
+            self.emit_report(&mut **output, report);
+            let _ = output.flush();
+            return "the manager's output".to_owned();
+        }
+        let (mut output, path) = report_output(
+            std::env::var_os("NO_DEADLOCKS").as_deref(),
+            &mut std::io::stderr(),
+        );
+        self.emit_report(&mut output, report);
+        path
+    }
+
+    #[allow(unused_must_use)]
+    fn emit_report(&self, output: &mut dyn std::io::Write, report: &DeadlockReport) {
+        match self.report_format {
+            ReportFormat::Text => {}
+            ReportFormat::Summary => {
+                writeln!(output, "{}", report.summary);
+                return;
+            }
+            ReportFormat::Records => {
+                for record in report.records.iter() {
+                    writeln!(output, "{}", record);
+                }
+                return;
+            }
+        }
+        writeln!(output, "{}", self.report_header);
+        if let Some(prefix) = &self.report_prefix {
+            writeln!(output, "{}", prefix);
+        }
+        write!(output, "{}", report.text);
+        writeln!(output, "{}", self.report_footer);
+        writeln!(output);
+    }
+}
+
+/// Picks where reports go from the value of `NO_DEADLOCKS`: `stdout`, `stderr`, or a file to append to.
+/// Falls back to stderr, explaining why on `notices`, if the file can't be opened.
+fn report_output(
+    setting: Option<&std::ffi::OsStr>,
+    notices: &mut dyn std::io::Write,
+) -> (Box<dyn std::io::Write>, String) {
+    let path = match setting {
+        None => return (Box::new(std::io::stderr()), "stderr".to_owned()),
+        Some(path) if path == "stderr" => {
+            return (Box::new(std::io::stderr()), "stderr".to_owned())
+        }
+        Some(path) if path == "stdout" => {
+            return (Box::new(std::io::stdout()), "stdout".to_owned())
+        }
+        Some(path) => path,
+    };
+    match std::fs::OpenOptions::new()
+        .append(true)
+        .create(true)
+        .open(path)
+    {
+        Ok(file) => (Box::new(file), path.to_string_lossy().into_owned()),
+        Err(error) => {
+            let _ = writeln!(
+                notices,
+                "no_deadlocks: couldn't open NO_DEADLOCKS={:?} ({}), writing the report to stderr instead",
+                path, error
+            );
+            (Box::new(std::io::stderr()), "stderr".to_owned())
+        }
+    }
+}
+
+impl Deref for LockManager {
+    type Target = LockManagerInner;
+
+    fn deref(&self) -> &Self::Target {
+        unsafe { &*self.inner.get() }
+    }
+}
+impl LockManager {
+    pub fn new() -> Self {
+        Self::from_inner(LockManagerInner::new())
+    }
+
+    pub fn with_analysis_timeout(analysis_timeout: std::time::Duration) -> Self {
+        Self::from_inner(LockManagerInner::with_analysis_timeout(analysis_timeout))
+    }
+
+    /// A manager writing its reports to `output`, regardless of `NO_DEADLOCKS`.
+    pub fn with_output(output: Box<dyn std::io::Write + Send>) -> Self {
+        let mut inner = LockManagerInner::new();
+        inner.output = Some(std::sync::Mutex::new(output));
+        Self::from_inner(inner)
+    }
+
+    fn from_inner(inner: LockManagerInner) -> Self {
+        LockManager {
+            id: NEXT_MANAGER_ID.fetch_add(1, Ordering::Relaxed),
+            inner: UnsafeCell::new(inner),
+            event_log_enabled: AtomicBool::new(false),
+            event_log: std::sync::Mutex::new(None),
+            warning_hook: std::sync::Mutex::new(None),
+            try_lock_reentrance_detection: AtomicBool::new(false),
+            lock_order_enabled: AtomicBool::new(false),
+            lock_order: std::sync::Mutex::new(Graph::new()),
+            historical_order: std::sync::Mutex::new(Graph::new()),
+            watchdogs: AtomicUsize::new(0),
+            drains: AtomicUsize::new(0),
+            threads: AtomicUsize::new(0),
+            creation_tracking: AtomicBool::new(false),
+            convoy_detection: std::sync::Mutex::new(None),
+            cross_thread_release_warning: AtomicBool::new(false),
+            wait_stacks: std::sync::Mutex::new(Map::new()),
+            delays_injected: AtomicBool::new(false),
+            injected_delays: std::sync::Mutex::new(Map::new()),
+        }
+    }
+
+    /// Identifies this manager in diagnostics, unique within the process.
+    pub fn id(&self) -> usize {
+        self.id
+    }
+
+    /// Whether the current thread is the only one that ever acquired this manager's locks,
+    /// in which case nobody else could notify a condition variable it waits on.
+    pub(crate) fn used_by_current_thread_only(&self) -> bool {
+        self.threads.load(Ordering::Relaxed) == 1
+            && COUNTED.with(|counted| counted.borrow().contains(&self.id))
+    }
+
+    /// Panics if the lock was registered with another manager, which would mix up their keys.
+    fn check_owner(&self, state: &LockRepresentation) {
+        if let Some(owner) = state.manager {
+            if owner != self.id {
+                panic!(
+                    "lock belongs to manager {} but was used with manager {}",
+                    owner, self.id
+                );
+            }
+        }
+    }
+
+    pub fn analysis_timeout(&self) -> std::time::Duration {
+        unsafe { (*self.inner.get()).analysis_timeout }
+    }
+
+    /// When enabled, deadlock panics carry the whole report instead of pointing to where it was written.
+    pub fn set_panic_with_full_report(&self, enabled: bool) {
+        self.panic_with_full_report
+            .store(enabled, Ordering::Relaxed);
+    }
+
+    /// Restricts analyses to locks acquired, released or waited for within `window`, bounding the graph by the working set.
+    /// Waiting threads keep refreshing the locks they wait for, so a cycle can still be missed if one of them
+    /// isn't scheduled for longer than `window`: keep it well above scheduling delays. `None` analyses every lock.
+    pub fn set_analysis_window(&self, window: Option<Duration>) {
+        self.write_lock().analysis_window = window;
+    }
+
+    /// When enabled, reports end with every lock the reporting thread holds, including those outside the cycle,
+    /// with where each was acquired.
+    pub fn set_report_held_locks(&self, enabled: bool) {
+        self.report_held_locks.store(enabled, Ordering::Relaxed);
+    }
+
+    /// Selects how reports are written out, `ReportFormat::Text` by default. The deadlock hook still gets the full report.
+    pub fn set_report_format(&self, format: ReportFormat) {
+        self.write_lock().report_format = format;
+    }
+
+    /// Replaces the line written before each report, `=========== REPORT START ===========` by default.
+    pub fn set_report_header<S: Into<String>>(&self, header: S) {
+        self.write_lock().report_header = header.into();
+    }
+
+    /// Replaces the line written after each report, `=========== REPORT END ===========` by default.
+    pub fn set_report_footer<S: Into<String>>(&self, footer: S) {
+        self.write_lock().report_footer = footer.into();
+    }
+
+    /// Adds a line such as `build=1.2.3+abc` at the start of every written report.
+    pub fn set_report_prefix<S: Into<String>>(&self, prefix: S) {
+        self.write_lock().report_prefix = Some(prefix.into());
+    }
+
+    /// Leaves `thread`'s holds and waits out of the graphs this manager analyses, so that it's never part of a reported cycle.
+    /// Meant for background threads, such as loggers, whose waits always end eventually.
+    pub fn exclude_thread(&self, thread: ThreadId) {
+        let mut guard = self.write_lock();
+        if !guard.excluded_threads.contains(&thread) {
+            guard.excluded_threads.push(thread);
+        }
+    }
+
+    /// In dry run mode, deadlocks are reported as usual but never panic: the deadlocked threads keep waiting,
+    /// as they would with `std`'s locks. Each blocked acquisition reports its deadlock once.
+    pub fn set_dry_run(&self, enabled: bool) {
+        self.dry_run.store(enabled, Ordering::Relaxed);
+    }
+
+    /// When enabled, a thread requesting to write a lock it holds for reading is reported through the warning hook
+    /// instead of as a deadlock, and keeps waiting as it would with `std`'s locks.
+    /// This eases migrating code that relies on such upgrades, until they're fixed and this is disabled again.
+    pub fn set_upgrade_warning(&self, enabled: bool) {
+        self.upgrade_warning.store(enabled, Ordering::Relaxed);
+    }
+
+    /// Enables or disables recording the file and line where this manager's locks' `lock`, `read` and `write` are called,
+    /// which reports then show as `acquired at src/foo.rs:42`. This is much cheaper than backtraces,
+    /// and often enough to find the acquisition: combine it with `set_backtrace_capture(false)`.
+    pub fn set_location_capture(&self, enabled: bool) {
+        self.capture_locations.store(enabled, Ordering::Relaxed);
+    }
+
+    /// Downgrades reentrance detection, for code that is reentrant by contract while it's migrated to `ReentrantMutex`.
+    /// Under `Warn`, `_checked` acquisitions return `LockError::Deadlock` once the warning is emitted,
+    /// while the others, which can only report poisoning, still panic. Defaults to `Panic`.
+    pub fn set_reentrance_policy(&self, policy: ReentrancePolicy) {
+        self.reentrance_policy
+            .store(policy as u8, Ordering::Relaxed);
+    }
+
+    fn reentrance_policy(&self) -> ReentrancePolicy {
+        match self.reentrance_policy.load(Ordering::Relaxed) {
+            policy if policy == ReentrancePolicy::Warn as u8 => ReentrancePolicy::Warn,
+            policy if policy == ReentrancePolicy::Ignore as u8 => ReentrancePolicy::Ignore,
+            _ => ReentrancePolicy::Panic,
+        }
+    }
+
+    /// When enabled, each cycle is only reported the first time its locks deadlock: later detections of a cycle
+    /// between the same locks still panic, but skip the deadlock hook and the output.
+    /// This keeps retry loops that catch the panic from flooding logs with the same report.
+    pub fn set_first_failure_only(&self, enabled: bool) {
+        self.first_failure_only.store(enabled, Ordering::Relaxed);
+    }
+
+    /// Starts accumulating which locks were held while acquiring which others, see `lock_order_graph`.
+    /// While enabled, every lock is given a key on its first acquisition.
+    pub fn enable_lock_order_recording(&self) {
+        self.lock_order_enabled.store(true, Ordering::Relaxed);
+    }
+
+    pub fn disable_lock_order_recording(&self) {
+        self.lock_order_enabled.store(false, Ordering::Relaxed);
+    }
+
+    /// Makes every thread sleep for `delay` right after acquiring the lock registered under `after_lock`,
+    /// widening the window in which another thread can take locks in the opposite order.
+    /// This is a testing aid to reproduce timing-dependent deadlocks reliably. A zero `delay` removes the injection.
+    pub fn inject_delay(&self, after_lock: usize, delay: Duration) {
+        let mut delays = self
+            .injected_delays
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        if delay == Duration::from_secs(0) {
+            delays.remove(&after_lock);
+        } else {
+            delays.insert(after_lock, delay);
+        }
+        self.delays_injected
+            .store(!delays.is_empty(), Ordering::Relaxed);
+    }
+
+    /// Registers every lock created from now on right away, instead of on its first contention,
+    /// so that `inactive_locks` can find those that are never used, such as locks leaked by a cycle of `Arc`s.
+    pub fn enable_creation_tracking(&self) {
+        self.creation_tracking.store(true, Ordering::Relaxed);
+    }
+
+    pub fn disable_creation_tracking(&self) {
+        self.creation_tracking.store(false, Ordering::Relaxed);
+    }
+
+    /// Warns about lock convoys: when a lock is acquired after waiting `handoffs` times within `window`,
+    /// by at least two threads, they likely spend more time queuing for it than doing anything else.
+    /// Each lock is reported at most once per window.
+    pub fn enable_convoy_detection(&self, handoffs: usize, window: Duration) {
+        *self
+            .convoy_detection
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner) = Some((handoffs, window));
+    }
+
+    pub fn disable_convoy_detection(&self) {
+        *self
+            .convoy_detection
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner) = None;
+    }
+
+    /// Counts a contended acquisition by the current thread, returning the header of a convoy warning
+    /// and the participating threads if the lock just turned out to convoy.
+    fn track_convoy(
+        &self,
+        key: &LazyKey,
+        state: &mut LockRepresentation,
+    ) -> Option<(String, Vec<(ThreadId, Backtrace)>)> {
+        let (handoffs, window) = (*self
+            .convoy_detection
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner))?;
+        let now = Instant::now();
+        let thread = std::thread::current().id();
+        let trace = state.readers.last()?.1.clone();
+        let convoy = &mut state.convoy;
+        let expired = match convoy.since {
+            Some(since) => now - since > window,
+            None => true,
+        };
+        if expired {
+            *convoy = Convoy {
+                since: Some(now),
+                ..Convoy::default()
+            };
+        }
+        convoy.handoffs += 1;
+        match convoy
+            .threads
+            .iter_mut()
+            .find(|(known, _)| *known == thread)
+        {
+            Some(participant) => participant.1 = trace,
+            None => convoy.threads.push((thread, trace)),
+        }
+        if convoy.warned || convoy.handoffs < handoffs || convoy.threads.len() < 2 {
+            return None;
+        }
+        convoy.warned = true;
+        let lock = lock_label(key, &state.name);
+        let header = format!(
+            "possible lock convoy on {}: {} threads had to wait for it {} times within {:?}.",
+            lock,
+            state.convoy.threads.len(),
+            state.convoy.handoffs,
+            window
+        );
+        Some((header, state.convoy.threads.clone()))
+    }
+
+    /// Warns with `message`, followed by where each of `participants` acquired the lock.
+    fn warn_with_traces(
+        &self,
+        mut message: String,
+        acquired: &str,
+        participants: Vec<(ThreadId, Backtrace)>,
+    ) {
+        let threads = self.thread_indices();
+        for (thread, trace) in participants {
+            message.push_str(&format!(
+                "\n THREAD {} {} at:\n{}",
+                threads.label(thread),
+                acquired,
+                display_trace(&trace)
+            ));
+        }
+        std::mem::drop(threads);
+        self.warn(&message);
+    }
+
+    fn record_wait_stack(&self, stack: Backtrace, waited: Duration) {
+        if stack.frames().is_empty() {
+            return;
+        }
+        let ips: Vec<usize> = stack
+            .frames()
+            .iter()
+            .map(|frame| frame.ip() as usize)
+            .collect();
+        let mut stacks = self
+            .wait_stacks
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        match stacks.get_mut(&ips) {
+            Some((_, total)) => *total += waited,
+            None => {
+                stacks.insert(ips, (stack, waited));
+            }
+        }
+    }
+
+    /// The time contended acquisitions waited, summed by acquisition call stack in the folded format read by
+    /// `flamegraph.pl` and `inferno`: one `root;caller;acquirer <microseconds>` line per stack, sorted by stack.
+    /// Frames of this crate are left out. Stacks are only known while backtrace capture is enabled.
+    pub fn folded_wait_stacks(&self) -> String {
+        let stacks: Vec<(Backtrace, Duration)> = self
+            .wait_stacks
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .values()
+            .cloned()
+            .collect();
+        let mut folded: Map<String, u128> = Map::new();
+        for (mut stack, waited) in stacks {
+            stack.resolve();
+            let frames: Vec<String> = stack
+                .frames()
+                .iter()
+                .rev()
+                .flat_map(|frame| frame.symbols().first())
+                .filter_map(|symbol| symbol.name())
+                .map(|name| format!("{:#}", name))
+                .filter(|name| {
+                    !INTERNAL_FRAMES
+                        .iter()
+                        .any(|prefix| name.starts_with(prefix))
+                })
+                .collect();
+            *folded.entry(frames.join(";")).or_insert(0) += waited.as_micros();
+        }
+        let mut lines: Vec<String> = folded
+            .into_iter()
+            .filter(|(_, micros)| *micros > 0)
+            .map(|(stack, micros)| format!("{} {}\n", stack, micros))
+            .collect();
+        lines.sort_unstable();
+        lines.concat()
+    }
+
+    /// Must be called when a lock is created.
+    pub(crate) fn on_create(&self, key: &LazyKey, representation: &Arc<RepresentationCell>) {
+        if self.creation_tracking.load(Ordering::Relaxed) {
+            self.register_lock(key, representation);
+        }
+    }
+
+    /// The keys of the registered locks that were created more than `threshold` ago and never acquired
+    /// or waited for since, in ascending order. See `enable_creation_tracking`.
+    pub fn inactive_locks(&self, threshold: Duration) -> Vec<usize> {
+        let guard = self.read_lock();
+        let mut keys: Vec<usize> = guard
+            .locks
+            .iter()
+            .filter(|(_, representation)| {
+                let state = representation.lock();
+                state.last_activity == state.created && state.created.elapsed() > threshold
+            })
+            .map(|(key, _)| *key)
+            .collect();
+        keys.sort_unstable();
+        keys
+    }
+
+    /// The observed lock hierarchy: an edge `a -> b` means lock `b` was acquired while holding lock `a`.
+    pub fn lock_order_graph(&self) -> Graph<usize> {
+        self.lock_order
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+
+    /// Cycles of `lock_order_graph`: locks taken in inconsistent orders, even if never concurrently,
+    /// deadlock as soon as two threads run the conflicting paths at the same time.
+    /// Unlike deadlock detection, this catches the bug from a single-threaded run.
+    pub fn latent_deadlocks(&self) -> Vec<Vec<usize>> {
+        self.lock_order_graph().find_all_loops()
+    }
+
+    /// Warns about each of the `latent_deadlocks`, returning whether there was any.
+    /// Call it at the end of a run recorded with `enable_lock_order_recording`.
+    pub fn report_latent_deadlocks(&self) -> bool {
+        let cycles = self.latent_deadlocks();
+        for cycle in cycles.iter() {
+            let names: Vec<String> = {
+                let guard = self.read_lock();
+                cycle
+                    .iter()
+                    .chain(cycle.first())
+                    .map(|key| {
+                        guard
+                            .locks
+                            .get(key)
+                            .and_then(|representation| representation.lock().name.clone())
+                            .unwrap_or_else(|| format!("lock {}", key))
+                    })
+                    .collect()
+            };
+            self.warn(&format!(
+                "latent deadlock: these locks were acquired in inconsistent orders, each while holding the previous one: {}",
+                names.join(" -> ")
+            ));
+        }
+        !cycles.is_empty()
+    }
+
+    /// Renders `lock_order_graph` as a Graphviz DOT diagram, labelling locks with their names when they have one.
+    pub fn lock_order_dot(&self) -> String {
+        use std::fmt::Write;
+        let order = self.lock_order_graph();
+        let mut keys: Vec<usize> = order.nodes().cloned().collect();
+        keys.sort_unstable();
+        let guard = self.read_lock();
+        let mut dot = String::from("digraph lock_order {\n");
+        for key in keys.iter() {
+            let name = guard
+                .locks
+                .get(key)
+                .and_then(|representation| representation.lock().name.clone())
+                .unwrap_or_else(|| format!("lock {}", key));
+            writeln!(dot, "    {} [label={:?}];", key, name).unwrap();
+        }
+        for key in keys.iter() {
+            let mut successors: Vec<usize> = order.successors(key).cloned().collect();
+            successors.sort_unstable();
+            for successor in successors {
+                writeln!(dot, "    {} -> {};", key, successor).unwrap();
+            }
+        }
+        dot.push('}');
+        dot
+    }
+
+    /// The edges of `lock_order_graph` between named locks, sorted by names.
+    fn named_lock_order(&self) -> Vec<(String, String)> {
+        let order = self.lock_order_graph();
+        let guard = self.read_lock();
+        let name = |key: &usize| {
+            guard
+                .locks
+                .get(key)
+                .and_then(|representation| representation.lock().name.clone())
+        };
+        let mut edges = Vec::new();
+        for key in order.nodes() {
+            let from = match name(key) {
+                Some(from) => from,
+                None => continue,
+            };
+            for successor in order.successors(key) {
+                if let Some(to) = name(successor) {
+                    edges.push((from.clone(), to));
+                }
+            }
+        }
+        edges.sort_unstable();
+        edges.dedup();
+        edges
+    }
+
+    /// Orderings between named locks observed during this run whose inverse was imported
+    /// through `import_order_graph`, as `(held, acquired)` pairs.
+    pub fn order_inversions(&self) -> Vec<(String, String)> {
+        let historical = self
+            .historical_order
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        self.named_lock_order()
+            .into_iter()
+            .filter(|(from, to)| historical.successors(to).any(|known| known == from))
+            .collect()
+    }
+
+    /// Merges the orderings saved by `export_order_graph` into the historical set checked by `order_inversions`.
+    /// Locks are identified by name across runs, so unnamed locks are ignored.
+    #[cfg(feature = "serde")]
+    pub fn import_order_graph<P: AsRef<std::path::Path>>(&self, path: P) -> std::io::Result<()> {
+        let invalid = |error: &str| std::io::Error::new(std::io::ErrorKind::InvalidData, error);
+        let file = std::fs::File::open(path)?;
+        let value: serde_json::Value = serde_json::from_reader(std::io::BufReader::new(file))?;
+        let mut historical = self
+            .historical_order
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        let edges = value["edges"]
+            .as_array()
+            .ok_or_else(|| invalid("missing \"edges\" array"))?;
+        for edge in edges {
+            match (edge[0].as_str(), edge[1].as_str()) {
+                (Some(from), Some(to)) => historical.add_edge_and_nodes(from.into(), to.into()),
+                _ => return Err(invalid("invalid edge")),
+            }
+        }
+        Ok(())
+    }
+
+    /// Saves the union of the imported orderings and those observed between named locks during this run,
+    /// so that later runs can `import_order_graph` it.
+    #[cfg(feature = "serde")]
+    pub fn export_order_graph<P: AsRef<std::path::Path>>(&self, path: P) -> std::io::Result<()> {
+        let mut edges = self.named_lock_order();
+        {
+            let historical = self
+                .historical_order
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner);
+            for from in historical.nodes() {
+                for to in historical.successors(from) {
+                    edges.push((from.clone(), to.clone()));
+                }
+            }
+        }
+        edges.sort_unstable();
+        edges.dedup();
+        let value = serde_json::json!({ "edges": edges });
+        std::fs::write(path, serde_json::to_vec_pretty(&value)?)
+    }
+
+    /// Calls `hook` with every deadlock report, before the deadlock is handled.
+    /// `hook` runs while the manager is locked, and must not wait for instrumented locks.
+    pub fn set_deadlock_hook<F: Fn(&DeadlockReport) + Send + Sync + 'static>(&self, hook: F) {
+        self.write_lock().deadlock_hook = Some(Box::new(hook));
+    }
+
+    /// Names threads in reports and snapshots with `resolver` when it returns a name, such as one from a thread registry,
+    /// instead of the name the thread had when first seen. It must not use this crate's locks.
+    pub fn set_thread_name_resolver<F: Fn(ThreadId) -> Option<String> + Send + Sync + 'static>(
+        &self,
+        resolver: F,
+    ) {
+        self.thread_indices().resolver = Some(Box::new(resolver));
+    }
+
+    /// Makes every thread currently waiting for one of this manager's locks give up, so that a hung process can shut down.
+    /// `_checked` and cancellable calls return `LockError::Cancelled`, while the others, which can't report errors, panic.
+    /// This is a blunt emergency tool: cancelled threads unwind or bail out mid-operation, possibly leaving data inconsistent.
+    pub fn cancel_all_waiters(&self) {
+        self.drains.fetch_add(1, Ordering::AcqRel);
+    }
+
+    /// Spawns a thread looking for dependence cycles between any threads every `interval`.
+    /// Each new cycle is passed to the deadlock hook if there is one, and written out like other reports otherwise.
+    /// Unlike blocked threads, the watchdog never panics.
+    pub fn start_watchdog(self: &Arc<Self>, interval: Duration) -> WatchdogHandle {
+        let stop = Arc::new(AtomicBool::new(false));
+        self.watchdogs.fetch_add(1, Ordering::Relaxed);
+        let thread = std::thread::Builder::new()
+            .name("no_deadlocks watchdog".into())
+            .spawn({
+                let manager = self.clone();
+                let stop = stop.clone();
+                move || {
+                    exclude_current_thread();
+                    let mut last_cycle: Vec<DependencyNode> = Vec::new();
+                    while !stop.load(Ordering::Relaxed) {
+                        std::thread::park_timeout(interval);
+                        let mut guard = manager.write_lock();
+                        let report = match guard.find_cycle(None) {
+                            Some(report) => report,
+                            None => {
+                                last_cycle.clear();
+                                continue;
+                            }
+                        };
+                        let known = report.cycle.len() == last_cycle.len()
+                            && report.cycle.iter().all(|node| last_cycle.contains(node));
+                        if !known {
+                            match &guard.deadlock_hook {
+                                Some(hook) => hook(&report),
+                                None => {
+                                    guard.write_to_output(&report);
+                                }
+                            }
+                            last_cycle = report.cycle;
+                        }
+                    }
+                    manager.watchdogs.fetch_sub(1, Ordering::Relaxed);
+                }
+            })
+            .expect("failed to spawn the watchdog thread");
+        WatchdogHandle {
+            stop,
+            thread: Some(thread),
+        }
+    }
+
+    /// Analyses the dependency graph right away, returning any dependence cycle, whichever threads it involves.
+    /// Unlike blocked threads and watchdogs, this reports nothing: what to do with the cycle is up to the caller.
+    pub fn find_any_deadlock(&self) -> Option<DeadlockReport> {
+        self.write_lock().find_cycle(None)
+    }
+
+    /// Every dependence cycle in the graph right now, whichever threads they involve, as separate reports.
+    /// Cycles sharing a node are reported once. Nothing is written, counted or panicked: meant for health checks.
+    pub fn current_deadlocks(&self) -> Vec<DeadlockReport> {
+        let guard = self.read_lock();
+        let representations = guard.lock_representations();
+        guard
+            .dependency_graph(&representations)
+            .find_all_loops()
+            .into_iter()
+            .map(|cycle| guard.deadlock_report(cycle, &representations, false))
+            .collect()
+    }
+
+    /// The number reports show as `THREAD #2`: threads are numbered from 0 in the order they first acquired
+    /// or waited for one of this manager's locks. `None` if the thread never did.
+    pub fn thread_index(&self, thread: ThreadId) -> Option<usize> {
+        self.thread_indices().index(thread)
+    }
+
+    /// Replaces the algorithm used to find dependence cycles, `DepthFirstDetector` by default.
+    pub fn set_cycle_detector<D: CycleDetector + Send + Sync + 'static>(&self, detector: D) {
+        self.write_lock().cycle_detector = Box::new(detector);
+    }
+
+    /// When enabled, the `try_*_checked` methods fail with `LockError::WouldDeadlockReentrant`
+    /// instead of `LockError::WouldBlock` when the current thread already holds the lock.
+    pub fn set_try_lock_reentrance_detection(&self, enabled: bool) {
+        self.try_lock_reentrance_detection
+            .store(enabled, Ordering::Relaxed);
+    }
+
+    /// Returns where the current thread took `representation`, if that makes `request` impossible and detection is enabled.
+    pub(crate) fn reentrance(
+        &self,
+        representation: &Arc<RepresentationCell>,
+        request: RequestType,
+    ) -> Option<Backtrace> {
+        if !self.try_lock_reentrance_detection.load(Ordering::Relaxed) {
+            return None;
+        }
+        if !held_by_current_thread(representation, request) {
+            return None;
+        }
+        let state = representation.lock();
+        let id = std::thread::current().id();
+        let (_, trace, _, _, _) = state
+            .readers
+            .iter()
+            .find(|(reader, _, _, _, _)| *reader == id)?;
+        let mut trace = trace.clone();
+        trace.resolve();
+        Some(trace)
+    }
+
+    /// Routes non-fatal warnings, such as locks held for too long, to `hook` instead of stderr.
+    pub fn set_warning_hook<F: Fn(&str) + Send + Sync + 'static>(&self, hook: F) {
+        *self
+            .warning_hook
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner) = Some(Box::new(hook));
+    }
+
+    pub(crate) fn warn(&self, message: &str) {
+        match &*self
+            .warning_hook
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+        {
+            Some(hook) => hook(message),
+            None => eprintln!("no_deadlocks warning: {}", message),
+        }
+    }
+
+    /// Appends a record of every subsequent lock state transition to the file at `path`.
+    /// The resulting log can be analysed offline with `no_deadlocks::replay`.
+    /// If a write fails, the log is disabled and a warning is emitted.
+    pub fn enable_event_log<P: AsRef<std::path::Path>>(&self, path: P) -> std::io::Result<()> {
+        let log = EventLog::open(path)?;
+        *self
+            .event_log
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner) = Some(log);
+        self.event_log_enabled.store(true, Ordering::Relaxed);
+        Ok(())
+    }
+
+    pub fn disable_event_log(&self) {
+        self.event_log_enabled.store(false, Ordering::Relaxed);
+        *self
+            .event_log
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner) = None;
+    }
+
+    fn record_event(&self, key: usize, event: LockEvent) {
+        self.record_event_as(thread_number(std::thread::current().id()), key, event)
+    }
+
+    /// A log that failed a write is closed: its replay would be wrong anyway, and warning for every event would flood the hook.
+    fn record_event_as(&self, thread: u64, key: usize, event: LockEvent) {
+        if !self.event_log_enabled.load(Ordering::Relaxed) {
+            return;
+        }
+        let mut log = self
+            .event_log
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        let result = match log.as_mut() {
+            Some(log) => log.record(thread, key, event),
+            None => Ok(()),
+        };
+        if let Err(error) = result {
+            self.event_log_enabled.store(false, Ordering::Relaxed);
+            *log = None;
+            std::mem::drop(log);
+            self.warn(&format!(
+                "disabled the event log after a failed write: {}",
+                error
+            ));
+        }
+    }
+
+    /// Must be called after the lock was successfully locked, while still holding its representation.
+    pub(crate) fn on_acquire(&self, key: &LazyKey, request: RequestType) {
+        instrumentation::lock_acquired();
+        if let Some(key) = key.get() {
+            self.record_event(key, LockEvent::Acquire(request));
+        }
+    }
+
+    /// Must be called after the lock was successfully locked, once its representation is released.
+    pub(crate) fn after_acquire(&self, key: &LazyKey, representation: &Arc<RepresentationCell>) {
+        let mut entry = Held {
+            manager: self.id,
+            representation: representation.clone(),
+            key: key.get(),
+        };
+        if self.lock_order_enabled.load(Ordering::Relaxed) {
+            // Recorded locks need a key, even if they're never contended.
+            let key = match entry.key {
+                Some(key) => key,
+                None => self.register_lock(key, representation),
+            };
+            entry.key = Some(key);
+            with_held(|held| {
+                let mut order = self
+                    .lock_order
+                    .lock()
+                    .unwrap_or_else(PoisonError::into_inner);
+                order.add_node(key);
+                for previous in held.borrow().iter().filter(|e| e.manager == self.id) {
+                    match previous.key {
+                        Some(previous) if previous != key => {
+                            order.add_edge_and_nodes(previous, key)
+                        }
+                        _ => {}
+                    }
+                }
+            });
+        }
+        with_held(|held| held.borrow_mut().push(entry));
+        COUNTED.with(|counted| {
+            let mut counted = counted.borrow_mut();
+            if !counted.contains(&self.id) {
+                counted.push(self.id);
+                self.threads.fetch_add(1, Ordering::Relaxed);
+                self.thread_indices().insert_current();
+            }
+        });
+        if let (true, Some(key)) = (self.delays_injected.load(Ordering::Relaxed), key.get()) {
+            let delay = self
+                .injected_delays
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .get(&key)
+                .cloned();
+            if let Some(delay) = delay {
+                std::thread::sleep(delay);
+            }
+        }
+    }
+
+    /// Must be called after the lock was unlocked, while still holding its representation.
+    pub(crate) fn on_release(&self, key: &LazyKey, representation: &Arc<RepresentationCell>) {
+        instrumentation::lock_released();
+        if let Some(key) = key.get() {
+            self.record_event(key, LockEvent::Release);
+        }
+        forget_held(representation);
+    }
+
+    /// Like `on_release`, for a lock released by another thread than `holder`: its held set catches up later.
+    fn on_release_for(
+        &self,
+        key: &LazyKey,
+        representation: &Arc<RepresentationCell>,
+        holder: ThreadId,
+    ) {
+        instrumentation::lock_released();
+        if let Some(key) = key.get() {
+            self.record_event_as(thread_number(holder), key, LockEvent::Release);
+        }
+        let mut pending = CROSS_THREAD_RELEASES
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        pending.push((holder, representation.clone()));
+        PENDING_RELEASES.store(pending.len(), Ordering::Release);
+    }
+
+    /// Releases the lock on behalf of `holder`, the thread that acquired it, which differs from the current thread
+    /// if the guard was smuggled to another one. Such releases are reported if `warn_on_cross_thread_release` is enabled.
+    pub(crate) fn release_for(
+        &self,
+        key: &LazyKey,
+        representation: &Arc<RepresentationCell>,
+        holder: ThreadId,
+    ) {
+        let mut state = representation.lock();
+        let cross_thread = holder != std::thread::current().id();
+        let acquired = if cross_thread && self.cross_thread_release_warning.load(Ordering::Relaxed)
+        {
+            state
+                .readers
+                .iter()
+                .find(|reader| reader.0 == holder)
+                .map(|reader| (reader.1.clone(), state.name.clone()))
+        } else {
+            None
+        };
+        state.unlock_for(holder);
+        if cross_thread {
+            self.on_release_for(key, representation, holder);
+        } else {
+            self.on_release(key, representation);
+        }
+        std::mem::drop(state);
+        if let Some((trace, name)) = acquired {
+            let lock = lock_label(key, &name);
+            let threads = self.thread_indices();
+            let message = format!(
+                "{} was released by thread {}, but acquired by thread {}: its guard was moved across threads. Acquired at:\n{}\nReleased at:\n{:?}",
+                lock,
+                threads.label(std::thread::current().id()),
+                threads.label(holder),
+                display_trace(&trace),
+                Backtrace::new()
+            );
+            std::mem::drop(threads);
+            self.warn(&message);
+        }
+    }
+
+    /// When enabled, dropping a guard on another thread than the one that acquired it, which requires
+    /// unsafely sending it, warns with where the lock was acquired and released. The lock is released either way.
+    pub fn warn_on_cross_thread_release(&self, enabled: bool) {
+        self.cross_thread_release_warning
+            .store(enabled, Ordering::Relaxed);
+    }
+
+    /// Must be called when a guard is leaked: the lock stays held forever, but no longer
+    /// orders the locks its holder acquires afterwards.
+    pub(crate) fn on_leak(&self, representation: &Arc<RepresentationCell>) {
+        forget_held(representation);
+    }
+
+    /// Must be called when a guard is dropped during a panic, once its representation is released.
+    /// Poisoned locks are registered, so that `poisoned_locks` can list them.
+    pub(crate) fn on_poison(&self, key: &LazyKey, representation: &Arc<RepresentationCell>) {
+        if key.get().is_none() {
+            self.register_lock(key, representation);
+        }
+        representation.lock().poisoned = true;
+    }
+
+    /// Must be called after a new request on the lock was registered, while still holding its representation.
+    pub(crate) fn on_subscribe(&self, key: &LazyKey, request: RequestType) {
+        if let Some(key) = key.get() {
+            self.record_event(key, LockEvent::Subscribe(request));
+        }
+    }
+
+    /// Must be called after a request was withdrawn without acquiring the lock, while still holding its representation.
+    pub(crate) fn on_unsubscribe(&self, key: &LazyKey) {
+        if let Some(key) = key.get() {
+            self.record_event(key, LockEvent::Unsubscribe);
+        }
+    }
+
+    pub fn get_global_manager() -> Arc<Self> {
+        // Counting this call as a user before loading the pointer makes `shutdown`, which swaps the pointer out
+        // before waiting for users to leave, wait for the clone too.
+        GLOBAL_MANAGER_USERS.fetch_add(1, Ordering::SeqCst);
+        let manager = GLOBAL_MANAGER.load(Ordering::SeqCst);
+        let cloned = (!manager.is_null()).then(|| unsafe { (*manager).clone() });
+        GLOBAL_MANAGER_USERS.fetch_sub(1, Ordering::SeqCst);
+        if let Some(manager) = cloned {
+            return manager;
+        }
+        // Setting up, like shutting down, happens under the lifecycle lock: nothing can free the manager meanwhile.
+        let _lifecycle = GLOBAL_MANAGER_LIFECYCLE
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        let mut manager = GLOBAL_MANAGER.load(Ordering::SeqCst);
+        if manager.is_null() {
+            manager = Box::into_raw(Box::new(Arc::new(LockManager::new())));
+            GLOBAL_MANAGER.store(manager, Ordering::SeqCst);
+        }
+        unsafe { (*manager).clone() }
+    }
+
+    /// Makes a lock visible to deadlock analysis, assigning it a key if it didn't have one yet.
+    pub(crate) fn register_lock(
+        &self,
+        key: &LazyKey,
+        representation: &Arc<RepresentationCell>,
+    ) -> usize {
+        let mut guard = self.write_lock();
+        // The key is published while holding the representation, so that every transition
+        // is either part of the snapshot logged here, or logged by the hooks afterwards.
+        let mut state = representation.lock();
+        self.check_owner(&state);
+        if let Some(key) = key.get() {
+            return key;
+        }
+        state.manager = Some(self.id);
+        let new_key = guard.next_key;
+        guard.next_key += 1;
+        guard.locks.insert(new_key, representation.clone());
+        key.0.store(new_key, Ordering::Relaxed);
+        self.record_event(new_key, LockEvent::Create);
+        let acquisition = if state.write_locked {
+            RequestType::Write
+        } else {
+            RequestType::Read
+        };
+        for (reader, _trace, _, _, _) in state.readers.iter() {
+            self.record_event_as(
+                thread_number(*reader),
+                new_key,
+                LockEvent::Acquire(acquisition),
+            );
+        }
+        for (requester, request) in state.requests.iter() {
+            self.record_event_as(
+                thread_number(*requester),
+                new_key,
+                LockEvent::Subscribe(request.kind),
+            );
+        }
+        new_key
+    }
+
+    /// Blocks until the lock is taken, analysing the dependency graph if that takes too long.
+    /// Never fails with the default `options`.
+    pub(crate) fn acquire(
+        &self,
+        key: &LazyKey,
+        representation: &Arc<RepresentationCell>,
+        request: RequestType,
+        options: &WaitOptions<'_>,
+    ) -> Result<(), AcquireError> {
+        let state = representation.lock();
+        self.check_owner(&state);
+        let timeout = state
+            .analysis_timeout
+            .unwrap_or_else(|| self.analysis_timeout());
+        std::mem::drop(state);
+        check_leaves(representation);
+        let start = Instant::now();
+        let drains = self.drains.load(Ordering::Acquire);
+        let mut contended = false;
+        let mut reported = false;
+        let mut unanalysed = false;
+        let mut next_progress = options
+            .progress
+            .as_ref()
+            .map(|progress| start + progress.interval);
+        let withdraw = || {
+            let mut state = representation.lock();
+            state.unsubscribe();
+            self.on_unsubscribe(key);
+        };
+
+        loop {
+            let mut state = representation.lock();
+            if state.try_lock(request) {
+                let waited = start.elapsed();
+                state.stats.waits.record(waited);
+                let (convoy, stack) = if contended {
+                    let stack = state.readers.last().map(|reader| reader.1.clone());
+                    (self.track_convoy(key, &mut state), stack)
+                } else {
+                    (None, None)
+                };
+                self.on_acquire(key, request);
+                std::mem::drop(state);
+                self.after_acquire(key, representation);
+                if let Some(stack) = stack {
+                    self.record_wait_stack(stack, waited);
+                }
+                if let Some((header, participants)) = convoy {
+                    self.warn_with_traces(header, "last acquired it", participants);
+                }
+                return Ok(());
+            }
+            let first_failure = !contended;
+            let mut capped = None;
+            if first_failure {
+                contended = true;
+                state.stats.contentions += 1;
+                instrumentation::lock_contended();
+                if request == RequestType::Read && state.readers_capped() {
+                    let readers: Vec<(ThreadId, Backtrace)> = state
+                        .readers
+                        .iter()
+                        .map(|reader| (reader.0, reader.1.clone()))
+                        .collect();
+                    capped = Some((lock_label(key, &state.name), readers));
+                }
+            }
+            std::mem::drop(state);
+            if first_failure {
+                self.thread_indices().insert_current();
+            }
+            if let Some((lock, readers)) = capped {
+                let header = format!(
+                    "{} has reached its maximum of {} readers, which may have been leaked.",
+                    lock,
+                    readers.len()
+                );
+                self.warn_with_traces(header, "acquired it", readers);
+            }
+
+            // Waiting can't resolve a reentrance: report it right away, without analysing the whole graph.
+            if first_failure
+                && !detection_suppressed()
+                && held_by_current_thread(representation, request)
+            {
+                let id = match key.get() {
+                    Some(id) => id,
+                    None => self.register_lock(key, representation),
+                };
+                let mut state = representation.lock();
+                let upgrade = request == RequestType::Write && !state.write_locked;
+                if state.subscribe(request, start) {
+                    self.on_subscribe(key, request);
+                }
+                let cycle = vec![
+                    DependencyNode::Lock(id),
+                    DependencyNode::Thread(std::thread::current().id()),
+                ];
+                let mut representations = Map::new();
+                representations.insert(id, state);
+                let dependence_cycle: Vec<&DependencyNode> = cycle.iter().collect();
+                let threads = self.thread_indices();
+                let mut text = write_report(&dependence_cycle, &representations, &threads);
+                let summary = write_summary(&dependence_cycle, &representations, &threads);
+                let records = write_records(&dependence_cycle, &representations, &threads);
+                let kind = deadlock_kind(&cycle, &representations);
+                std::mem::drop(representations);
+                if self.report_held_locks.load(Ordering::Relaxed) {
+                    write_held_locks(&mut text, &[(id, representation)], &Map::new(), &threads);
+                }
+                std::mem::drop(threads);
+                withdraw();
+                if upgrade && self.upgrade_warning.load(Ordering::Relaxed) {
+                    self.warn(&format!(
+                        "a thread requested to write a lock it holds for reading, which deadlocks with `std`'s RwLock:\n{}",
+                        text
+                    ));
+                    unanalysed = true;
+                    reported = true;
+                    continue;
+                }
+                let policy = self.reentrance_policy();
+                if !upgrade && policy == ReentrancePolicy::Ignore {
+                    unanalysed = true;
+                    reported = true;
+                    continue;
+                }
+                instrumentation::deadlock();
+                self.deadlocks.fetch_add(1, Ordering::Relaxed);
+                let report = DeadlockReport {
+                    cycle,
+                    text,
+                    summary,
+                    records,
+                    kind,
+                };
+                if !upgrade && policy == ReentrancePolicy::Warn {
+                    self.warn(&format!("a reentrance was attempted:\n{}", report.text));
+                }
+                if options.return_deadlocks {
+                    return Err(AcquireError::Deadlock(report));
+                }
+                self.read_lock().handle_deadlock(&report);
+                reported = true;
+            }
+
+            if matches!(options.deadline, Some(deadline) if Instant::now() > deadline) {
+                withdraw();
+                return Err(AcquireError::TimedOut);
+            }
+            if self.drains.load(Ordering::Acquire) != drains {
+                withdraw();
+                if !options.return_deadlocks {
+                    panic!("waiting for the lock was cancelled by LockManager::cancel_all_waiters");
+                }
+                return Err(AcquireError::Cancelled);
+            }
+            if matches!(options.cancel, Some(cancel) if cancel.load(Ordering::Relaxed)) {
+                withdraw();
+                return Err(AcquireError::Cancelled);
+            }
+            if let (Some(progress), Some(next)) = (&options.progress, &mut next_progress) {
+                if Instant::now() >= *next {
+                    *next += progress.interval;
+                    if (progress.callback.borrow_mut())().is_break() {
+                        withdraw();
+                        return Err(AcquireError::Cancelled);
+                    }
+                }
+            }
+            if key.get().is_none() {
+                self.register_lock(key, representation);
+            }
+            let analysis_due = Instant::now().duration_since(start) >= timeout;
+            // A warned upgrade or ignored reentrance waits out of sight, so that analyses don't report it after all.
+            if !unanalysed && (analysis_due || self.watchdogs.load(Ordering::Relaxed) > 0) {
+                let mut state = representation.lock();
+                if state.subscribe(request, start) {
+                    self.on_subscribe(key, request);
+                }
+            }
+            if analysis_due && !reported {
+                let mut guard = self.write_lock();
+                if !options.return_deadlocks {
+                    reported = guard.analyse();
+                } else if let Some(report) = guard.find_deadlock() {
+                    // Withdrawing before releasing the manager breaks the cycle before anyone else can report it.
+                    withdraw();
+                    std::mem::drop(guard);
+                    return Err(AcquireError::Deadlock(report));
+                }
+            }
+
+            std::thread::yield_now();
+        }
+    }
+
+    pub fn remove_lock(&self, key: &usize) {
+        let mut guard = self.write_lock();
+        guard.locks.remove(key);
+        self.record_event(*key, LockEvent::Remove);
+    }
+
+    /// Estimates the memory retained for the registered locks, most of which usually goes to backtraces.
+    pub fn memory_usage(&self) -> MemoryUsage {
+        fn trace_size(trace: &Backtrace) -> usize {
+            std::mem::size_of_val(trace.frames())
+        }
+        fn context_size(context: &Option<Arc<str>>) -> usize {
+            context.as_ref().map_or(0, |context| context.len())
+        }
+        let guard = self.read_lock();
+        let mut usage = MemoryUsage::default();
+        for representation in guard.locks.values() {
+            let representation = representation.lock();
+            usage.locks += std::mem::size_of::<RepresentationCell>()
+                + std::mem::size_of::<(usize, Arc<RepresentationCell>)>()
+                + representation.readers.capacity() * std::mem::size_of::<Holder>()
+                + representation.requests.len() * std::mem::size_of::<(ThreadId, Request)>()
+                + representation.stats.waits.heap_size();
+            usage.names += representation.name.as_ref().map_or(0, String::capacity);
+            for (_, trace, _, context, _) in representation.readers.iter() {
+                usage.backtraces += 1;
+                usage.backtrace_bytes += trace_size(trace);
+                usage.names += context_size(context);
+            }
+            for (_, request) in representation.requests.iter() {
+                usage.backtraces += 1;
+                usage.backtrace_bytes += trace_size(&request.trace);
+                usage.names += request.thread_name.as_ref().map_or(0, String::capacity)
+                    + context_size(&request.context);
+            }
+        }
+        usage
+    }
+
+    /// Returns the counters of the lock registered under `key`.
+    pub fn lock_stats(&self, key: usize) -> Option<LockStats> {
+        let guard = self.read_lock();
+        let stats = guard.locks.get(&key)?.lock().stats.clone();
+        Some(stats)
+    }
+
+    /// Estimates how long acquisitions of the lock registered under `key` waited, from their first attempt.
+    pub fn lock_latency_percentiles(&self, key: usize) -> Option<LatencyStats> {
+        let guard = self.read_lock();
+        let stats = guard.locks.get(&key)?.lock().stats.waits.stats();
+        Some(stats)
+    }
+
+    /// The keys of the locks that were poisoned by a panic while held, in ascending order.
+    pub fn poisoned_locks(&self) -> Vec<usize> {
+        let guard = self.read_lock();
+        let mut keys: Vec<usize> = guard
+            .locks
+            .iter()
+            .filter(|(_, representation)| representation.lock().poisoned)
+            .map(|(key, _)| *key)
+            .collect();
+        keys.sort_unstable();
+        keys
+    }
+
+    /// Zeroes the counters of the lock registered under `key`, keeping its name and settings.
+    /// Returns `false` if no such lock is registered.
+    pub fn reset_lock_stats(&self, key: usize) -> bool {
+        let guard = self.read_lock();
+        match guard.locks.get(&key) {
+            Some(representation) => {
+                representation.lock().stats = LockStats::default();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Formats the counters of the registered locks and of the manager in Prometheus' text exposition format,
+    /// ready to be served to a scraper. Locks are labeled by key, and by name if they have one.
+    /// Only registered locks are listed: a lock registers the first time a thread waits for it,
+    /// so locks that never saw contention are missing, and their acquisitions aren't counted anywhere.
+    pub fn prometheus_metrics(&self) -> String {
+        use std::fmt::Write;
+        let guard = self.read_lock();
+        let mut keys: Vec<usize> = guard.locks.keys().cloned().collect();
+        keys.sort_unstable();
+        let locks: Vec<(String, LockStats, usize)> = keys
+            .iter()
+            .map(|key| {
+                let representation = guard.locks.get(key).unwrap().lock();
+                let mut labels = format!("lock=\"{}\"", key);
+                if let Some(name) = &representation.name {
+                    let name = name
+                        .replace('\\', "\\\\")
+                        .replace('"', "\\\"")
+                        .replace('\n', "\\n");
+                    write!(labels, ",name=\"{}\"", name).unwrap();
+                }
+                let stats = representation.stats.clone();
+                (labels, stats, representation.readers.len())
+            })
+            .collect();
+        let mut text = String::new();
+        let mut family = |name: &str, kind: &str, help: &str, samples: Vec<(String, u64)>| {
+            writeln!(text, "# HELP {} {}", name, help).unwrap();
+            writeln!(text, "# TYPE {} {}", name, kind).unwrap();
+            for (labels, value) in samples {
+                if labels.is_empty() {
+                    writeln!(text, "{} {}", name, value).unwrap();
+                } else {
+                    writeln!(text, "{}{{{}}} {}", name, labels, value).unwrap();
+                }
+            }
+        };
+        let per_lock = |value: fn(&LockStats, usize) -> u64| -> Vec<(String, u64)> {
+            locks
+                .iter()
+                .map(|(labels, stats, holders)| (labels.clone(), value(stats, *holders)))
+                .collect()
+        };
+        family(
+            "no_deadlocks_lock_acquisitions_total",
+            "counter",
+            "Acquisitions of the lock.",
+            per_lock(|stats, _| stats.acquisitions),
+        );
+        family(
+            "no_deadlocks_lock_contentions_total",
+            "counter",
+            "Acquisitions of the lock that couldn't succeed on the first attempt.",
+            per_lock(|stats, _| stats.contentions),
+        );
+        family(
+            "no_deadlocks_lock_holders",
+            "gauge",
+            "Threads currently holding the lock.",
+            per_lock(|_, holders| holders as u64),
+        );
+        let held = locks.iter().filter(|(_, _, holders)| *holders > 0).count();
+        family(
+            "no_deadlocks_locks_held",
+            "gauge",
+            "Registered locks currently held.",
+            vec![(String::new(), held as u64)],
+        );
+        family(
+            "no_deadlocks_analyses_total",
+            "counter",
+            "Dependency graph analyses.",
+            vec![(String::new(), guard.analyses.load(Ordering::Relaxed) as u64)],
+        );
+        family(
+            "no_deadlocks_deadlocks_total",
+            "counter",
+            "Detected deadlocks.",
+            vec![(
+                String::new(),
+                guard.deadlocks.load(Ordering::Relaxed) as u64,
+            )],
+        );
+        text
+    }
+
+    /// Takes a consistent snapshot of which thread holds and waits for which lock.
+    pub fn snapshot_graph(&self) -> WaitForGraph {
+        let guard = self.read_lock();
+        let mut keys: Vec<usize> = guard.locks.keys().cloned().collect();
+        keys.sort_unstable();
+        let representations: Vec<_> = keys
+            .iter()
+            .map(|key| (*key, guard.locks.get(key).unwrap().lock()))
+            .collect();
+        let mut graph = WaitForGraph::default();
+        for (key, representation) in representations.iter() {
+            let lock_node = DependencyNode::Lock(*key);
+            graph.add_node(lock_node, representation.name.clone());
+            let held = if representation.write_locked {
+                RequestType::Write
+            } else {
+                RequestType::Read
+            };
+            for (reader, _trace, _, _, _) in representation.readers.iter() {
+                graph.add_edge(lock_node, DependencyNode::Thread(*reader), held);
+            }
+            for (requester, request) in representation.requests.iter() {
+                if representation.write_locked
+                    || request.kind == RequestType::Write
+                    || representation.readers_capped()
+                {
+                    let thread = DependencyNode::Thread(*requester);
+                    let name = self
+                        .thread_indices()
+                        .name(*requester)
+                        .or_else(|| request.thread_name.clone());
+                    graph.add_node(thread, name);
+                    graph.add_wait(thread, lock_node, request.kind, request.reason);
+                }
+            }
+        }
+        graph
+    }
+
+    /// Marks the lock registered under `key` as a leaf: acquiring another lock while holding it panics.
+    /// Returns `false` if no such lock is registered.
+    pub fn mark_leaf(&self, key: usize) -> bool {
+        match self.read_lock().locks.get(&key) {
+            Some(representation) => {
+                representation.lock().leaf = true;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Follows wait-for edges from `thread`: the lock it waits for, a thread holding that lock, the lock that one waits for...
+    /// The path ends at a thread that isn't waiting, or before a node would repeat if the chain loops into a cycle.
+    /// Only threads that waited long enough to subscribe are seen waiting, and only the first holder of shared locks is followed.
+    pub fn wait_chain(&self, thread: ThreadId) -> Vec<DependencyNode> {
+        let graph = self.snapshot_graph();
+        let mut chain = vec![DependencyNode::Thread(thread)];
+        while let Some(next) = graph
+            .edges
+            .iter()
+            .find(|edge| edge.from == *chain.last().unwrap())
+            .map(|edge| edge.to)
+        {
+            if chain.contains(&next) {
+                break;
+            }
+            chain.push(next);
+        }
+        chain
+    }
+
+    /// Lists every thread that has waited long enough to subscribe to a lock and is still waiting for it.
+    pub fn blocked_threads(&self) -> Vec<BlockedThread> {
+        let guard = self.read_lock();
+        let mut blocked = Vec::new();
+        for (key, representation) in guard.locks.iter() {
+            for (thread, request) in representation.lock().requests.iter() {
+                blocked.push(BlockedThread {
+                    thread: *thread,
+                    thread_name: request.thread_name.clone(),
+                    lock: *key,
+                    request: request.kind,
+                    waiting_for: request.since.elapsed(),
+                    reason: request.reason,
+                    backtrace: resolve_and_trim(&request.trace),
+                });
+            }
+        }
+        blocked
+    }
+
+    /// The threads subscribed to the lock registered under `key`, in the order they started waiting.
+    /// Locks aren't fair: this is the order in which waiters arrived, which a fair lock would serve them in,
+    /// but any of them may actually get the lock first.
+    pub fn wait_queue(&self, key: usize) -> Vec<ThreadId> {
+        let guard = self.read_lock();
+        let mut waiters: Vec<(Instant, ThreadId)> = match guard.locks.get(&key) {
+            Some(representation) => representation
+                .lock()
+                .requests
+                .iter()
+                .map(|(thread, request)| (request.since, *thread))
+                .collect(),
+            None => Vec::new(),
+        };
+        waiters.sort_by_key(|(since, _)| *since);
+        waiters.into_iter().map(|(_, thread)| thread).collect()
+    }
+
+    pub(crate) fn read_lock(&self) -> LockManagerReadGuard<'_> {
+        let mut backoff = Backoff::default();
+        let mut state = self.lock.load(Ordering::Relaxed);
+        loop {
+            if state >= 0 {
+                match self.lock.compare_exchange(
+                    state,
+                    state + 1,
+                    Ordering::Acquire,
+                    Ordering::Relaxed,
+                ) {
+                    Ok(_) => break,
+                    Err(new_state) => state = new_state,
+                }
+            } else {
+                backoff.snooze();
+                state = self.lock.load(Ordering::Relaxed);
+            }
+        }
+        LockManagerReadGuard { inner: self }
+    }
+
+    pub(crate) fn write_lock(&self) -> LockManagerWriteGuard<'_> {
+        let mut backoff = Backoff::default();
+        while self
+            .lock
+            .compare_exchange_weak(0, -1, Ordering::Acquire, Ordering::Relaxed)
+            != Ok(0)
+        {
+            backoff.snooze();
+        }
+        LockManagerWriteGuard { inner: &self.inner }
+    }
+}
+
+impl Default for LockManager {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Waits between attempts at the manager lock: spinning briefly, since it's usually held for short times,
+/// then yielding so that a descheduled holder can run instead of being starved by spinning waiters.
+#[derive(Default)]
+struct Backoff {
+    step: u32,
+}
+
+impl Backoff {
+    const SPIN_LIMIT: u32 = 6;
+
+    fn snooze(&mut self) {
+        if self.step < Self::SPIN_LIMIT {
+            for _ in 0..1 << self.step {
+                std::hint::spin_loop();
+            }
+            self.step += 1;
+        } else {
+            std::thread::yield_now();
+        }
+    }
+}
+
+#[allow(unused_must_use)]
+fn write_report(
+    dependence_cycle: &[&DependencyNode],
+    representations: &Map<usize, MutexGuard<LockRepresentation>>,
+    threads: &ThreadIndices,
+) -> String {
+    use std::fmt::Write;
+    let mut report = String::new();
+    if dependence_cycle.len() == 2 {
+        writeln!(report, "A reentrance has been attempted, but `std::sync`'s locks are not reentrant. This results in a deadlock. dependence cycle: {}", display_cycle(dependence_cycle, threads));
+        let lock_id = match dependence_cycle[0] {
+            DependencyNode::Lock(id) => id,
+            _ => {
+                if let DependencyNode::Lock(id) = dependence_cycle[1] {
+                    id
+                } else {
+                    unreachable!()
+                }
+            }
+        };
+        let lock = representations.get(lock_id).unwrap();
+        let locked_trace = display_site("acquired", lock.readers[0].4, &lock.readers[0].1);
+        let reentrant_thread = dependence_cycle
+            .iter()
+            .find_map(|node| match node {
+                DependencyNode::Thread(id) => Some(*id),
+                _ => None,
+            })
+            .unwrap();
+        let reentrance = lock.requests.get(&reentrant_thread).unwrap();
+        let reentrance_trace = display_site("requested", reentrance.location, &reentrance.trace);
+        if let Some(context) = &lock.readers[0].3 {
+            writeln!(report, "Context: {}", context);
+        }
+        writeln!(
+            report,
+            "Lock taken at:\r\n{}\r\nReentrace at:\r\n{}",
+            locked_trace, reentrance_trace
+        );
+    } else {
+        writeln!(
+            report,
+            "A deadlock has been detected, here's the dependence cycle: {}",
+            display_cycle(dependence_cycle, threads)
+        );
+        for lock_id in dependence_cycle.iter().filter_map(|val| match *val {
+            DependencyNode::Lock(id) => Some(id),
+            _ => None,
+        }) {
+            let representation = representations.get(lock_id).unwrap();
+            let priority = match representation.priority {
+                0 => String::new(),
+                priority => format!(" [priority {}]", priority),
+            };
+            match &representation.name {
+                Some(name) => writeln!(report, "LOCK {} ({}){}:", lock_id, name, priority),
+                None => writeln!(report, "LOCK {}{}:", lock_id, priority),
+            };
+            writeln!(report, "BLOCKING:");
+            for (thread_id, request) in representation.requests.iter() {
+                writeln!(
+                    report,
+                    " THREAD {}{} requesting {} rights, {}, at:",
+                    threads.label(*thread_id),
+                    context_suffix(&request.context),
+                    request.kind,
+                    request.reason
+                );
+                writeln!(
+                    report,
+                    "{}",
+                    display_site("requested", request.location, &request.trace)
+                );
+            }
+            writeln!(report, "BLOCKED BY:");
+            for (thread_id, trace, _, context, location) in representation.readers.iter() {
+                writeln!(
+                    report,
+                    " THREAD {}{} blocked at:",
+                    threads.label(*thread_id),
+                    context_suffix(context)
+                );
+                writeln!(report, "{}", display_site("acquired", *location, trace));
+            }
+        }
+    }
+    report
+}
+
+/// How warnings refer to a lock: by name if it has one, by key if it's registered.
+fn lock_label(key: &LazyKey, name: &Option<String>) -> String {
+    match (key.get(), name) {
+        (_, Some(name)) => name.clone(),
+        (Some(key), None) => format!("lock {}", key),
+        (None, None) => "a lock".to_owned(),
+    }
+}
+
+/// Tells reentrances, cycles between a lock and the thread holding it, from other deadlocks.
+fn deadlock_kind(
+    cycle: &[DependencyNode],
+    representations: &Map<usize, MutexGuard<LockRepresentation>>,
+) -> DeadlockKind {
+    let (mut lock_id, mut thread) = (None, None);
+    for node in cycle {
+        match node {
+            DependencyNode::Lock(id) => lock_id = Some(*id),
+            DependencyNode::Thread(id) => thread = Some(*id),
+        }
+    }
+    let (lock_id, thread) = match (cycle.len(), lock_id, thread) {
+        (2, Some(lock_id), Some(thread)) => (lock_id, thread),
+        _ => return DeadlockKind::Cycle,
+    };
+    let no_trace = || Backtrace::from(Vec::new());
+    let mut report = ReentranceReport {
+        lock_id,
+        lock_name: None,
+        original_acquire: no_trace(),
+        reentrant_attempt: no_trace(),
+    };
+    if let Some(lock) = representations.get(&lock_id) {
+        report.lock_name = lock.name.clone();
+        if let Some(reader) = lock.readers.iter().find(|reader| reader.0 == thread) {
+            report.original_acquire = resolve_and_trim(&reader.1);
+        }
+        if let Some(request) = lock.requests.get(&thread) {
+            report.reentrant_attempt = resolve_and_trim(&request.trace);
+        }
+    }
+    DeadlockKind::Reentrance(Box::new(report))
+}
+
+/// The threads holding the cycle's lowest priority locks, which may break it.
+fn victims(
+    cycle: &[DependencyNode],
+    representations: &Map<usize, MutexGuard<LockRepresentation>>,
+) -> Vec<ThreadId> {
+    // Locks point to their holders, so each lock is followed by the thread holding it.
+    let held: Vec<(u32, ThreadId)> = cycle
+        .iter()
+        .zip(cycle.iter().cycle().skip(1))
+        .filter_map(|pair| match pair {
+            (DependencyNode::Lock(id), DependencyNode::Thread(holder)) => {
+                Some((representations.get(id)?.priority, *holder))
+            }
+            _ => None,
+        })
+        .collect();
+    let lowest = held.iter().map(|(priority, _)| *priority).min();
+    held.into_iter()
+        .filter(|(priority, _)| Some(*priority) == lowest)
+        .map(|(_, holder)| holder)
+        .collect()
+}
+
+/// Sums the cycle up on one line. Locks and threads missing from `representations` are shown by id, and their frames are left out.
+fn write_summary(
+    dependence_cycle: &[&DependencyNode],
+    representations: &Map<usize, MutexGuard<LockRepresentation>>,
+    indices: &ThreadIndices,
+) -> String {
+    let mut locks = Vec::new();
+    let mut threads = Vec::new();
+    let mut frames = Vec::new();
+    for node in dependence_cycle {
+        match **node {
+            DependencyNode::Lock(id) => locks.push(
+                match representations.get(&id).and_then(|lock| lock.name.as_ref()) {
+                    Some(name) => format!("{}({})", name, id),
+                    None => id.to_string(),
+                },
+            ),
+            DependencyNode::Thread(thread) => {
+                let request = representations
+                    .values()
+                    .find_map(|lock| lock.requests.get(&thread));
+                threads.push(
+                    match indices
+                        .name(thread)
+                        .or_else(|| request.and_then(|request| request.thread_name.clone()))
+                    {
+                        Some(name) => name,
+                        None => indices.short(thread),
+                    },
+                );
+                frames.extend(request.and_then(|request| top_frame(&request.trace)));
+            }
+        }
+    }
+    let mut summary = format!(
+        "DEADLOCK cycle_len={} locks=[{}] threads=[{}]",
+        locks.len(),
+        locks.join(","),
+        threads.join(",")
+    );
+    if !frames.is_empty() {
+        summary.push_str(&format!(" at [{}]", frames.join(",")));
+    }
+    summary
+}
+
+/// One `key=value` line per thread of the cycle, describing the lock it waits for:
+/// `deadlock lock=2 name=cache thread=io-worker request=write frame="src/io.rs:42 io::flush"`.
+fn write_records(
+    dependence_cycle: &[&DependencyNode],
+    representations: &Map<usize, MutexGuard<LockRepresentation>>,
+    threads: &ThreadIndices,
+) -> Vec<String> {
+    let mut records = Vec::new();
+    for (node, next) in dependence_cycle
+        .iter()
+        .zip(dependence_cycle.iter().cycle().skip(1))
+    {
+        let (thread, lock) = match (**node, **next) {
+            (DependencyNode::Thread(thread), DependencyNode::Lock(lock)) => (thread, lock),
+            _ => continue,
+        };
+        let representation = representations.get(&lock);
+        let request = representation.and_then(|lock| lock.requests.get(&thread));
+        let mut record = format!("deadlock lock={}", lock);
+        if let Some(name) = representation.and_then(|lock| lock.name.as_ref()) {
+            record.push_str(&format!(" name={}", logfmt_value(name)));
+        }
+        let thread_name = threads
+            .name(thread)
+            .or_else(|| request.and_then(|request| request.thread_name.clone()));
+        record.push_str(&format!(
+            " thread={}",
+            logfmt_value(&thread_name.unwrap_or_else(|| threads.short(thread)))
+        ));
+        if let Some(request) = request {
+            record.push_str(&format!(" request={}", request.kind));
+            if let Some(caller) = caller(&request.trace) {
+                let mut frame = String::new();
+                if let (Some(file), Some(line)) = (caller.filename(), caller.lineno()) {
+                    frame.push_str(&format!("{}:{} ", file.display(), line));
+                }
+                if let Some(name) = caller.name() {
+                    frame.push_str(&format!("{:#}", name));
+                }
+                record.push_str(&format!(" frame={}", logfmt_value(frame.trim_end())));
+            }
+        }
+        records.push(record);
+    }
+    records
+}
+
+/// Quotes `value` if it contains spaces, quotes or `=`, so that each record stays parseable.
+fn logfmt_value(value: &str) -> String {
+    if value.is_empty() || value.contains(|c: char| c.is_whitespace() || c == '"' || c == '=') {
+        format!("{:?}", value)
+    } else {
+        value.to_owned()
+    }
+}
+
+/// Frames from the locks' own implementation, which `caller` skips.
+const INTERNAL_FRAMES: &[&str] = &[
+    "backtrace::",
+    "no_deadlocks::lock_manager::LockManager",
+    "no_deadlocks::lock_manager::LockRepresentation",
+    "no_deadlocks::mutex::Mutex",
+    "no_deadlocks::rwlock::RwLock",
+    "no_deadlocks::condvar::Condvar",
+    "<no_deadlocks::raw::RawNoDeadlocks",
+    "lock_api::",
+];
+
+/// The first frame outside of the locks' own implementation: where the lock was requested.
+fn caller(trace: &Backtrace) -> Option<backtrace::BacktraceSymbol> {
+    let mut trace = trace.clone();
+    trace.resolve();
+    let symbol = trace
+        .frames()
+        .iter()
+        .flat_map(|frame| frame.symbols())
+        .find(|symbol| match symbol.name() {
+            Some(name) => {
+                let name = format!("{:#}", name);
+                !INTERNAL_FRAMES
+                    .iter()
+                    .any(|prefix| name.starts_with(prefix))
+            }
+            None => false,
+        })?;
+    Some(symbol.clone())
+}
+
+/// The name of the function that requested the lock.
+fn top_frame(trace: &Backtrace) -> Option<String> {
+    Some(format!("{:#}", caller(trace)?.name()?))
+}
+
+/// Lists the locks held by the current thread, looking their keys up in `snapshot` if they were registered late.
+/// States found in `representations` are read from there, since their cells are already locked; the others are locked one at a time.
+#[allow(unused_must_use)]
+fn write_held_locks(
+    report: &mut String,
+    snapshot: &[(usize, &Arc<RepresentationCell>)],
+    representations: &Map<usize, MutexGuard<LockRepresentation>>,
+    threads: &ThreadIndices,
+) {
+    use std::fmt::Write;
+    let thread = std::thread::current().id();
+    let held: Vec<(Option<usize>, Arc<RepresentationCell>)> = with_held(|held| {
+        held.borrow()
+            .iter()
+            .map(|entry| (entry.key, entry.representation.clone()))
+            .collect()
+    });
+    writeln!(report, "THREAD {} currently holds:", threads.label(thread));
+    let mut describe = |key: Option<usize>, state: &LockRepresentation| {
+        match (key, &state.name) {
+            (Some(key), Some(name)) => writeln!(report, " LOCK {} ('{}') acquired at:", key, name),
+            (Some(key), None) => writeln!(report, " LOCK {} acquired at:", key),
+            (None, Some(name)) => writeln!(report, " unregistered LOCK ('{}') acquired at:", name),
+            (None, None) => writeln!(report, " unregistered LOCK acquired at:"),
+        };
+        if let Some((_, trace, _, _, location)) =
+            state.readers.iter().find(|reader| reader.0 == thread)
+        {
+            writeln!(report, "{}", display_site("acquired", *location, trace));
+        }
+    };
+    for (key, representation) in held {
+        let key = snapshot
+            .iter()
+            .find(|(_, cell)| Arc::ptr_eq(cell, &representation))
+            .map(|(key, _)| *key)
+            .or(key);
+        match key.and_then(|key| representations.get(&key)) {
+            Some(state) => describe(key, state),
+            None => describe(key, &representation.lock()),
+        }
+    }
+}
+
+/// Runs the detection on a hand-built dependency graph, without any live lock.
+/// The report follows the same structure as live ones, without backtraces.
+pub fn analyze_graph(graph: &Graph<DependencyNode>) -> Option<DeadlockReport> {
+    use std::fmt::Write;
+    let cycle = DepthFirstDetector.find(graph)?;
+    let dependence_cycle: Vec<&DependencyNode> = cycle.iter().collect();
+    // Hand-built graphs have no manager: their threads are numbered in the order the cycle lists them.
+    let mut threads = ThreadIndices::default();
+    for node in &cycle {
+        if let DependencyNode::Thread(id) = node {
+            threads.insert(*id, None);
+        }
+    }
+    let mut text = String::new();
+    if cycle.len() == 2 {
+        writeln!(text, "A reentrance has been attempted, but `std::sync`'s locks are not reentrant. This results in a deadlock. dependence cycle: {}", display_cycle(&dependence_cycle, &threads)).unwrap();
+    } else {
+        writeln!(
+            text,
+            "A deadlock has been detected, here's the dependence cycle: {}",
+            display_cycle(&dependence_cycle, &threads)
+        )
+        .unwrap();
+    }
+    for (node, next) in cycle.iter().zip(cycle.iter().cycle().skip(1)) {
+        match node {
+            DependencyNode::Lock(_) => writeln!(
+                text,
+                "{} is held by {}",
+                node_label(node, &threads),
+                node_label(next, &threads)
+            ),
+            DependencyNode::Thread(_) => writeln!(
+                text,
+                "{} waits for {}",
+                node_label(node, &threads),
+                node_label(next, &threads)
+            ),
+        }
+        .unwrap();
+    }
+    let summary = write_summary(&dependence_cycle, &Map::new(), &threads);
+    let records = write_records(&dependence_cycle, &Map::new(), &threads);
+    let kind = deadlock_kind(&cycle, &Map::new());
+    Some(DeadlockReport {
+        cycle,
+        text,
+        summary,
+        records,
+        kind,
+    })
+}
+
+/// The resolved trace as printed in reports, or a note if it wasn't captured.
+fn display_trace(trace: &Backtrace) -> String {
+    if trace.frames().is_empty() {
+        "  <backtrace capture disabled for this lock>".to_owned()
+    } else {
+        format!("{:?}", resolve_and_trim(trace))
+    }
+}
+
+/// The trace as printed in reports, preceded by the call site if it was recorded.
+fn display_site(verb: &str, location: Site, trace: &Backtrace) -> String {
+    match location {
+        Some(location) if trace.frames().is_empty() => format!("  {} at {}", verb, location),
+        Some(location) => format!("  {} at {}\n{}", verb, location, display_trace(trace)),
+        None => display_trace(trace),
+    }
+}
+
+/// Frees the global manager, for leak checkers to find nothing left behind at exit. Calling it again,
+/// or before the global manager was ever used, does nothing; a later lock then sets up a new global manager.
+/// Fails without freeing anything if locks or other references to the global manager are still alive.
+pub fn shutdown() -> Result<(), ShutdownError> {
+    // Held throughout: callers of `get_global_manager` that find no manager wait for the outcome,
+    // rather than setting up a second one next to the manager of the locks still alive.
+    let _lifecycle = GLOBAL_MANAGER_LIFECYCLE
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner);
+    let manager = GLOBAL_MANAGER.swap(std::ptr::null_mut(), Ordering::SeqCst);
+    if manager.is_null() {
+        return Ok(());
+    }
+    // Past this, no `get_global_manager` can reach the swapped out manager without having cloned it already.
+    let mut backoff = Backoff::default();
+    while GLOBAL_MANAGER_USERS.load(Ordering::SeqCst) != 0 {
+        backoff.snooze();
+    }
+    let references = Arc::strong_count(unsafe { &*manager });
+    if references == 1 {
+        std::mem::drop(unsafe { Box::from_raw(manager) });
+        return Ok(());
+    }
+    let mut live_locks: Vec<(usize, Option<String>)> = {
+        let guard = unsafe { &*manager }.read_lock();
+        guard
+            .locks
+            .iter()
+            .map(|(key, representation)| (*key, representation.lock().name.clone()))
+            .collect()
+    };
+    live_locks.sort_unstable();
+    GLOBAL_MANAGER.store(manager, Ordering::SeqCst);
+    Err(ShutdownError {
+        references: references - 1,
+        live_locks: live_locks
+            .into_iter()
+            .map(|(key, name)| match name {
+                Some(name) => format!("lock {} ({})", key, name),
+                None => format!("lock {}", key),
+            })
+            .collect(),
+    })
+}
+
+static PREWARMED: std::sync::Once = std::sync::Once::new();
+
+/// Loads the symbol tables used to resolve backtraces, which otherwise happens on the first report and delays it.
+/// Call it at startup: only the first call does any work.
+pub fn prewarm() {
+    PREWARMED.call_once(|| std::mem::drop(Backtrace::new()));
 }
 
 fn resolve_and_trim(trace: &Backtrace) -> Backtrace {
@@ -374,53 +3259,1610 @@ fn resolve_and_trim(trace: &Backtrace) -> Backtrace {
 }
 
 #[test]
-#[should_panic]
-fn with_deadlock() {
-    use crate::Mutex;
-    use std::sync::Arc;
-    let mut1 = Arc::new(Mutex::new(0));
-    let mut2 = Arc::new(Mutex::new(0));
-    let _guard1 = mut1.lock();
-    let th = std::thread::spawn({
+#[should_panic]
+fn with_deadlock() {
+    use crate::Mutex;
+    use std::sync::Arc;
+    let mut1 = Arc::new(Mutex::new(0));
+    let mut2 = Arc::new(Mutex::new(0));
+    let _guard1 = mut1.lock();
+    let th = std::thread::spawn({
+        let mut1 = mut1.clone();
+        let mut2 = mut2.clone();
+        move || {
+            let _guard2 = mut2.lock();
+            let _guard1 = mut1.lock();
+        }
+    });
+    std::thread::sleep(std::time::Duration::from_millis(200));
+    let _guard2 = mut2.lock();
+    th.join().unwrap();
+}
+
+#[test]
+fn uncontended_locks_stay_unregistered() {
+    use crate::Mutex;
+    let manager = Arc::new(LockManager::new());
+    let mutex = Arc::new(Mutex::with_manager(manager.clone(), 0));
+    for _ in 0..10 {
+        *mutex.lock().unwrap() += 1;
+    }
+    std::mem::drop(mutex.try_lock().unwrap());
+    assert_eq!(manager.write_lock().next_key, 0);
+    let guard = mutex.lock().unwrap();
+    let th = std::thread::spawn({
+        let mutex = mutex.clone();
+        move || *mutex.lock().unwrap() += 1
+    });
+    while manager.write_lock().next_key == 0 {
+        std::thread::yield_now();
+    }
+    std::mem::drop(guard);
+    th.join().unwrap();
+    assert_eq!(*mutex.lock().unwrap(), 11);
+    assert_eq!(manager.write_lock().next_key, 1);
+}
+
+#[test]
+fn without_deadlock() {
+    use crate::Mutex;
+    use std::sync::Arc;
+    let mut1 = Arc::new(Mutex::new(0));
+    let mut2 = Arc::new(Mutex::new(0));
+    let guard1 = mut1.lock();
+    let th = std::thread::spawn({
+        let mut1 = mut1.clone();
+        let mut2 = mut2.clone();
+        move || {
+            let _guard1 = mut1.lock();
+            let _guard2 = mut2.lock();
+            std::thread::sleep(std::time::Duration::from_millis(200));
+        }
+    });
+    std::thread::sleep(std::time::Duration::from_millis(100));
+    let guard2 = mut2.lock();
+    std::mem::drop((guard1, guard2));
+    th.join().unwrap();
+}
+
+#[test]
+#[should_panic]
+fn reentrance_detection() {
+    use crate::Mutex;
+    let mutex = Mutex::new(0);
+    let _guard1 = mutex.lock();
+    let _guard2 = mutex.lock();
+}
+
+#[test]
+fn panic_with_full_report() {
+    use crate::Mutex;
+    let manager = Arc::new(LockManager::with_analysis_timeout(Duration::from_millis(
+        10,
+    )));
+    manager.set_panic_with_full_report(true);
+    let mutex = Mutex::with_manager(manager, 0);
+    let _guard = mutex.lock();
+    let payload = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        let _reentrance = mutex.lock();
+    }))
+    .unwrap_err();
+    let message = payload.downcast_ref::<String>().unwrap();
+    assert!(message.contains("dependence cycle: "));
+    assert!(message.contains("lock 0"));
+    assert!(message.contains("Reentrace at:"));
+}
+
+#[test]
+fn wait_reasons() {
+    use crate::RwLock;
+    use std::sync::mpsc::channel;
+    let manager = Arc::new(LockManager::with_analysis_timeout(Duration::from_millis(
+        10,
+    )));
+    let rwlock = Arc::new(RwLock::with_manager(manager.clone(), ()));
+    let read = rwlock.read();
+    let (release, released) = channel::<()>();
+    let (acquired, on_acquired) = channel();
+    let reader = std::thread::spawn({
+        let rwlock = rwlock.clone();
+        move || {
+            let _read = rwlock.read();
+            acquired.send(()).unwrap();
+            released.recv().unwrap();
+        }
+    });
+    on_acquired.recv().unwrap();
+    let writer = std::thread::spawn({
+        let rwlock = rwlock.clone();
+        move || std::mem::drop(rwlock.write())
+    });
+    let start = Instant::now();
+    let mut blocked = manager.blocked_threads();
+    while blocked.is_empty() {
+        assert!(start.elapsed() < Duration::from_secs(5));
+        std::thread::sleep(Duration::from_millis(5));
+        blocked = manager.blocked_threads();
+    }
+    assert_eq!(blocked[0].reason, WaitReason::WaitingForReaders(2));
+    let graph = manager.snapshot_graph();
+    assert!(graph
+        .edges
+        .iter()
+        .any(|edge| edge.reason == Some(WaitReason::WaitingForReaders(2))));
+    assert_eq!(
+        blocked[0].reason.to_string(),
+        "waiting for 2 readers to drain"
+    );
+    release.send(()).unwrap();
+    std::mem::drop(read);
+    reader.join().unwrap();
+    writer.join().unwrap();
+}
+
+#[test]
+fn blocked_threads_listing() {
+    use crate::{Mutex, RwLock};
+    let manager = Arc::new(LockManager::with_analysis_timeout(Duration::from_millis(
+        10,
+    )));
+    let mutex = Arc::new(Mutex::with_manager(manager.clone(), ()));
+    let rwlock = Arc::new(RwLock::with_manager(manager.clone(), ()));
+    let mutex_guard = mutex.lock();
+    let rwlock_guard = rwlock.write();
+    let waiters = vec![
+        std::thread::Builder::new()
+            .name("mutex waiter".into())
+            .spawn({
+                let mutex = mutex.clone();
+                move || std::mem::drop(mutex.lock())
+            })
+            .unwrap(),
+        std::thread::Builder::new()
+            .name("rwlock waiter".into())
+            .spawn({
+                let rwlock = rwlock.clone();
+                move || std::mem::drop(rwlock.read())
+            })
+            .unwrap(),
+    ];
+    let start = Instant::now();
+    let mut blocked = manager.blocked_threads();
+    while blocked.len() < 2 {
+        assert!(start.elapsed() < Duration::from_secs(5));
+        std::thread::sleep(Duration::from_millis(10));
+        blocked = manager.blocked_threads();
+    }
+    blocked.sort_by_key(|thread| thread.thread_name.clone());
+    assert_eq!(blocked.len(), 2);
+    assert_eq!(blocked[0].thread_name.as_deref(), Some("mutex waiter"));
+    assert_eq!(blocked[0].request, RequestType::Write);
+    assert_eq!(blocked[1].thread_name.as_deref(), Some("rwlock waiter"));
+    assert_eq!(blocked[1].request, RequestType::Read);
+    assert_ne!(blocked[0].lock, blocked[1].lock);
+    assert!(blocked
+        .iter()
+        .all(|thread| thread.waiting_for >= Duration::from_millis(10)));
+    std::mem::drop((mutex_guard, rwlock_guard));
+    for waiter in waiters {
+        waiter.join().unwrap();
+    }
+    assert!(manager.blocked_threads().is_empty());
+}
+
+#[test]
+fn friendly_cycle_display() {
+    let thread = std::thread::current().id();
+    let cycle = [DependencyNode::Thread(thread), DependencyNode::Lock(3)];
+    let cycle: Vec<&DependencyNode> = cycle.iter().collect();
+    assert_eq!(
+        display_cycle(&cycle, &ThreadIndices::default()),
+        format!("[thread {:?}, lock 3]", thread)
+    );
+    let mut threads = ThreadIndices::default();
+    threads.insert_current();
+    assert_eq!(display_cycle(&cycle, &threads), "[thread #0, lock 3]");
+}
+
+#[test]
+fn custom_cycle_detector() {
+    use crate::graphs::{Graph, TarjanDetector};
+    use crate::{LockError, Mutex};
+    use std::sync::Barrier;
+    struct Blind(Arc<AtomicUsize>);
+    impl CycleDetector for Blind {
+        fn find(&self, _graph: &Graph<DependencyNode>) -> Option<Vec<DependencyNode>> {
+            self.0.fetch_add(1, Ordering::Relaxed);
+            None
+        }
+    }
+    let calls = Arc::new(AtomicUsize::new(0));
+    let manager = Arc::new(LockManager::with_analysis_timeout(Duration::from_millis(
+        10,
+    )));
+    manager.set_cycle_detector(Blind(calls.clone()));
+    let mut1 = Arc::new(Mutex::with_manager(manager.clone(), ()));
+    let mut2 = Arc::new(Mutex::with_manager(manager.clone(), ()));
+    let guard = mut1.lock();
+    let waiter = std::thread::spawn({
         let mut1 = mut1.clone();
-        let mut2 = mut2.clone();
         move || {
-            let _guard2 = mut2.lock();
-            let _guard1 = mut1.lock();
+            matches!(
+                mut1.try_lock_for_checked(Duration::from_millis(50)),
+                Err(LockError::TimedOut)
+            )
         }
     });
-    std::thread::sleep(std::time::Duration::from_millis(200));
-    let _guard2 = mut2.lock();
-    th.join().unwrap();
+    assert!(waiter.join().unwrap());
+    std::mem::drop(guard);
+    assert!(calls.load(Ordering::Relaxed) > 0);
+    manager.set_cycle_detector(TarjanDetector);
+    let barrier = Arc::new(Barrier::new(2));
+    let threads: Vec<_> = vec![(mut1.clone(), mut2.clone()), (mut2, mut1)]
+        .into_iter()
+        .map(|(first, second)| {
+            let barrier = barrier.clone();
+            std::thread::spawn(move || {
+                let _first = first.lock();
+                barrier.wait();
+                match second.lock_checked() {
+                    Err(LockError::Deadlock(report)) => Some(report.cycle.len()),
+                    _ => None,
+                }
+            })
+        })
+        .collect();
+    let cycles: Vec<usize> = threads
+        .into_iter()
+        .filter_map(|thread| thread.join().unwrap())
+        .collect();
+    assert_eq!(cycles, vec![4]);
 }
 
 #[test]
-fn without_deadlock() {
+fn lock_order_recording() {
+    use crate::{Mutex, RwLock};
+    let manager = Arc::new(LockManager::new());
+    manager.enable_lock_order_recording();
+    let a = Mutex::with_manager(manager.clone(), ());
+    let b = RwLock::with_manager(manager.clone(), ());
+    let c = Mutex::with_manager(manager.clone(), ());
+    a.set_name("a");
+    for _ in 0..2 {
+        let _a = a.lock();
+        let _b = b.read();
+        let _c = c.try_lock();
+    }
+    {
+        let _a = a.lock();
+        let _c = c.lock();
+    }
+    let _b = b.write();
+    let order = manager.lock_order_graph();
+    let successors = |key: usize| {
+        let mut successors: Vec<usize> = order.successors(&key).cloned().collect();
+        successors.sort_unstable();
+        successors
+    };
+    assert_eq!(successors(0), vec![1, 2]);
+    assert_eq!(successors(1), vec![2]);
+    assert!(successors(2).is_empty());
+    let dot = manager.lock_order_dot();
+    assert!(dot.contains("0 [label=\"a\"];"));
+    assert!(dot.contains("1 -> 2;"));
+}
+
+#[test]
+fn watchdog_detects_foreign_deadlocks() {
+    use crate::{LockError, Mutex};
+    use std::ops::ControlFlow;
+    use std::sync::Barrier;
+    // Waiting threads never run an analysis themselves.
+    let manager = Arc::new(LockManager::with_analysis_timeout(Duration::from_secs(
+        3600,
+    )));
+    let reports = Arc::new(std::sync::Mutex::new(Vec::new()));
+    manager.set_deadlock_hook({
+        let reports = reports.clone();
+        move |report: &DeadlockReport| reports.lock().unwrap().push(report.cycle.clone())
+    });
+    let watchdog = manager.start_watchdog(Duration::from_millis(10));
+    let mut1 = Arc::new(Mutex::with_manager(manager.clone(), ()));
+    let mut2 = Arc::new(Mutex::with_manager(manager.clone(), ()));
+    let barrier = Arc::new(Barrier::new(2));
+    let threads: Vec<_> = vec![(mut1.clone(), mut2.clone()), (mut2, mut1)]
+        .into_iter()
+        .map(|(first, second)| {
+            let barrier = barrier.clone();
+            let reports = reports.clone();
+            std::thread::spawn(move || {
+                let _first = first.lock();
+                barrier.wait();
+                let result = second.lock_with_progress(Duration::from_millis(5), || {
+                    if reports.lock().unwrap().is_empty() {
+                        ControlFlow::Continue(())
+                    } else {
+                        ControlFlow::Break(())
+                    }
+                });
+                // Once one of them gives up, the other acquires its lock.
+                matches!(result, Ok(_) | Err(LockError::Cancelled))
+            })
+        })
+        .collect();
+    for thread in threads {
+        assert!(thread.join().unwrap());
+    }
+    watchdog.stop();
+    let reports = reports.lock().unwrap();
+    assert_eq!(reports.len(), 1);
+    assert_eq!(reports[0].len(), 4);
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn persisted_order_inversions() {
     use crate::Mutex;
-    use std::sync::Arc;
-    let mut1 = Arc::new(Mutex::new(0));
-    let mut2 = Arc::new(Mutex::new(0));
-    let guard1 = mut1.lock();
-    let th = std::thread::spawn({
-        let mut1 = mut1.clone();
-        let mut2 = mut2.clone();
+    let path = std::env::temp_dir().join(format!("no_deadlocks_order_{}.json", std::process::id()));
+    let previous_run = Arc::new(LockManager::new());
+    previous_run.enable_lock_order_recording();
+    let a = Mutex::with_manager(previous_run.clone(), ());
+    let b = Mutex::with_manager(previous_run.clone(), ());
+    a.set_name("a");
+    b.set_name("b");
+    {
+        let _a = a.lock();
+        let _b = b.lock();
+    }
+    previous_run.export_order_graph(&path).unwrap();
+
+    let manager = Arc::new(LockManager::new());
+    manager.enable_lock_order_recording();
+    manager.import_order_graph(&path).unwrap();
+    let a = Mutex::with_manager(manager.clone(), ());
+    let b = Mutex::with_manager(manager.clone(), ());
+    a.set_name("a");
+    b.set_name("b");
+    assert!(manager.order_inversions().is_empty());
+    {
+        let _b = b.lock();
+        let _a = a.lock();
+    }
+    assert_eq!(
+        manager.order_inversions(),
+        vec![("b".to_owned(), "a".to_owned())]
+    );
+    manager.export_order_graph(&path).unwrap();
+    let merged = std::fs::read_to_string(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    let merged: serde_json::Value = serde_json::from_str(&merged).unwrap();
+    assert_eq!(merged["edges"].as_array().unwrap().len(), 2);
+}
+
+#[test]
+fn report_output_selection() {
+    use std::ffi::OsStr;
+    let mut notices = Vec::new();
+    let (_, path) = report_output(Some(OsStr::new("stdout")), &mut notices);
+    assert_eq!(path, "stdout");
+    let (_, path) = report_output(None, &mut notices);
+    assert_eq!(path, "stderr");
+    assert!(notices.is_empty());
+    let unwritable = "/nonexistent-no-deadlocks-dir/report.txt";
+    let (_, path) = report_output(Some(OsStr::new(unwritable)), &mut notices);
+    assert_eq!(path, "stderr");
+    let notices = String::from_utf8(notices).unwrap();
+    assert!(notices.contains(unwritable));
+    assert!(notices.contains("writing the report to stderr instead"));
+}
+
+#[test]
+fn what_if_analysis() {
+    let (first, second) = (
+        DependencyNode::Thread(std::thread::current().id()),
+        DependencyNode::Thread(std::thread::spawn(|| {}).thread().id()),
+    );
+    let mut graph = Graph::new();
+    graph.add_edge_and_nodes(DependencyNode::Lock(0), first);
+    graph.add_edge_and_nodes(first, DependencyNode::Lock(1));
+    graph.add_edge_and_nodes(DependencyNode::Lock(1), second);
+    assert!(analyze_graph(&graph).is_none());
+    graph.add_edge_and_nodes(second, DependencyNode::Lock(0));
+    let report = analyze_graph(&graph).unwrap();
+    assert_eq!(report.cycle.len(), 4);
+    assert!(report.text.starts_with("A deadlock has been detected"));
+    assert!(report.text.contains("lock 0 is held by thread #"));
+    assert!(
+        report.text.contains("thread #1 waits for lock 0")
+            || report.text.contains("thread #0 waits for lock 0")
+    );
+    assert!(!report.text.contains(&format!(
+        "thread {} ",
+        thread_number(std::thread::current().id())
+    )));
+}
+
+#[test]
+fn immediate_reentrance_detection() {
+    use crate::{LockError, Mutex, RwLock};
+    let manager = Arc::new(LockManager::new());
+    // Registers locks as soon as they're acquired.
+    manager.enable_lock_order_recording();
+    let mutex = Mutex::with_manager(manager.clone(), ());
+    let rwlock = RwLock::with_manager(manager.clone(), ());
+    let guard = mutex.lock();
+    let read = rwlock.read();
+    assert!(std::panic::catch_unwind(assert_no_locks_held).is_err());
+    // Holding the manager from another thread would stall any detection relying on it.
+    let (locked, release) = (
+        Arc::new(std::sync::Barrier::new(2)),
+        Arc::new(std::sync::Barrier::new(2)),
+    );
+    let holder = std::thread::spawn({
+        let (manager, locked, release) = (manager.clone(), locked.clone(), release.clone());
         move || {
-            let _guard1 = mut1.lock();
-            let _guard2 = mut2.lock();
-            std::thread::sleep(std::time::Duration::from_millis(200));
+            let _manager = manager.write_lock();
+            locked.wait();
+            release.wait();
         }
     });
-    std::thread::sleep(std::time::Duration::from_millis(100));
-    let guard2 = mut2.lock();
-    std::mem::drop((guard1, guard2));
-    th.join().unwrap();
+    locked.wait();
+    let start = Instant::now();
+    assert!(matches!(
+        mutex.lock_checked(),
+        Err(LockError::Deadlock(report)) if report.cycle.len() == 2
+    ));
+    assert!(matches!(
+        rwlock.write_checked(),
+        Err(LockError::Deadlock(_))
+    ));
+    assert!(start.elapsed() < Duration::from_secs(1));
+    release.wait();
+    holder.join().unwrap();
+    std::mem::drop((guard, read));
+    assert_no_locks_held();
 }
 
 #[test]
-#[should_panic]
-fn reentrance_detection() {
+fn dry_run_reports_without_panicking() {
     use crate::Mutex;
-    let mutex = Mutex::new(0);
-    let _guard1 = mutex.lock();
-    let _guard2 = mutex.lock();
+    use std::ops::ControlFlow;
+    use std::sync::Barrier;
+    let manager = Arc::new(LockManager::with_analysis_timeout(Duration::from_secs(
+        3600,
+    )));
+    manager.set_dry_run(true);
+    let reports = Arc::new(AtomicUsize::new(0));
+    manager.set_deadlock_hook({
+        let reports = reports.clone();
+        move |_: &DeadlockReport| {
+            reports.fetch_add(1, Ordering::Relaxed);
+        }
+    });
+    // Makes waiting threads subscribe immediately, without ever analysing on its own.
+    let watchdog = manager.start_watchdog(Duration::from_secs(3600));
+    let mut1 = Arc::new(Mutex::with_manager(manager.clone(), ()));
+    let mut2 = Arc::new(Mutex::with_manager(manager.clone(), ()));
+    // Only the thread waiting for `mut2` analyses, and it would panic outside of dry run mode.
+    mut2.set_analysis_timeout(Some(Duration::from_millis(10)));
+    let barrier = Arc::new(Barrier::new(2));
+    let blocked = std::thread::spawn({
+        let (mut1, mut2, barrier) = (mut1.clone(), mut2.clone(), barrier.clone());
+        move || {
+            let _first = mut1.lock();
+            barrier.wait();
+            std::mem::drop(mut2.lock());
+        }
+    });
+    let second = mut2.lock();
+    barrier.wait();
+    let result = mut1.lock_with_progress(Duration::from_millis(5), || {
+        if reports.load(Ordering::Relaxed) == 0 {
+            ControlFlow::Continue(())
+        } else {
+            ControlFlow::Break(())
+        }
+    });
+    assert!(matches!(result, Err(crate::LockError::Cancelled)));
+    std::mem::drop(second);
+    blocked.join().unwrap();
+    watchdog.stop();
+    assert_eq!(reports.load(Ordering::Relaxed), 1);
+}
+
+#[test]
+fn analysis_window_excludes_idle_locks() {
+    use crate::{LockError, Mutex};
+    use std::sync::Barrier;
+    struct Recorder(Arc<std::sync::Mutex<Vec<DependencyNode>>>);
+    impl CycleDetector for Recorder {
+        fn find(&self, graph: &Graph<DependencyNode>) -> Option<Vec<DependencyNode>> {
+            self.0.lock().unwrap().extend(graph.nodes().cloned());
+            DepthFirstDetector.find(graph)
+        }
+    }
+    let manager = Arc::new(LockManager::with_analysis_timeout(Duration::from_millis(
+        10,
+    )));
+    let seen = Arc::new(std::sync::Mutex::new(Vec::new()));
+    manager.set_cycle_detector(Recorder(seen.clone()));
+    manager.set_analysis_window(Some(Duration::from_millis(200)));
+    manager.enable_lock_order_recording();
+    let idle = Mutex::with_manager(manager.clone(), ());
+    let _idle = idle.lock();
+    let idle = idle.key().unwrap();
+    std::thread::sleep(Duration::from_millis(300));
+    let mut1 = Arc::new(Mutex::with_manager(manager.clone(), ()));
+    let mut2 = Arc::new(Mutex::with_manager(manager.clone(), ()));
+    let barrier = Arc::new(Barrier::new(2));
+    let threads: Vec<_> = vec![(mut1.clone(), mut2.clone()), (mut2, mut1)]
+        .into_iter()
+        .map(|(first, second)| {
+            let barrier = barrier.clone();
+            std::thread::spawn(move || {
+                let _first = first.lock();
+                barrier.wait();
+                matches!(second.lock_checked(), Err(LockError::Deadlock(_)))
+            })
+        })
+        .collect();
+    let deadlocks = threads
+        .into_iter()
+        .map(|thread| thread.join().unwrap())
+        .filter(|deadlocked| *deadlocked)
+        .count();
+    assert_eq!(deadlocks, 1);
+    let seen = seen.lock().unwrap();
+    assert!(seen
+        .iter()
+        .any(|node| matches!(node, DependencyNode::Lock(_))));
+    assert!(!seen.contains(&DependencyNode::Lock(idle)));
+}
+
+#[test]
+fn contexts_in_reports() {
+    use crate::{LockError, Mutex};
+    use std::sync::Barrier;
+    let manager = Arc::new(LockManager::with_analysis_timeout(Duration::from_millis(
+        10,
+    )));
+    let mut1 = Arc::new(Mutex::with_manager(manager.clone(), ()));
+    let mut2 = Arc::new(Mutex::with_manager(manager, ()));
+    let barrier = Arc::new(Barrier::new(2));
+    let threads: Vec<_> = vec![
+        ("request-a", mut1.clone(), mut2.clone()),
+        ("request-b", mut2, mut1),
+    ]
+    .into_iter()
+    .map(|(context, first, second)| {
+        let barrier = barrier.clone();
+        std::thread::spawn(move || {
+            set_context(context);
+            let _first = first.lock();
+            barrier.wait();
+            match second.lock_checked() {
+                Err(LockError::Deadlock(report)) => Some(report.text),
+                _ => None,
+            }
+        })
+    })
+    .collect();
+    let reports: Vec<String> = threads
+        .into_iter()
+        .filter_map(|thread| thread.join().unwrap())
+        .collect();
+    assert_eq!(reports.len(), 1);
+    assert!(reports[0].contains("(context: request-a)"));
+    assert!(reports[0].contains("(context: request-b)"));
+    clear_context();
+    assert!(current_context().is_none());
+}
+
+#[test]
+fn cross_wired_managers() {
+    let (first, second) = (LockManager::new(), LockManager::new());
+    assert_ne!(first.id(), second.id());
+    let key = LazyKey::new();
+    let representation = Arc::new(RepresentationCell::new());
+    first.register_lock(&key, &representation);
+    let expected = format!(
+        "lock belongs to manager {} but was used with manager {}",
+        first.id(),
+        second.id()
+    );
+    let message = |result: std::thread::Result<()>| {
+        result
+            .unwrap_err()
+            .downcast_ref::<String>()
+            .unwrap()
+            .clone()
+    };
+    let register = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        second.register_lock(&key, &representation);
+    }));
+    assert_eq!(message(register), expected);
+    let acquire = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        let _ = second.acquire(
+            &key,
+            &representation,
+            RequestType::Write,
+            &WaitOptions::default(),
+        );
+    }));
+    assert_eq!(message(acquire), expected);
+}
+
+#[test]
+fn wait_queue_order() {
+    use crate::Mutex;
+    let manager = Arc::new(LockManager::new());
+    let mutex = Arc::new(Mutex::with_manager(manager.clone(), ()));
+    // Waiters subscribe as soon as they start waiting.
+    mutex.set_analysis_timeout(Some(Duration::from_secs(0)));
+    let guard = mutex.lock();
+    let mut waiters = Vec::new();
+    for queued in 1..=3 {
+        let waiter = std::thread::spawn({
+            let mutex = mutex.clone();
+            move || std::mem::drop(mutex.lock())
+        });
+        let start = Instant::now();
+        while mutex.key().map_or(0, |key| manager.wait_queue(key).len()) < queued {
+            assert!(start.elapsed() < Duration::from_secs(5));
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        waiters.push(waiter);
+    }
+    let expected: Vec<ThreadId> = waiters.iter().map(|waiter| waiter.thread().id()).collect();
+    assert_eq!(manager.wait_queue(mutex.key().unwrap()), expected);
+    std::mem::drop(guard);
+    for waiter in waiters {
+        waiter.join().unwrap();
+    }
+    assert!(manager.wait_queue(mutex.key().unwrap()).is_empty());
+}
+
+#[test]
+fn custom_report_framing() {
+    let manager = LockManager::new();
+    manager.set_report_prefix("build=1.2.3+abc");
+    manager.set_report_footer("--- end ---");
+    let report = DeadlockReport {
+        cycle: Vec::new(),
+        text: "A deadlock has been detected\n".to_owned(),
+        summary: String::new(),
+        records: Vec::new(),
+        kind: DeadlockKind::Cycle,
+    };
+    let mut output = Vec::new();
+    manager.read_lock().emit_report(&mut output, &report);
+    assert_eq!(
+        String::from_utf8(output).unwrap(),
+        "=========== REPORT START ===========\nbuild=1.2.3+abc\nA deadlock has been detected\n--- end ---\n\n"
+    );
+}
+
+#[test]
+fn held_locks_in_reports() {
+    use crate::{LockError, Mutex};
+    use std::sync::Barrier;
+    let manager = Arc::new(LockManager::with_analysis_timeout(Duration::from_millis(
+        10,
+    )));
+    manager.set_report_held_locks(true);
+    let mut1 = Arc::new(Mutex::with_manager(manager.clone(), ()));
+    let mut2 = Arc::new(Mutex::with_manager(manager.clone(), ()));
+    let barrier = Arc::new(Barrier::new(2));
+    let threads: Vec<_> = vec![
+        ("cache-a", mut1.clone(), mut2.clone()),
+        ("cache-b", mut2, mut1),
+    ]
+    .into_iter()
+    .map(|(name, first, second)| {
+        let barrier = barrier.clone();
+        let cache = Mutex::with_manager(manager.clone(), ());
+        cache.set_name(name);
+        std::thread::spawn(move || {
+            let _cache = cache.lock();
+            let _first = first.lock();
+            barrier.wait();
+            match second.lock_checked() {
+                Err(LockError::Deadlock(report)) => Some((name, report.text)),
+                _ => None,
+            }
+        })
+    })
+    .collect();
+    let reports: Vec<(&str, String)> = threads
+        .into_iter()
+        .filter_map(|thread| thread.join().unwrap())
+        .collect();
+    assert_eq!(reports.len(), 1);
+    let (name, text) = &reports[0];
+    let section = &text[text.find("currently holds:").unwrap()..];
+    assert!(section.contains(&format!("('{}') acquired at:", name)));
+    assert_eq!(section.matches(" acquired at:").count(), 2);
+}
+
+#[test]
+fn suppressed_detection() {
+    use crate::{LockError, Mutex};
+    let mutex = Mutex::with_manager(
+        Arc::new(LockManager::with_analysis_timeout(Duration::from_millis(
+            10,
+        ))),
+        (),
+    );
+    let _guard = mutex.lock();
+    let inside = suppress_detection(|| mutex.try_lock_for_checked(Duration::from_millis(50)));
+    assert!(matches!(inside, Err(LockError::TimedOut)));
+    assert!(!detection_suppressed());
+    let outside = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        std::mem::drop(mutex.lock());
+    }));
+    assert!(outside.is_err());
+}
+
+#[test]
+fn one_line_summaries() {
+    use crate::{LockError, Mutex};
+    use std::sync::Barrier;
+    let manager = Arc::new(LockManager::with_analysis_timeout(Duration::from_millis(
+        10,
+    )));
+    let cache = Arc::new(Mutex::with_manager(manager.clone(), ()));
+    cache.set_name("cache");
+    let index = Arc::new(Mutex::with_manager(manager.clone(), ()));
+    index.set_name("index");
+    let barrier = Arc::new(Barrier::new(2));
+    let threads: Vec<_> = vec![
+        ("io-worker", cache.clone(), index.clone()),
+        ("flusher", index, cache),
+    ]
+    .into_iter()
+    .map(|(name, first, second)| {
+        let barrier = barrier.clone();
+        std::thread::Builder::new()
+            .name(name.to_owned())
+            .spawn(move || {
+                let _first = first.lock();
+                barrier.wait();
+                match second.lock_checked() {
+                    Err(LockError::Deadlock(report)) => Some(report),
+                    _ => None,
+                }
+            })
+            .unwrap()
+    })
+    .collect();
+    let reports: Vec<DeadlockReport> = threads
+        .into_iter()
+        .filter_map(|thread| thread.join().unwrap())
+        .collect();
+    assert_eq!(reports.len(), 1);
+    let summary = &reports[0].summary;
+    assert!(summary.starts_with("DEADLOCK cycle_len=2 locks=["));
+    assert!(summary.contains("cache(") && summary.contains("index("));
+    assert!(summary.contains("io-worker") && summary.contains("flusher"));
+    assert!(summary.contains(" at [no_deadlocks::lock_manager::one_line_summaries::"));
+    assert!(!summary.contains('\n'));
+    manager.set_report_format(ReportFormat::Summary);
+    let mut output = Vec::new();
+    manager.read_lock().emit_report(&mut output, &reports[0]);
+    assert_eq!(String::from_utf8(output).unwrap(), format!("{}\n", summary));
+}
+
+#[test]
+fn cancelling_all_waiters() {
+    use crate::{LockError, Mutex};
+    let manager = Arc::new(LockManager::with_analysis_timeout(Duration::from_secs(
+        3600,
+    )));
+    let mutex = Arc::new(Mutex::with_manager(manager.clone(), ()));
+    let guard = mutex.lock();
+    let waiters: Vec<std::thread::JoinHandle<bool>> = vec![
+        std::thread::spawn({
+            let mutex = mutex.clone();
+            move || matches!(mutex.lock_checked(), Err(LockError::Cancelled))
+        }),
+        std::thread::spawn({
+            let mutex = mutex.clone();
+            move || {
+                let never = AtomicBool::new(false);
+                matches!(mutex.lock_cancellable(&never), Err(LockError::Cancelled))
+            }
+        }),
+        std::thread::spawn({
+            let mutex = mutex.clone();
+            move || {
+                std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| mutex.lock().is_ok()))
+                    .is_err()
+            }
+        }),
+    ];
+    let start = Instant::now();
+    while mutex.stats().contentions < 3 {
+        assert!(start.elapsed() < Duration::from_secs(5));
+        std::thread::sleep(Duration::from_millis(1));
+    }
+    manager.cancel_all_waiters();
+    for waiter in waiters {
+        assert!(waiter.join().unwrap());
+    }
+    // Only the threads that were waiting are cancelled.
+    std::mem::drop(guard);
+    assert!(mutex.lock_checked().is_ok());
+}
+
+#[test]
+fn reports_to_the_manager_output() {
+    use crate::Mutex;
+    #[derive(Clone, Default)]
+    struct Buffer(Arc<std::sync::Mutex<Vec<u8>>>);
+    impl std::io::Write for Buffer {
+        fn write(&mut self, bytes: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(bytes)
+        }
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+    let buffer = Buffer::default();
+    let manager = Arc::new(LockManager::with_output(Box::new(buffer.clone())));
+    let hooked = Arc::new(AtomicBool::new(false));
+    manager.set_deadlock_hook({
+        let hooked = hooked.clone();
+        move |_: &DeadlockReport| hooked.store(true, Ordering::Relaxed)
+    });
+    let mutex = Mutex::with_manager(manager, ());
+    let _guard = mutex.lock();
+    let reentrance = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        std::mem::drop(mutex.lock());
+    }));
+    let message = reentrance.unwrap_err();
+    assert!(message
+        .downcast_ref::<String>()
+        .unwrap()
+        .ends_with("See the manager's output for details"));
+    assert!(hooked.load(Ordering::Relaxed));
+    let written = String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap();
+    assert!(written.starts_with("=========== REPORT START ===========\n"));
+    assert!(written.contains("A reentrance has been attempted"));
+}
+
+#[test]
+fn manager_lock_under_heavy_contention() {
+    let manager = Arc::new(LockManager::new());
+    let start = Instant::now();
+    let threads: Vec<_> = (0..16)
+        .map(|i| {
+            let manager = manager.clone();
+            std::thread::spawn(move || {
+                for _ in 0..500 {
+                    if i % 4 == 0 {
+                        manager.write_lock().next_key += 1;
+                    } else {
+                        std::hint::black_box(manager.read_lock().locks.len());
+                    }
+                }
+            })
+        })
+        .collect();
+    for thread in threads {
+        thread.join().unwrap();
+    }
+    assert_eq!(manager.read_lock().next_key, 4 * 500);
+    assert!(start.elapsed() < Duration::from_secs(10));
+}
+
+#[test]
+fn chain_of_waits() {
+    use crate::Mutex;
+    use std::sync::mpsc;
+    let manager = Arc::new(LockManager::with_analysis_timeout(Duration::from_millis(
+        10,
+    )));
+    let l1 = Arc::new(Mutex::with_manager(manager.clone(), ()));
+    let l2 = Arc::new(Mutex::with_manager(manager.clone(), ()));
+    let (held, wait_held) = mpsc::channel();
+    let (release, wait_release) = mpsc::channel::<()>();
+    let t3 = std::thread::spawn({
+        let (l2, held) = (l2.clone(), held.clone());
+        move || {
+            let _l2 = l2.lock();
+            held.send(()).unwrap();
+            wait_release.recv().unwrap();
+        }
+    });
+    wait_held.recv().unwrap();
+    let t2 = std::thread::spawn({
+        let (l1, l2) = (l1.clone(), l2.clone());
+        move || {
+            let _l1 = l1.lock();
+            held.send(()).unwrap();
+            std::mem::drop(l2.lock());
+        }
+    });
+    wait_held.recv().unwrap();
+    let t1 = std::thread::spawn({
+        let l1 = l1.clone();
+        move || std::mem::drop(l1.lock())
+    });
+    let start = Instant::now();
+    let mut chain = manager.wait_chain(t1.thread().id());
+    while chain.len() < 5 {
+        assert!(start.elapsed() < Duration::from_secs(5));
+        std::thread::sleep(Duration::from_millis(1));
+        chain = manager.wait_chain(t1.thread().id());
+    }
+    let expected = vec![
+        DependencyNode::Thread(t1.thread().id()),
+        DependencyNode::Lock(l1.key().unwrap()),
+        DependencyNode::Thread(t2.thread().id()),
+        DependencyNode::Lock(l2.key().unwrap()),
+        DependencyNode::Thread(t3.thread().id()),
+    ];
+    release.send(()).unwrap();
+    for thread in [t1, t2, t3] {
+        thread.join().unwrap();
+    }
+    assert_eq!(chain, expected);
+}
+
+#[test]
+fn line_oriented_records() {
+    use crate::{LockError, Mutex};
+    use std::sync::Barrier;
+    let manager = Arc::new(LockManager::with_analysis_timeout(Duration::from_millis(
+        10,
+    )));
+    manager.set_report_format(ReportFormat::Records);
+    let cache = Arc::new(Mutex::with_manager(manager.clone(), ()));
+    cache.set_name("cache");
+    let index = Arc::new(Mutex::with_manager(manager.clone(), ()));
+    index.set_name("search index");
+    let barrier = Arc::new(Barrier::new(2));
+    let threads: Vec<_> = vec![
+        ("io-worker", cache.clone(), index.clone()),
+        ("flusher", index.clone(), cache.clone()),
+    ]
+    .into_iter()
+    .map(|(name, first, second)| {
+        let barrier = barrier.clone();
+        std::thread::Builder::new()
+            .name(name.to_owned())
+            .spawn(move || {
+                let _first = first.lock();
+                barrier.wait();
+                match second.lock_checked() {
+                    Err(LockError::Deadlock(report)) => Some(report),
+                    _ => None,
+                }
+            })
+            .unwrap()
+    })
+    .collect();
+    let reports: Vec<DeadlockReport> = threads
+        .into_iter()
+        .filter_map(|thread| thread.join().unwrap())
+        .collect();
+    assert_eq!(reports.len(), 1);
+    let mut output = Vec::new();
+    manager.read_lock().emit_report(&mut output, &reports[0]);
+    let output = String::from_utf8(output).unwrap();
+    let mut lines: Vec<&str> = output.lines().collect();
+    lines.sort_unstable();
+    assert_eq!(lines.len(), 2);
+    let mut expected = [
+        ("flusher", cache.key().unwrap(), "name=cache"),
+        ("io-worker", index.key().unwrap(), "name=\"search index\""),
+    ];
+    // Keys are single digits here, so lines sort like their keys.
+    expected.sort_unstable_by_key(|(_, lock, _)| *lock);
+    for (line, (thread, lock, name)) in lines.iter().zip(expected.iter()) {
+        let prefix = format!(
+            "deadlock lock={} {} thread={} request=write frame=\"",
+            lock, name, thread
+        );
+        assert!(line.starts_with(&prefix), "{}", line);
+        assert!(line.contains("line_oriented_records"), "{}", line);
+        assert!(line.ends_with('"'));
+    }
+}
+
+#[test]
+fn upgrade_warnings() {
+    use crate::{LockError, RwLock};
+    let manager = Arc::new(LockManager::with_analysis_timeout(Duration::from_millis(
+        10,
+    )));
+    let warnings = Arc::new(std::sync::Mutex::new(Vec::new()));
+    manager.set_warning_hook({
+        let warnings = warnings.clone();
+        move |warning: &str| warnings.lock().unwrap().push(warning.to_owned())
+    });
+    let rwlock = RwLock::with_manager(manager.clone(), ());
+    let read = rwlock.read();
+    manager.set_upgrade_warning(true);
+    assert!(matches!(
+        rwlock.try_write_for_checked(Duration::from_millis(50)),
+        Err(LockError::TimedOut)
+    ));
+    assert_eq!(warnings.lock().unwrap().len(), 1);
+    assert!(warnings.lock().unwrap()[0].contains("holds for reading"));
+    manager.set_upgrade_warning(false);
+    assert!(std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        let _upgrade = rwlock.write();
+    }))
+    .is_err());
+    std::mem::drop(read);
+    assert!(rwlock.try_write().is_ok());
+}
+
+#[test]
+fn reentrance_policies() {
+    use crate::{LockError, Mutex};
+    let manager = Arc::new(LockManager::with_analysis_timeout(Duration::from_millis(
+        10,
+    )));
+    let warnings = Arc::new(std::sync::Mutex::new(Vec::new()));
+    manager.set_warning_hook({
+        let warnings = warnings.clone();
+        move |warning: &str| warnings.lock().unwrap().push(warning.to_owned())
+    });
+    let mutex = Mutex::with_manager(manager.clone(), ());
+    let _guard = mutex.lock();
+    manager.set_reentrance_policy(ReentrancePolicy::Warn);
+    assert!(matches!(mutex.lock_checked(), Err(LockError::Deadlock(_))));
+    assert_eq!(warnings.lock().unwrap().len(), 1);
+    assert!(warnings.lock().unwrap()[0].contains("reentrance was attempted"));
+    manager.set_reentrance_policy(ReentrancePolicy::Ignore);
+    assert!(matches!(
+        mutex.try_lock_for_checked(Duration::from_millis(50)),
+        Err(LockError::TimedOut)
+    ));
+    assert_eq!(warnings.lock().unwrap().len(), 1);
+    manager.set_reentrance_policy(ReentrancePolicy::Warn);
+    // Plain acquisitions can't return the error, so they still panic after warning.
+    assert!(std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        std::mem::drop(mutex.lock());
+    }))
+    .is_err());
+    assert_eq!(warnings.lock().unwrap().len(), 2);
+}
+
+#[test]
+fn mutex_rwlock_abba() {
+    use crate::{LockError, Mutex, RwLock};
+    use std::sync::Barrier;
+    // Thread B holding the `RwLock` for reading deadlocks A just the same as holding it for writing.
+    for b_writes in [true, false].iter().cloned() {
+        let manager = Arc::new(LockManager::with_analysis_timeout(Duration::from_millis(
+            10,
+        )));
+        let mutex = Arc::new(Mutex::with_manager(manager.clone(), ()));
+        let rwlock = Arc::new(RwLock::with_manager(manager.clone(), ()));
+        let barrier = Arc::new(Barrier::new(2));
+        let a = std::thread::spawn({
+            let (mutex, rwlock, barrier) = (mutex.clone(), rwlock.clone(), barrier.clone());
+            move || {
+                let _mutex = mutex.lock();
+                barrier.wait();
+                match rwlock.write_checked() {
+                    Err(LockError::Deadlock(report)) => Some(report),
+                    _ => None,
+                }
+            }
+        });
+        let b = std::thread::spawn({
+            let (mutex, rwlock) = (mutex.clone(), rwlock.clone());
+            move || {
+                let (_write, _read) = if b_writes {
+                    (Some(rwlock.write()), None)
+                } else {
+                    (None, Some(rwlock.read()))
+                };
+                barrier.wait();
+                match mutex.lock_checked() {
+                    Err(LockError::Deadlock(report)) => Some(report),
+                    _ => None,
+                }
+            }
+        });
+        let reports: Vec<DeadlockReport> = vec![a.join().unwrap(), b.join().unwrap()]
+            .into_iter()
+            .flatten()
+            .collect();
+        // Whichever thread reports withdraws, letting the other one through.
+        assert_eq!(reports.len(), 1);
+        let cycle = &reports[0].cycle;
+        assert_eq!(cycle.len(), 4);
+        assert!(cycle.contains(&DependencyNode::Lock(mutex.key().unwrap())));
+        assert!(cycle.contains(&DependencyNode::Lock(rwlock.key().unwrap())));
+    }
+}
+
+#[test]
+fn thread_indices_in_reports() {
+    use crate::{LockError, Mutex};
+    use std::sync::Barrier;
+    let manager = Arc::new(LockManager::with_analysis_timeout(Duration::from_millis(
+        10,
+    )));
+    let mut1 = Arc::new(Mutex::with_manager(manager.clone(), ()));
+    let mut2 = Arc::new(Mutex::with_manager(manager.clone(), ()));
+    let both = Arc::new(Barrier::new(2));
+    let (locked, first_locked) = std::sync::mpsc::channel();
+    let spawn = |name: &str, held: Arc<Mutex<()>>, wanted: Arc<Mutex<()>>| {
+        let (both, locked) = (both.clone(), locked.clone());
+        std::thread::Builder::new()
+            .name(name.into())
+            .spawn(move || {
+                let _held = held.lock();
+                locked.send(()).unwrap();
+                both.wait();
+                match wanted.lock_checked() {
+                    Err(LockError::Deadlock(report)) => Some(report),
+                    _ => None,
+                }
+            })
+            .unwrap()
+    };
+    // `worker-a` locks before `worker-b` is even spawned, so they're numbered in that order.
+    let a = spawn("worker-a", mut1.clone(), mut2.clone());
+    first_locked.recv().unwrap();
+    let b = spawn("worker-b", mut2, mut1);
+    let (a_id, b_id) = (a.thread().id(), b.thread().id());
+    let report = vec![a.join().unwrap(), b.join().unwrap()]
+        .into_iter()
+        .flatten()
+        .next()
+        .unwrap();
+    assert_eq!(manager.thread_index(a_id), Some(0));
+    assert_eq!(manager.thread_index(b_id), Some(1));
+    assert_eq!(manager.thread_index(std::thread::current().id()), None);
+    assert!(report.text.contains("THREAD #0 (worker-a)"));
+    assert!(report.text.contains("THREAD #1 (worker-b)"));
+    assert!(report.text.contains("thread #0") && report.text.contains("thread #1"));
+    assert!(!report.text.contains("ThreadId("));
+}
+
+#[test]
+fn never_used_locks() {
+    use crate::{Mutex, RwLock};
+    let manager = Arc::new(LockManager::new());
+    let untracked = Mutex::with_manager(manager.clone(), ());
+    manager.enable_creation_tracking();
+    let forgotten = RwLock::with_manager(manager.clone(), ());
+    let used = Mutex::with_manager(manager.clone(), ());
+    std::mem::drop(used.lock());
+    assert!(manager.inactive_locks(Duration::from_millis(20)).is_empty());
+    std::thread::sleep(Duration::from_millis(30));
+    assert_eq!(
+        manager.inactive_locks(Duration::from_millis(20)),
+        vec![forgotten.key().unwrap()]
+    );
+    assert_eq!(untracked.key(), None);
+    std::mem::drop(forgotten);
+    assert!(manager.inactive_locks(Duration::from_millis(20)).is_empty());
+}
+
+#[test]
+fn prewarmed_symbols() {
+    prewarm();
+    let start = Instant::now();
+    prewarm();
+    assert!(start.elapsed() < Duration::from_millis(10));
+    let mut trace = Backtrace::new_unresolved();
+    trace.resolve();
+    assert!(trace
+        .frames()
+        .iter()
+        .flat_map(|frame| frame.symbols())
+        .any(|symbol| symbol.name().is_some()));
+}
+
+#[test]
+fn excluded_threads_form_no_cycles() {
+    use crate::{LockError, Mutex};
+    use std::sync::Barrier;
+    let manager = Arc::new(LockManager::with_analysis_timeout(Duration::from_millis(
+        10,
+    )));
+    let mut1 = Arc::new(Mutex::with_manager(manager.clone(), ()));
+    let mut2 = Arc::new(Mutex::with_manager(manager.clone(), ()));
+    let barrier = Arc::new(Barrier::new(3));
+    let spawn = |held: Arc<Mutex<()>>, wanted: Arc<Mutex<()>>, exclude: bool| {
+        let barrier = barrier.clone();
+        std::thread::spawn(move || {
+            if exclude {
+                exclude_current_thread();
+            }
+            let _held = held.lock();
+            barrier.wait();
+            // The excluded thread gives up first, letting the other one through.
+            let patience = Duration::from_millis(if exclude { 200 } else { 5000 });
+            matches!(
+                wanted.try_lock_for_checked(patience),
+                Err(LockError::Deadlock(_))
+            )
+        })
+    };
+    let background = spawn(mut1.clone(), mut2.clone(), true);
+    let worker = spawn(mut2, mut1, false);
+    barrier.wait();
+    std::thread::sleep(Duration::from_millis(50));
+    assert!(manager.find_any_deadlock().is_none());
+    let background_id = background.thread().id();
+    assert!(!background.join().unwrap());
+    assert!(!worker.join().unwrap());
+    // Exited threads stop being scanned by analyses.
+    assert!(!EXCLUDED_THREADS.lock().unwrap().contains(&background_id));
+}
+
+#[test]
+fn all_current_deadlocks() {
+    use crate::Mutex;
+    use std::sync::Barrier;
+    let manager = Arc::new(LockManager::with_analysis_timeout(Duration::from_millis(
+        10,
+    )));
+    let locks: Vec<_> = (0..4)
+        .map(|_| Arc::new(Mutex::with_manager(manager.clone(), ())))
+        .collect();
+    let barrier = Arc::new(Barrier::new(4));
+    // Two independent pairs of threads, each locking its pair of locks in opposite orders.
+    let threads: Vec<_> = [(0, 1), (1, 0), (2, 3), (3, 2)]
+        .iter()
+        .map(|(held, wanted)| {
+            let (held, wanted) = (locks[*held].clone(), locks[*wanted].clone());
+            let barrier = barrier.clone();
+            std::thread::spawn(move || {
+                let _held = held.lock();
+                barrier.wait();
+                // The waiters show up in the graph without reporting anything, and eventually give up.
+                suppress_detection(|| {
+                    std::mem::drop(wanted.try_lock_for_checked(Duration::from_secs(2)))
+                });
+            })
+        })
+        .collect();
+    let start = Instant::now();
+    let mut deadlocks = manager.current_deadlocks();
+    while deadlocks.len() < 2 {
+        assert!(start.elapsed() < Duration::from_secs(1));
+        std::thread::sleep(Duration::from_millis(10));
+        deadlocks = manager.current_deadlocks();
+    }
+    assert_eq!(deadlocks.len(), 2);
+    assert!(deadlocks.iter().all(|report| report.cycle.len() == 4));
+    assert!(deadlocks[0]
+        .cycle
+        .iter()
+        .all(|node| !deadlocks[1].cycle.contains(node)));
+    for thread in threads {
+        thread.join().unwrap();
+    }
+    assert!(manager.current_deadlocks().is_empty());
+}
+
+#[test]
+fn resolved_thread_names() {
+    use crate::{LockError, Mutex};
+    let manager = Arc::new(LockManager::new());
+    let current = std::thread::current().id();
+    manager.set_thread_name_resolver(move |thread| {
+        if thread == current {
+            Some("registry-worker".to_owned())
+        } else {
+            None
+        }
+    });
+    let mutex = Mutex::with_manager(manager, ());
+    let _guard = mutex.lock();
+    let report = match mutex.lock_checked() {
+        Err(LockError::Deadlock(report)) => report,
+        _ => panic!("the reentrance should be reported"),
+    };
+    assert!(report.summary.contains("threads=[registry-worker]"));
+    assert!(report.records[0].contains("thread=registry-worker"));
+}
+
+#[test]
+fn prometheus_exposition() {
+    use crate::Mutex;
+    let manager = Arc::new(LockManager::with_analysis_timeout(Duration::from_millis(
+        10,
+    )));
+    let mutex = Arc::new(Mutex::with_manager(manager.clone(), ()));
+    mutex.set_name("config \"main\"");
+    let uncontended = Mutex::with_manager(manager.clone(), ());
+    std::mem::drop(uncontended.lock());
+    let guard = mutex.lock().unwrap();
+    let waiter = std::thread::spawn({
+        let mutex = mutex.clone();
+        move || std::mem::drop(mutex.lock())
+    });
+    let start = Instant::now();
+    while manager.analyses.load(Ordering::Relaxed) == 0 {
+        assert!(start.elapsed() < Duration::from_secs(5));
+        std::thread::sleep(Duration::from_millis(1));
+    }
+    let text = manager.prometheus_metrics();
+    std::mem::drop(guard);
+    waiter.join().unwrap();
+    let key = mutex.key().unwrap();
+    for line in text.lines().filter(|line| line.starts_with("# TYPE ")) {
+        let fields: Vec<_> = line.split(' ').collect();
+        assert_eq!(fields.len(), 4, "{}", line);
+        assert!(fields[3] == "counter" || fields[3] == "gauge", "{}", line);
+    }
+    assert!(text.contains("# TYPE no_deadlocks_lock_acquisitions_total counter\n"));
+    let labels = format!("{{lock=\"{}\",name=\"config \\\"main\\\"\"}}", key);
+    assert!(text.contains(&format!(
+        "no_deadlocks_lock_acquisitions_total{} 1\n",
+        labels
+    )));
+    assert!(text.contains(&format!(
+        "no_deadlocks_lock_contentions_total{} 1\n",
+        labels
+    )));
+    assert!(text.contains(&format!("no_deadlocks_lock_holders{} 1\n", labels)));
+    assert!(text.contains("no_deadlocks_locks_held 1\n"));
+    assert!(text.contains("no_deadlocks_deadlocks_total 0\n"));
+    assert!(uncontended.key().is_none());
+    assert_eq!(
+        text.lines()
+            .filter(|line| line.starts_with("no_deadlocks_lock_acquisitions_total{"))
+            .count(),
+        1
+    );
+}
+
+#[test]
+fn first_failure_only_reports() {
+    use crate::Mutex;
+    #[derive(Clone, Default)]
+    struct Buffer(Arc<std::sync::Mutex<Vec<u8>>>);
+    impl std::io::Write for Buffer {
+        fn write(&mut self, bytes: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(bytes)
+        }
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+    let buffer = Buffer::default();
+    let manager = Arc::new(LockManager::with_output(Box::new(buffer.clone())));
+    manager.set_first_failure_only(true);
+    let mutex = Mutex::with_manager(manager, ());
+    let _guard = mutex.lock();
+    for _ in 0..2 {
+        let retry = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            std::mem::drop(mutex.lock());
+        }));
+        assert!(retry.is_err());
+    }
+    let written = String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap();
+    assert_eq!(written.matches("REPORT START").count(), 1);
+}
+
+#[test]
+fn injected_delays_reproduce_inversions() {
+    use crate::{LockError, Mutex};
+    let manager = Arc::new(LockManager::with_analysis_timeout(Duration::from_millis(
+        10,
+    )));
+    manager.enable_creation_tracking();
+    let a = Arc::new(Mutex::with_manager(manager.clone(), ()));
+    let b = Arc::new(Mutex::with_manager(manager.clone(), ()));
+    // Both threads hold their first lock by the time either requests its second.
+    manager.inject_delay(a.key().unwrap(), Duration::from_millis(100));
+    manager.inject_delay(b.key().unwrap(), Duration::from_millis(100));
+    let inverted = |first: Arc<Mutex<()>>, second: Arc<Mutex<()>>| {
+        std::thread::spawn(move || {
+            let _first = first.lock_checked().ok();
+            matches!(second.lock_checked(), Err(LockError::Deadlock(_)))
+        })
+    };
+    let ab = inverted(a.clone(), b.clone());
+    let ba = inverted(b.clone(), a.clone());
+    let deadlocks = [ab.join().unwrap(), ba.join().unwrap()];
+    assert_eq!(deadlocks.iter().filter(|deadlock| **deadlock).count(), 1);
+    manager.inject_delay(a.key().unwrap(), Duration::from_secs(0));
+    manager.inject_delay(b.key().unwrap(), Duration::from_secs(0));
+    assert!(!manager.delays_injected.load(Ordering::Relaxed));
+}
+
+#[test]
+fn memory_usage_grows_with_traces() {
+    use crate::Mutex;
+    let manager = Arc::new(LockManager::new());
+    manager.enable_creation_tracking();
+    let mutexes: Vec<_> = (0..100)
+        .map(|_| Mutex::with_manager(manager.clone(), ()))
+        .collect();
+    let idle = manager.memory_usage();
+    assert_eq!(idle.backtraces, 0);
+    let few: Vec<_> = mutexes[..10].iter().map(|mutex| mutex.lock()).collect();
+    let some = manager.memory_usage();
+    let many: Vec<_> = mutexes[10..].iter().map(|mutex| mutex.lock()).collect();
+    let all = manager.memory_usage();
+    assert_eq!((some.backtraces, all.backtraces), (10, 100));
+    assert!(idle.backtrace_bytes < some.backtrace_bytes);
+    assert!(some.backtrace_bytes < all.backtrace_bytes);
+    assert!(some.total() < all.total());
+    std::mem::drop((few, many));
+    assert_eq!(manager.memory_usage().backtraces, 0);
+}
+
+#[test]
+fn reports_call_sites() {
+    use crate::Mutex;
+    let manager = Arc::new(LockManager::new());
+    manager.set_location_capture(true);
+    let mutex = Mutex::with_manager(manager, ());
+    let (_guard, acquired) = (mutex.lock(), line!());
+    let (report, requested) = (
+        crate::testing::capture_deadlock_report(|| std::mem::drop(mutex.lock())),
+        line!(),
+    );
+    let report = report.unwrap();
+    assert!(report.contains(&format!("acquired at {}:{}:", file!(), acquired)));
+    assert!(report.contains(&format!("requested at {}:{}:", file!(), requested - 1)));
+}
+
+#[test]
+fn convoys_are_reported() {
+    use crate::Mutex;
+    let manager = Arc::new(LockManager::new());
+    manager.enable_convoy_detection(20, Duration::from_secs(10));
+    let warnings = Arc::new(std::sync::Mutex::new(Vec::new()));
+    manager.set_warning_hook({
+        let warnings = warnings.clone();
+        move |message| warnings.lock().unwrap().push(message.to_owned())
+    });
+    let mutex = Arc::new(Mutex::with_manager(manager.clone(), 0u64));
+    mutex.set_name("queue");
+    for _ in 0..100 {
+        *mutex.lock().unwrap() += 1;
+    }
+    assert!(warnings.lock().unwrap().is_empty());
+    let threads: Vec<_> = (0..4)
+        .map(|_| {
+            let mutex = mutex.clone();
+            std::thread::spawn(move || {
+                for _ in 0..100 {
+                    let mut guard = mutex.lock().unwrap();
+                    *guard += 1;
+                    std::thread::sleep(Duration::from_micros(100));
+                    std::mem::drop(guard);
+                    std::thread::sleep(Duration::from_micros(50));
+                }
+            })
+        })
+        .collect();
+    for thread in threads {
+        thread.join().unwrap();
+    }
+    let warnings = warnings.lock().unwrap();
+    assert_eq!(warnings.len(), 1);
+    assert!(warnings[0].starts_with("possible lock convoy on queue:"));
+    assert!(warnings[0].contains("last acquired it at:"));
+}
+
+#[test]
+fn latent_deadlocks_from_a_single_thread() {
+    use crate::Mutex;
+    let manager = Arc::new(LockManager::new());
+    manager.enable_lock_order_recording();
+    let warnings = Arc::new(std::sync::Mutex::new(Vec::new()));
+    manager.set_warning_hook({
+        let warnings = warnings.clone();
+        move |message| warnings.lock().unwrap().push(message.to_owned())
+    });
+    let a = Mutex::with_manager(manager.clone(), ());
+    let b = Mutex::with_manager(manager.clone(), ());
+    a.set_name("a");
+    b.set_name("b");
+    {
+        let _a = a.lock();
+        let _b = b.lock();
+    }
+    assert!(!manager.report_latent_deadlocks());
+    {
+        let _b = b.lock();
+        let _a = a.lock();
+    }
+    let mut cycles = manager.latent_deadlocks();
+    cycles[0].sort_unstable();
+    assert_eq!(cycles, [[a.key().unwrap(), b.key().unwrap()]]);
+    assert!(manager.report_latent_deadlocks());
+    let warnings = warnings.lock().unwrap();
+    assert_eq!(warnings.len(), 1);
+    assert!(warnings[0].ends_with("a -> b -> a") || warnings[0].ends_with("b -> a -> b"));
+}
+
+#[test]
+fn cross_thread_releases() {
+    use crate::Mutex;
+    struct Smuggled<G>(G);
+    unsafe impl<G> Send for Smuggled<G> {}
+    let manager = Arc::new(LockManager::new());
+    manager.warn_on_cross_thread_release(true);
+    let warnings = Arc::new(std::sync::Mutex::new(Vec::new()));
+    manager.set_warning_hook({
+        let warnings = warnings.clone();
+        move |message| warnings.lock().unwrap().push(message.to_owned())
+    });
+    let mutex: &'static Mutex<()> = Box::leak(Box::new(Mutex::with_manager(manager, ())));
+    mutex.set_name("config");
+    let guard = Smuggled(mutex.lock().unwrap());
+    std::thread::spawn(move || std::mem::drop(guard))
+        .join()
+        .unwrap();
+    // The acquiring thread no longer counts the lock as held.
+    crate::assert_no_locks_held();
+    assert!(mutex.try_lock().is_ok());
+    let warnings = warnings.lock().unwrap();
+    assert_eq!(warnings.len(), 1);
+    assert!(warnings[0].starts_with("config was released by thread"));
+    assert!(warnings[0].contains("Released at:"));
+}
+
+#[test]
+fn structured_reentrance_reports() {
+    use crate::Mutex;
+    let manager = Arc::new(LockManager::new());
+    let reports = Arc::new(std::sync::Mutex::new(Vec::new()));
+    manager.set_deadlock_hook({
+        let reports = reports.clone();
+        move |report: &DeadlockReport| reports.lock().unwrap().push(report.kind.clone())
+    });
+    let mutex = Mutex::with_manager(manager, ());
+    mutex.set_name("config");
+    let _guard = mutex.lock();
+    let _ = crate::testing::capture_deadlock_report(|| std::mem::drop(mutex.lock()));
+    let reports = reports.lock().unwrap();
+    match reports.first() {
+        Some(DeadlockKind::Reentrance(reentrance)) => {
+            assert_eq!(reentrance.lock_id, mutex.key().unwrap());
+            assert_eq!(reentrance.lock_name.as_deref(), Some("config"));
+            assert!(!reentrance.original_acquire.frames().is_empty());
+            assert!(!reentrance.reentrant_attempt.frames().is_empty());
+        }
+        other => panic!("expected a reentrance, got {:?}", other),
+    }
+}
+
+#[test]
+fn folded_wait_stack_export() {
+    use crate::Mutex;
+    #[inline(never)]
+    fn contended_site(mutex: &Mutex<()>) {
+        std::mem::drop(mutex.lock());
+    }
+    let manager = Arc::new(LockManager::new());
+    let mutex = Arc::new(Mutex::with_manager(manager.clone(), ()));
+    let guard = mutex.lock();
+    let waiter = std::thread::spawn({
+        let mutex = mutex.clone();
+        move || contended_site(&mutex)
+    });
+    let start = Instant::now();
+    while mutex.stats().contentions == 0 {
+        assert!(start.elapsed() < Duration::from_secs(5));
+        std::thread::sleep(Duration::from_millis(1));
+    }
+    std::thread::sleep(Duration::from_millis(5));
+    std::mem::drop(guard);
+    waiter.join().unwrap();
+    let folded = manager.folded_wait_stacks();
+    let line = folded
+        .lines()
+        .find(|line| line.contains("folded_wait_stack_export::contended_site"))
+        .unwrap();
+    let (stack, micros) = line.rsplit_once(' ').unwrap();
+    assert!(micros.parse::<u64>().unwrap() >= 1000);
+    assert!(!stack.contains("no_deadlocks::mutex::Mutex"));
 }
@@ -1,11 +1,24 @@
-use std::cell::UnsafeCell;
+use std::cell::{RefCell, UnsafeCell};
+use std::ops::ControlFlow;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{LockResult, PoisonError, TryLockError, TryLockResult};
-use std::time::Instant;
+use std::thread::ThreadId;
+use std::time::{Duration, Instant};
+
+use backtrace::Backtrace;
+
+use crate::lock_manager::{
+    at_caller, held_by_current_thread, AcquireError, LazyKey, LockStats, NotSend, Progress,
+    RepresentationCell, RequestType, WaitOptions,
+};
+use crate::{CheckedResult, LockError};
 
 /// An instrumented version of `std::sync::Mutex`
+///
+/// Uncontended acquisitions only lock this mutex's own state: the manager is only involved once a thread has to wait.
 pub struct Mutex<T: ?Sized> {
-    key: usize,
+    key: LazyKey,
+    representation: std::sync::Arc<RepresentationCell>,
     poisoned: AtomicBool,
     manager: std::sync::Arc<crate::lock_manager::LockManager>,
     inner: UnsafeCell<T>,
@@ -19,51 +32,173 @@ impl<T: Default> Default for Mutex<T> {
 
 impl<T> Mutex<T> {
     pub fn new(inner: T) -> Self {
-        let manager = crate::lock_manager::LockManager::get_global_manager();
-        let key = manager.create_lock();
-        Mutex {
-            inner: UnsafeCell::new(inner),
-            poisoned: AtomicBool::new(false),
-            manager,
-            key,
-        }
+        Self::with_manager(
+            crate::lock_manager::LockManager::get_global_manager(),
+            inner,
+        )
+    }
+
+    /// Creates a lock whose contention is analysed immediately, regardless of the manager's timeout.
+    pub fn new_eager(inner: T) -> Self {
+        let lock = Self::new(inner);
+        lock.set_analysis_timeout(Some(Duration::from_secs(0)));
+        lock
+    }
+
+    /// Creates a lock that captures backtraces even if `set_backtrace_capture` disabled them.
+    pub fn new_traced(inner: T) -> Self {
+        let lock = Self::new(inner);
+        lock.representation.lock().capture_traces = Some(true);
+        lock
+    }
+
+    /// Creates a lock that never captures backtraces, sparing their cost: its reports note where they're missing.
+    pub fn new_untraced(inner: T) -> Self {
+        let lock = Self::new(inner);
+        lock.representation.lock().capture_traces = Some(false);
+        lock
+    }
+
+    /// Creates a leaf lock: acquiring any other lock while holding it panics, before it can cause a deadlock.
+    pub fn new_leaf(inner: T) -> Self {
+        let lock = Self::new(inner);
+        lock.representation.lock().leaf = true;
+        lock
+    }
+
+    /// Creates a lock with a priority, see `set_priority`.
+    pub fn new_with_priority(priority: u32, inner: T) -> Self {
+        let lock = Self::new(inner);
+        lock.set_priority(priority);
+        lock
     }
 
     pub fn with_manager(
         manager: std::sync::Arc<crate::lock_manager::LockManager>,
         inner: T,
     ) -> Self {
-        let key = manager.create_lock();
-        Mutex {
+        let lock = Mutex {
             inner: UnsafeCell::new(inner),
             poisoned: AtomicBool::new(false),
             manager,
-            key,
+            representation: Default::default(),
+            key: LazyKey::new(),
+        };
+        lock.manager.on_create(&lock.key, &lock.representation);
+        lock
+    }
+
+    /// Locks the mutex and replaces its value, returning the previous one.
+    pub fn replace(&self, value: T) -> LockResult<T> {
+        match self.lock() {
+            Ok(mut guard) => Ok(std::mem::replace(&mut *guard, value)),
+            Err(poisoned) => Err(PoisonError::new(std::mem::replace(
+                &mut *poisoned.into_inner(),
+                value,
+            ))),
+        }
+    }
+
+    /// Swaps the values of two mutexes.
+    /// Both are locked in address order, so concurrent swaps of the same pair can't deadlock each other.
+    pub fn swap(&self, other: &Mutex<T>) -> LockResult<()> {
+        if std::ptr::eq(self, other) {
+            return Ok(());
+        }
+        let (first, second) = if (self as *const Self) < (other as *const Self) {
+            (self, other)
+        } else {
+            (other, self)
+        };
+        let mut first = first.lock().unwrap_or_else(PoisonError::into_inner);
+        let mut second = second.lock().unwrap_or_else(PoisonError::into_inner);
+        std::mem::swap(&mut *first, &mut *second);
+        if self.is_poisoned() || other.is_poisoned() {
+            Err(PoisonError::new(()))
+        } else {
+            Ok(())
         }
     }
 
     pub fn into_inner(self) -> LockResult<T> {
-        let key = self.key;
+        let key = self.key.get();
         let poisonned = self.poisoned.load(Ordering::Relaxed);
         let manager = unsafe { core::ptr::read(&self.manager) };
+        let representation = unsafe { core::ptr::read(&self.representation) };
         let value = unsafe { core::ptr::read(&self.inner) }.into_inner();
         core::mem::forget(self);
-        manager.remove_lock(&key);
+        std::mem::drop(representation);
+        if let Some(key) = key {
+            manager.remove_lock(&key);
+        }
         if poisonned {
             Err(PoisonError::new(value))
         } else {
             Ok(value)
         }
     }
+
+    /// Locks the mutex, returning a guard only known to deref to `T`, which fits generic code written against
+    /// `std::sync::MutexGuard` through `Deref`/`DerefMut` bounds. `std`'s guards can't be built from this crate's,
+    /// so APIs naming `std::sync::MutexGuard` itself must still be migrated. Like `lock_two`, this ignores poisoning.
+    #[track_caller]
+    pub fn lock_std_like(&self) -> impl std::ops::DerefMut<Target = T> + '_ {
+        self.lock().unwrap_or_else(PoisonError::into_inner)
+    }
 }
 
 impl<T: ?Sized> Drop for Mutex<T> {
     fn drop(&mut self) {
-        self.manager.remove_lock(&self.key)
+        if let Some(key) = self.key.get() {
+            self.manager.remove_lock(&key)
+        }
     }
 }
 
 impl<T: ?Sized> Mutex<T> {
+    /// Overrides the manager's analysis timeout for this lock; `None` restores the default.
+    pub fn set_analysis_timeout(&self, timeout: Option<Duration>) {
+        self.representation.lock().analysis_timeout = timeout;
+    }
+
+    /// The key under which this lock appears in reports, once it has been contended.
+    pub fn key(&self) -> Option<usize> {
+        self.key.get()
+    }
+
+    pub(crate) fn representation(&self) -> &std::sync::Arc<RepresentationCell> {
+        &self.representation
+    }
+
+    pub fn name(&self) -> Option<String> {
+        self.representation.lock().name.clone()
+    }
+
+    pub fn set_name<S: Into<String>>(&self, name: S) {
+        self.representation.lock().name = Some(name.into());
+    }
+
+    /// When a deadlock is detected, the thread holding the cycle's lowest priority lock is the one that panics,
+    /// releasing its locks so that the others can go on. Locks default to priority 0.
+    pub fn set_priority(&self, priority: u32) {
+        self.representation.lock().priority = priority;
+    }
+
+    pub fn stats(&self) -> LockStats {
+        self.representation.lock().stats.clone()
+    }
+
+    /// Zeroes this lock's counters, keeping its key, name and settings.
+    pub fn reset_diagnostics(&self) {
+        self.representation.lock().stats = LockStats::default();
+    }
+
+    /// Whether the calling thread holds this mutex, as in `debug_assert!(config.is_held_by_current_thread())`.
+    /// Only a thread-local list is consulted unless this thread does hold it.
+    pub fn is_held_by_current_thread(&self) -> bool {
+        held_by_current_thread(&self.representation, RequestType::Write)
+    }
+
     pub fn get_mut(&mut self) -> LockResult<&mut T> {
         let reference = unsafe { &mut *self.inner.get() };
         if self.poisoned.load(Ordering::Relaxed) {
@@ -77,11 +212,13 @@ impl<T: ?Sized> Mutex<T> {
         self.poisoned.load(Ordering::Relaxed)
     }
 
-    pub fn try_lock(&self) -> TryLockResult<MutexGuard<T>> {
-        let mut guard = self.manager.write_lock();
-        let representation = guard.locks.get_mut(&self.key).unwrap();
+    pub fn try_lock(&self) -> TryLockResult<MutexGuard<'_, T>> {
+        let mut representation = self.representation.lock();
         if representation.try_write_lock() {
-            let returned_guard = MutexGuard { inner: self };
+            self.manager.on_acquire(&self.key, RequestType::Write);
+            std::mem::drop(representation);
+            self.manager.after_acquire(&self.key, &self.representation);
+            let returned_guard = MutexGuard::new(self);
             if self.is_poisoned() {
                 Err(TryLockError::Poisoned(PoisonError::new(returned_guard)))
             } else {
@@ -92,33 +229,328 @@ impl<T: ?Sized> Mutex<T> {
         }
     }
 
-    pub fn lock(&self) -> LockResult<MutexGuard<T>> {
-        let timeout = self.manager.analysis_timeout();
-        let start = Instant::now();
+    /// A best-effort `try_lock` for hot paths that can retry: it never waits, not even for the lock's bookkeeping,
+    /// so it may fail spuriously while another thread merely inspects the lock, like `compare_exchange_weak`.
+    /// Returns `None` on any failure, and ignores poisoning.
+    pub fn try_lock_weak(&self) -> Option<MutexGuard<'_, T>> {
+        let mut representation = self.representation.try_lock()?;
+        if !representation.try_write_lock() {
+            return None;
+        }
+        self.manager.on_acquire(&self.key, RequestType::Write);
+        std::mem::drop(representation);
+        self.manager.after_acquire(&self.key, &self.representation);
+        Some(MutexGuard::new(self))
+    }
 
-        loop {
-            let mut guard = self.manager.write_lock();
-            let representation = guard.locks.get_mut(&self.key).unwrap();
+    #[track_caller]
+    pub fn lock(&self) -> LockResult<MutexGuard<'_, T>> {
+        at_caller(&self.manager, std::panic::Location::caller(), || {
+            self.acquire()
+        });
+        let returned_guard = MutexGuard::new(self);
+        if self.is_poisoned() {
+            Err(PoisonError::new(returned_guard))
+        } else {
+            Ok(returned_guard)
+        }
+    }
 
-            if representation.try_write_lock() {
-                let returned_guard = MutexGuard { inner: self };
-                if self.is_poisoned() {
-                    return Err(PoisonError::new(returned_guard));
-                } else {
-                    return Ok(returned_guard);
+    /// Locks the mutex, keeping it only if `pred` holds for its contents: otherwise, it's released right away and `None` is returned.
+    #[track_caller]
+    pub fn lock_if<F: FnOnce(&T) -> bool>(&self, pred: F) -> LockResult<Option<MutexGuard<'_, T>>> {
+        at_caller(&self.manager, std::panic::Location::caller(), || {
+            self.acquire()
+        });
+        let guard = MutexGuard::new(self);
+        let guard = if pred(unsafe { &*self.inner.get() }) {
+            Some(guard)
+        } else {
+            None
+        };
+        if self.is_poisoned() {
+            Err(PoisonError::new(guard))
+        } else {
+            Ok(guard)
+        }
+    }
+
+    /// Blocks until the lock is taken, analysing the dependency graph if that takes too long.
+    fn acquire(&self) {
+        self.acquire_with(&WaitOptions::default()).ok();
+    }
+
+    fn acquire_with(&self, options: &WaitOptions) -> Result<(), AcquireError> {
+        self.manager
+            .acquire(&self.key, &self.representation, RequestType::Write, options)
+    }
+
+    /// Like `lock`, but returns detected deadlocks instead of panicking.
+    pub fn lock_checked(&self) -> CheckedResult<MutexGuard<'_, T>> {
+        self.acquire_with(&WaitOptions {
+            return_deadlocks: true,
+            ..WaitOptions::default()
+        })?;
+        LockError::check_poison(MutexGuard::new(self), self.is_poisoned())
+    }
+
+    /// Like `try_lock`, but can report reentrance, see `LockManager::set_try_lock_reentrance_detection`.
+    pub fn try_lock_checked(&self) -> CheckedResult<MutexGuard<'_, T>> {
+        match self.try_lock() {
+            Err(TryLockError::WouldBlock) => Err(self
+                .manager
+                .reentrance(&self.representation, RequestType::Write)
+                .map_or(LockError::WouldBlock, LockError::WouldDeadlockReentrant)),
+            result => Ok(result?),
+        }
+    }
+
+    /// Like `lock_checked`, but gives up with `LockError::TimedOut` after `timeout`.
+    pub fn try_lock_for_checked(&self, timeout: Duration) -> CheckedResult<MutexGuard<'_, T>> {
+        self.acquire_with(&WaitOptions {
+            deadline: Some(Instant::now() + timeout),
+            return_deadlocks: true,
+            ..WaitOptions::default()
+        })?;
+        LockError::check_poison(MutexGuard::new(self), self.is_poisoned())
+    }
+
+    /// Like `lock`, but the returned guard warns through the manager if it is held longer than `threshold`.
+    pub fn lock_warn_after(&self, threshold: Duration) -> LockResult<WarnAfterGuard<'_, T>> {
+        let trace = Backtrace::new_unresolved();
+        let wrap = |guard| WarnAfterGuard {
+            guard,
+            acquired: Instant::now(),
+            threshold,
+            trace,
+        };
+        match self.lock() {
+            Ok(guard) => Ok(wrap(guard)),
+            Err(poisoned) => Err(PoisonError::new(wrap(poisoned.into_inner()))),
+        }
+    }
+
+    /// Like `lock`, but a watchdog thread warns through the manager, with where the lock was acquired,
+    /// as soon as the returned guard has been held for longer than `max_hold`.
+    pub fn lock_with_deadline(&self, max_hold: Duration) -> LockResult<DeadlineGuard<'_, T>> {
+        self.lock_watched(max_hold, false)
+    }
+
+    /// Like `lock_with_deadline`, but the watchdog also releases the lock once it warned, letting other threads take it.
+    ///
+    /// # Safety
+    /// Once `max_hold` elapsed, the guard may still give access to the data while another thread holds the lock:
+    /// the caller must not use it past that point. Only meant for tests that must not hang on a stuck thread.
+    pub unsafe fn lock_with_forced_release(
+        &self,
+        max_hold: Duration,
+    ) -> LockResult<DeadlineGuard<'_, T>> {
+        self.lock_watched(max_hold, true)
+    }
+
+    fn lock_watched(&self, max_hold: Duration, force: bool) -> LockResult<DeadlineGuard<'_, T>> {
+        let wrap = |guard| {
+            let lock = match (self.key(), self.name()) {
+                (_, Some(name)) => name,
+                (Some(key), None) => format!("lock {}", key),
+                (None, None) => "a lock".to_owned(),
+            };
+            let mut trace = Backtrace::new_unresolved();
+            let (manager, representation) = (self.manager.clone(), self.representation.clone());
+            // Registered up front, so that the expiry owns everything it releases the lock with.
+            let key = if force {
+                Some(self.manager.register_lock(&self.key, &self.representation))
+            } else {
+                None
+            };
+            let holder = std::thread::current().id();
+            let watch = std::sync::Arc::new(Watch::default());
+            watch
+                .state
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .on_expiry = Some(Box::new(move |state: &mut WatchState| {
+                trace.resolve();
+                manager.warn(&format!(
+                    "{} has been held for longer than {:?}{}. Acquired at:\n{:?}",
+                    lock,
+                    max_hold,
+                    if force {
+                        ", forcing its release"
+                    } else {
+                        ", and still is"
+                    },
+                    trace
+                ));
+                if let Some(key) = key {
+                    manager.release_for(&LazyKey::registered(key), &representation, holder);
+                    state.forced = true;
+                }
+            }));
+            Deadlines::get().watch(Instant::now() + max_hold, watch.clone());
+            DeadlineGuard {
+                guard: Some(guard),
+                watch,
+            }
+        };
+        match self.lock() {
+            Ok(guard) => Ok(wrap(guard)),
+            Err(poisoned) => Err(PoisonError::new(wrap(poisoned.into_inner()))),
+        }
+    }
+
+    /// Like `lock_checked`, but calls `f` every `interval` while waiting.
+    /// If `f` breaks, waiting stops and `LockError::Cancelled` is returned.
+    pub fn lock_with_progress<F: FnMut() -> ControlFlow<()>>(
+        &self,
+        interval: Duration,
+        mut f: F,
+    ) -> CheckedResult<MutexGuard<'_, T>> {
+        self.acquire_with(&WaitOptions {
+            return_deadlocks: true,
+            progress: Some(Progress {
+                interval,
+                callback: RefCell::new(&mut f),
+            }),
+            ..WaitOptions::default()
+        })?;
+        LockError::check_poison(MutexGuard::new(self), self.is_poisoned())
+    }
+
+    /// Like `lock_checked`, but gives up with `LockError::Cancelled` once `cancel` is set,
+    /// so that threads waiting for a lock can follow a cooperative shutdown.
+    pub fn lock_cancellable(&self, cancel: &AtomicBool) -> CheckedResult<MutexGuard<'_, T>> {
+        self.acquire_with(&WaitOptions {
+            return_deadlocks: true,
+            cancel: Some(cancel),
+            ..WaitOptions::default()
+        })?;
+        LockError::check_poison(MutexGuard::new(self), self.is_poisoned())
+    }
+
+    fn release(&self) {
+        let mut representation = self.representation.lock();
+        representation.unlock();
+        self.manager.on_release(&self.key, &self.representation);
+    }
+}
+
+/// Locks two mutexes together, returning their guards in argument order.
+/// They're locked in address order, so `lock_two(x, y)` and `lock_two(y, x)` can't deadlock each other.
+/// Like `Mutex::swap`, this ignores poisoning: use `Mutex::is_poisoned` to check for it.
+///
+/// Panics if `a` and `b` are the same mutex, which would otherwise deadlock.
+pub fn lock_two<'a, A, B>(
+    a: &'a Mutex<A>,
+    b: &'a Mutex<B>,
+) -> (MutexGuard<'a, A>, MutexGuard<'a, B>) {
+    let (a_address, b_address) = (a as *const Mutex<A> as usize, b as *const Mutex<B> as usize);
+    if a_address == b_address {
+        panic!("lock_two was given the same mutex twice, which would deadlock");
+    }
+    if a_address < b_address {
+        let a = a.lock().unwrap_or_else(PoisonError::into_inner);
+        (a, b.lock().unwrap_or_else(PoisonError::into_inner))
+    } else {
+        let b = b.lock().unwrap_or_else(PoisonError::into_inner);
+        (a.lock().unwrap_or_else(PoisonError::into_inner), b)
+    }
+}
+
+/// Locks every mutex of `mutexes` together, returning their guards in argument order.
+/// They're tried in address order, and if one is taken, all those acquired so far are released before waiting for it:
+/// no thread ever holds some of them while waiting for the others. Like `lock_two`, this ignores poisoning.
+///
+/// Panics if a mutex appears twice, which would otherwise deadlock.
+pub fn lock_all_or_retry<'a, T>(mutexes: &[&'a Mutex<T>]) -> Vec<MutexGuard<'a, T>> {
+    let address = |index: &usize| mutexes[*index] as *const Mutex<T> as usize;
+    let mut order: Vec<usize> = (0..mutexes.len()).collect();
+    order.sort_unstable_by_key(address);
+    if order
+        .windows(2)
+        .any(|pair| address(&pair[0]) == address(&pair[1]))
+    {
+        panic!("lock_all_or_retry was given the same mutex twice, which would deadlock");
+    }
+    loop {
+        let mut guards: Vec<(usize, MutexGuard<'a, T>)> = Vec::with_capacity(mutexes.len());
+        let mut busy = None;
+        for index in order.iter() {
+            match mutexes[*index].try_lock() {
+                Ok(guard) => guards.push((*index, guard)),
+                Err(TryLockError::Poisoned(poisoned)) => {
+                    guards.push((*index, poisoned.into_inner()))
+                }
+                Err(TryLockError::WouldBlock) => {
+                    busy = Some(*index);
+                    break;
                 }
-            } else if Instant::now().duration_since(start) > timeout {
-                representation.subscribe_write();
-                guard.analyse();
             }
+        }
+        match busy {
+            None => {
+                guards.sort_unstable_by_key(|(index, _)| *index);
+                return guards.into_iter().map(|(_, guard)| guard).collect();
+            }
+            Some(index) => {
+                std::mem::drop(guards);
+                // Holding nothing, waiting for the busy mutex can't deadlock.
+                std::mem::drop(mutexes[index].lock());
+            }
+        }
+    }
+}
 
-            std::thread::yield_now();
+/// Guards of several mutexes, locked together by `lock_all_or_retry`, that are rolled back unless committed.
+/// If the transaction is dropped without `commit`, such as on an early return or a panic,
+/// `rollback` runs over the still held guards before they're released.
+/// A panic still poisons the mutexes, but their contents were rolled back by the time other threads see them.
+pub struct LockTransaction<'a, T, R: FnOnce(&mut [MutexGuard<'a, T>])> {
+    guards: Vec<MutexGuard<'a, T>>,
+    rollback: Option<R>,
+}
+
+impl<'a, T, R: FnOnce(&mut [MutexGuard<'a, T>])> LockTransaction<'a, T, R> {
+    pub fn new(mutexes: &[&'a Mutex<T>], rollback: R) -> Self {
+        LockTransaction {
+            guards: lock_all_or_retry(mutexes),
+            rollback: Some(rollback),
+        }
+    }
+
+    /// The guards, in the order their mutexes were given to `new`.
+    pub fn guards(&mut self) -> &mut [MutexGuard<'a, T>] {
+        &mut self.guards
+    }
+
+    /// Keeps the changes, releasing the mutexes without rolling back.
+    pub fn commit(mut self) {
+        self.rollback = None;
+    }
+}
+
+impl<'a, T, R: FnOnce(&mut [MutexGuard<'a, T>])> Drop for LockTransaction<'a, T, R> {
+    fn drop(&mut self) {
+        if let Some(rollback) = self.rollback.take() {
+            rollback(&mut self.guards);
         }
     }
 }
 
 pub struct MutexGuard<'l, T: ?Sized> {
     inner: &'l Mutex<T>,
+    /// The acquiring thread, see `LockManager::warn_on_cross_thread_release`.
+    holder: ThreadId,
+    _not_send: NotSend,
+}
+impl<'l, T: ?Sized> MutexGuard<'l, T> {
+    fn new(inner: &'l Mutex<T>) -> Self {
+        MutexGuard {
+            inner,
+            holder: std::thread::current().id(),
+            _not_send: NotSend::default(),
+        }
+    }
 }
 impl<'l, T> std::ops::Deref for MutexGuard<'l, T> {
     type Target = T;
@@ -132,18 +564,1017 @@ impl<'l, T> std::ops::DerefMut for MutexGuard<'l, T> {
     }
 }
 impl<'l, T: ?Sized> MutexGuard<'l, T> {
-    pub(crate) fn unlock(self) -> &'l Mutex<T> {
+    pub(crate) fn manager(&self) -> &'l crate::lock_manager::LockManager {
+        &self.inner.manager
+    }
+
+    /// Releases the lock, returning the mutex so that it may be locked again later:
+    /// `let mutex = MutexGuard::unlock(guard);`.
+    pub fn unlock(guard: Self) -> &'l Mutex<T> {
+        guard.inner
+    }
+
+    pub(crate) fn mutex(&self) -> &'l Mutex<T> {
         self.inner
     }
+
+    /// Clears the mutex's poison, returning whether it was poisoned. Since the guard holds the lock,
+    /// no other thread can observe the poison between the check and the clearing.
+    pub fn take_poison(&mut self) -> bool {
+        let poisoned = self.inner.poisoned.swap(false, Ordering::Relaxed);
+        if poisoned {
+            self.inner.representation.lock().poisoned = false;
+        }
+        poisoned
+    }
+
+    /// Keeps the mutex locked for the rest of the process, returning a reference to its contents.
+    /// The lock is never released: any later attempt to lock it, including from this thread, will block.
+    pub fn leak(guard: Self) -> &'l mut T {
+        let mutex = guard.inner;
+        std::mem::forget(guard);
+        mutex.manager.on_leak(&mutex.representation);
+        unsafe { &mut *mutex.inner.get() }
+    }
+
+    /// Releases the lock and immediately takes it back, giving waiting threads a chance to take it in between.
+    /// Returns an error if a thread that took the lock in between poisoned it.
+    pub fn bump(&mut self) -> LockResult<()> {
+        self.inner.release();
+        std::thread::yield_now();
+        self.inner.acquire();
+        if self.inner.is_poisoned() {
+            Err(PoisonError::new(()))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Releases the lock for the duration of `f`, taking it back before returning, even if `f` panics.
+    /// Like `bump`'s, the lock may have been poisoned in the meantime: use `Mutex::is_poisoned` to check for it.
+    pub fn unlocked<R, F: FnOnce() -> R>(&mut self, f: F) -> R {
+        struct Relock<'a, T: ?Sized>(&'a Mutex<T>);
+        impl<'a, T: ?Sized> Drop for Relock<'a, T> {
+            fn drop(&mut self) {
+                self.0.acquire();
+            }
+        }
+        self.inner.release();
+        let _relock = Relock(self.inner);
+        f()
+    }
 }
 impl<'l, T: ?Sized> Drop for MutexGuard<'l, T> {
     fn drop(&mut self) {
-        let mut guard = self.inner.manager.write_lock();
-        guard.locks.get_mut(&self.inner.key).unwrap().unlock();
+        // Poisoned before the release, so that the next holder can't take the lock without seeing it.
         if std::thread::panicking() {
             self.inner.poisoned.store(true, Ordering::Relaxed);
+            self.inner
+                .manager
+                .on_poison(&self.inner.key, &self.inner.representation);
+        }
+        self.inner
+            .manager
+            .release_for(&self.inner.key, &self.inner.representation, self.holder);
+    }
+}
+/// A `MutexGuard` that warns when dropped if it was held for too long. See `Mutex::lock_warn_after`.
+pub struct WarnAfterGuard<'l, T: ?Sized> {
+    guard: MutexGuard<'l, T>,
+    acquired: Instant,
+    threshold: Duration,
+    trace: Backtrace,
+}
+impl<'l, T> std::ops::Deref for WarnAfterGuard<'l, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+impl<'l, T> std::ops::DerefMut for WarnAfterGuard<'l, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.guard
+    }
+}
+impl<'l, T: ?Sized> Drop for WarnAfterGuard<'l, T> {
+    fn drop(&mut self) {
+        let held = self.acquired.elapsed();
+        if held > self.threshold {
+            self.trace.resolve();
+            let mutex = self.guard.inner;
+            let lock = match (mutex.key(), mutex.name()) {
+                (_, Some(name)) => name,
+                (Some(key), None) => format!("lock {}", key),
+                (None, None) => "a lock".to_owned(),
+            };
+            mutex.manager.warn(&format!(
+                "{} was held for {:?}, longer than {:?}. Acquired at:\n{:?}",
+                lock, held, self.threshold, self.trace
+            ));
+        }
+    }
+}
+
+/// A `MutexGuard` watched for being held too long. See `Mutex::lock_with_deadline`.
+pub struct DeadlineGuard<'l, T: ?Sized> {
+    /// Only `None` while dropping.
+    guard: Option<MutexGuard<'l, T>>,
+    watch: std::sync::Arc<Watch>,
+}
+/// Shared between a `DeadlineGuard` and the deadlines thread.
+#[derive(Default)]
+struct Watch {
+    state: std::sync::Mutex<WatchState>,
+}
+/// What the deadlines thread does once a guard expired.
+type Expiry = Box<dyn FnOnce(&mut WatchState) + Send>;
+#[derive(Default)]
+struct WatchState {
+    dropped: bool,
+    forced: bool,
+    on_expiry: Option<Expiry>,
+}
+
+/// The deadlines of every `DeadlineGuard`, expired by a single thread started on first use.
+struct Deadlines {
+    pending: std::sync::Mutex<Vec<(Instant, std::sync::Arc<Watch>)>>,
+    wake: std::sync::Condvar,
+}
+impl Deadlines {
+    fn get() -> &'static Deadlines {
+        static DEADLINES: std::sync::OnceLock<Deadlines> = std::sync::OnceLock::new();
+        DEADLINES.get_or_init(|| {
+            std::thread::Builder::new()
+                .name("no_deadlocks deadlines".into())
+                .spawn(|| Deadlines::get().run())
+                .expect("failed to spawn the deadlines thread");
+            Deadlines {
+                pending: Default::default(),
+                wake: Default::default(),
+            }
+        })
+    }
+
+    fn watch(&self, deadline: Instant, watch: std::sync::Arc<Watch>) {
+        self.pending
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .push((deadline, watch));
+        self.wake.notify_one();
+    }
+
+    fn unwatch(&self, watch: &std::sync::Arc<Watch>) {
+        self.pending
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .retain(|(_, pending)| !std::sync::Arc::ptr_eq(pending, watch));
+    }
+
+    fn run(&self) {
+        let mut pending = self.pending.lock().unwrap_or_else(PoisonError::into_inner);
+        loop {
+            let now = Instant::now();
+            let (expired, waiting) = pending
+                .drain(..)
+                .partition(|(deadline, _)| *deadline <= now);
+            *pending = waiting;
+            if !expired.is_empty() {
+                // Expiries warn through hooks: they run without blocking other guards.
+                std::mem::drop(pending);
+                for (_, watch) in expired {
+                    let mut state = watch.state.lock().unwrap_or_else(PoisonError::into_inner);
+                    if let (false, Some(expire)) = (state.dropped, state.on_expiry.take()) {
+                        expire(&mut state);
+                    }
+                }
+                pending = self.pending.lock().unwrap_or_else(PoisonError::into_inner);
+                continue;
+            }
+            pending = match pending.iter().map(|(deadline, _)| *deadline).min() {
+                Some(next) => {
+                    self.wake
+                        .wait_timeout(pending, next - now)
+                        .unwrap_or_else(PoisonError::into_inner)
+                        .0
+                }
+                None => self
+                    .wake
+                    .wait(pending)
+                    .unwrap_or_else(PoisonError::into_inner),
+            };
+        }
+    }
+}
+impl<'l, T: ?Sized> DeadlineGuard<'l, T> {
+    /// Whether the watchdog forcibly released the lock, see `Mutex::lock_with_forced_release`.
+    pub fn forcibly_released(&self) -> bool {
+        self.watch
+            .state
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .forced
+    }
+}
+impl<'l, T> std::ops::Deref for DeadlineGuard<'l, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        self.guard.as_deref().unwrap()
+    }
+}
+impl<'l, T> std::ops::DerefMut for DeadlineGuard<'l, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        self.guard.as_deref_mut().unwrap()
+    }
+}
+impl<'l, T: ?Sized> Drop for DeadlineGuard<'l, T> {
+    fn drop(&mut self) {
+        let mut state = self
+            .watch
+            .state
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        state.dropped = true;
+        let forced = state.forced;
+        std::mem::drop(state);
+        Deadlines::get().unwatch(&self.watch);
+        let guard = self.guard.take().unwrap();
+        if forced {
+            // The deadlines thread already released the lock through the manager.
+            std::mem::forget(guard);
+        }
+    }
+}
+
+unsafe impl<T: ?Sized + Send> Send for Mutex<T> {}
+unsafe impl<T: ?Sized + Send> Sync for Mutex<T> {}
+// Sharing a guard shares `&T`, so like `std`'s it's only `Sync` if `T` is.
+unsafe impl<'l, T: ?Sized + Sync> Sync for MutexGuard<'l, T> {}
+
+#[test]
+fn bump_lets_waiters_through() {
+    use std::sync::Arc;
+    use std::time::Duration;
+    let mutex = Arc::new(Mutex::new(0));
+    let mut guard = mutex.lock().unwrap();
+    let waiter = std::thread::spawn({
+        let mutex = mutex.clone();
+        move || *mutex.lock().unwrap() += 1
+    });
+    let start = Instant::now();
+    while *guard == 0 {
+        assert!(
+            start.elapsed() < Duration::from_secs(5),
+            "the waiter starved"
+        );
+        std::thread::sleep(Duration::from_millis(1));
+        guard.bump().unwrap();
+    }
+    *guard += guard.unlocked(|| 1);
+    assert_eq!(*guard, 2);
+    std::mem::drop(guard);
+    waiter.join().unwrap();
+    std::thread::spawn({
+        let mutex = mutex.clone();
+        move || {
+            let _guard = mutex.lock().unwrap();
+            panic!("poisoning");
+        }
+    })
+    .join()
+    .unwrap_err();
+    let mut guard = mutex.lock().unwrap_or_else(PoisonError::into_inner);
+    assert!(guard.bump().is_err());
+}
+
+#[test]
+fn unlocked_relocks_after_caught_panics() {
+    use std::panic::{catch_unwind, AssertUnwindSafe};
+    use std::sync::{mpsc, Arc};
+    let mutex = Arc::new(Mutex::new(0));
+    let (released, on_released) = mpsc::channel();
+    let (taken, on_taken) = mpsc::channel();
+    let (relocked, on_relocked) = mpsc::channel();
+    let panicking = std::thread::spawn({
+        let mutex = mutex.clone();
+        move || {
+            let mut guard = mutex.lock().unwrap();
+            catch_unwind(AssertUnwindSafe(|| {
+                guard.unlocked(|| {
+                    released.send(()).unwrap();
+                    on_taken.recv().unwrap();
+                    panic!("panicking while unlocked");
+                })
+            }))
+            .unwrap_err();
+            // The guard must have taken the lock back before it can be used again.
+            *guard += 1;
+            relocked.send(*guard).unwrap();
+        }
+    });
+    on_released.recv().unwrap();
+    let mut holder = mutex.lock().unwrap();
+    taken.send(()).unwrap();
+    std::thread::sleep(Duration::from_millis(50));
+    assert!(on_relocked.try_recv().is_err());
+    *holder = 10;
+    std::mem::drop(holder);
+    assert_eq!(on_relocked.recv().unwrap(), 11);
+    panicking.join().unwrap();
+    assert!(!mutex.is_poisoned());
+    assert_eq!(*mutex.try_lock().unwrap(), 11);
+}
+
+#[test]
+fn eager_lock_detects_immediately() {
+    use crate::lock_manager::LockManager;
+    use std::sync::{Arc, Barrier};
+    let manager = Arc::new(LockManager::with_analysis_timeout(Duration::from_secs(5)));
+    let mutexes: Vec<_> = (0..2)
+        .map(|_| {
+            let mutex = Mutex::with_manager(manager.clone(), ());
+            mutex.set_analysis_timeout(Some(Duration::from_secs(0)));
+            Arc::new(mutex)
+        })
+        .collect();
+    let barrier = Arc::new(Barrier::new(2));
+    let start = Instant::now();
+    let threads: Vec<_> = vec![
+        (mutexes[0].clone(), mutexes[1].clone()),
+        (mutexes[1].clone(), mutexes[0].clone()),
+    ]
+    .into_iter()
+    .map(|(first, second)| {
+        let barrier = barrier.clone();
+        std::thread::spawn(move || {
+            let _first = first.lock_checked().unwrap();
+            barrier.wait();
+            matches!(second.lock_checked(), Err(LockError::Deadlock(_)))
+        })
+    })
+    .collect();
+    let detected = threads
+        .into_iter()
+        .map(|thread| thread.join().unwrap())
+        .filter(|deadlock| *deadlock)
+        .count();
+    assert!(detected > 0);
+    assert!(start.elapsed() < Duration::from_secs(1));
+}
+
+#[test]
+fn concurrent_swaps() {
+    use std::sync::Arc;
+    let pair = Arc::new((Mutex::new(0), Mutex::new(1)));
+    assert_eq!(pair.0.replace(2).unwrap(), 0);
+    assert_eq!(pair.0.replace(0).unwrap(), 2);
+    let threads: Vec<_> = (0..2)
+        .map(|i| {
+            let pair = pair.clone();
+            std::thread::spawn(move || {
+                for _ in 0..1000 {
+                    if i == 0 {
+                        pair.0.swap(&pair.1).unwrap();
+                    } else {
+                        pair.1.swap(&pair.0).unwrap();
+                    }
+                }
+            })
+        })
+        .collect();
+    for thread in threads {
+        thread.join().unwrap();
+    }
+    let values = (*pair.0.lock().unwrap(), *pair.1.lock().unwrap());
+    assert!(values == (0, 1) || values == (1, 0));
+}
+
+#[test]
+fn reset_diagnostics_keeps_identity() {
+    use crate::lock_manager::LockManager;
+    use std::sync::Arc;
+    let manager = Arc::new(LockManager::new());
+    let mutex = Arc::new(Mutex::with_manager(manager.clone(), ()));
+    mutex.set_name("config");
+    let guard = mutex.lock().unwrap();
+    let waiter = std::thread::spawn({
+        let mutex = mutex.clone();
+        move || std::mem::drop(mutex.lock())
+    });
+    let start = Instant::now();
+    while mutex.stats().contentions == 0 {
+        assert!(start.elapsed() < Duration::from_secs(5));
+        std::thread::sleep(Duration::from_millis(1));
+    }
+    std::mem::drop(guard);
+    waiter.join().unwrap();
+    let key = mutex.key().unwrap();
+    let stats = manager.lock_stats(key).unwrap();
+    assert_eq!((stats.acquisitions, stats.contentions), (2, 1));
+    mutex.reset_diagnostics();
+    assert_eq!(mutex.stats(), LockStats::default());
+    assert_eq!(mutex.key(), Some(key));
+    assert_eq!(mutex.name().as_deref(), Some("config"));
+    std::mem::drop(mutex.lock());
+    assert!(manager.reset_lock_stats(key));
+    assert_eq!(manager.lock_stats(key), Some(LockStats::default()));
+}
+
+#[test]
+fn warns_when_held_too_long() {
+    use crate::lock_manager::LockManager;
+    use std::sync::Arc;
+    let manager = Arc::new(LockManager::new());
+    let warnings = Arc::new(std::sync::Mutex::new(Vec::new()));
+    manager.set_warning_hook({
+        let warnings = warnings.clone();
+        move |message| warnings.lock().unwrap().push(message.to_owned())
+    });
+    let mutex = Mutex::with_manager(manager, 0);
+    mutex.set_name("slow");
+    *mutex.lock_warn_after(Duration::from_secs(5)).unwrap() += 1;
+    assert!(warnings.lock().unwrap().is_empty());
+    {
+        let mut guard = mutex.lock_warn_after(Duration::from_millis(10)).unwrap();
+        *guard += 1;
+        std::thread::sleep(Duration::from_millis(20));
+    }
+    let warnings = warnings.lock().unwrap();
+    assert_eq!(warnings.len(), 1);
+    assert!(warnings[0].starts_with("slow was held for "));
+    assert!(warnings[0].contains("warns_when_held_too_long"));
+    assert_eq!(*mutex.lock().unwrap(), 2);
+}
+
+#[test]
+fn progress_callback_cancels() {
+    use std::sync::Arc;
+    let mutex = Arc::new(Mutex::new(()));
+    let _guard = mutex.lock().unwrap();
+    let calls = std::thread::spawn({
+        let mutex = mutex.clone();
+        move || {
+            let mut calls = 0;
+            let result = mutex.lock_with_progress(Duration::from_millis(5), || {
+                calls += 1;
+                if calls < 3 {
+                    ControlFlow::Continue(())
+                } else {
+                    ControlFlow::Break(())
+                }
+            });
+            assert!(matches!(result, Err(LockError::Cancelled)));
+            calls
+        }
+    })
+    .join()
+    .unwrap();
+    assert_eq!(calls, 3);
+}
+
+#[test]
+fn hold_time_stats() {
+    let mutex = Mutex::new(());
+    for hold in [10, 30].iter() {
+        let _guard = mutex.lock().unwrap();
+        std::thread::sleep(Duration::from_millis(*hold));
+    }
+    let stats = mutex.stats();
+    assert_eq!(stats.holds, 2);
+    assert!(stats.max_hold_time >= Duration::from_millis(30));
+    assert!(stats.max_hold_time < Duration::from_millis(500));
+    assert!(stats.avg_hold_time() >= Duration::from_millis(20));
+    assert!(stats.avg_hold_time() < stats.max_hold_time);
+}
+
+#[test]
+fn uncontended_locking_bypasses_the_manager() {
+    use crate::lock_manager::LockManager;
+    use std::sync::Arc;
+    let manager = Arc::new(LockManager::new());
+    let mutexes: Vec<_> = (0..4)
+        .map(|_| Arc::new(Mutex::with_manager(manager.clone(), 0u64)))
+        .collect();
+    // Holding the manager exclusively would stall any acquisition that needs it.
+    let exclusive = manager.write_lock();
+    let workers: Vec<_> = mutexes
+        .iter()
+        .cloned()
+        .map(|mutex| {
+            std::thread::spawn(move || {
+                for _ in 0..1_000 {
+                    *mutex.lock().unwrap() += 1;
+                    *mutex.try_lock().unwrap() += 1;
+                }
+            })
+        })
+        .collect();
+    for worker in workers {
+        worker.join().unwrap();
+    }
+    std::mem::drop(exclusive);
+    assert!(mutexes.iter().all(|mutex| *mutex.lock().unwrap() == 2_000));
+    assert!(mutexes.iter().all(|mutex| mutex.key().is_none()));
+}
+
+#[test]
+fn leaked_guard_stays_locked() {
+    let manager = std::sync::Arc::new(crate::lock_manager::LockManager::with_analysis_timeout(
+        Duration::from_millis(10),
+    ));
+    manager.enable_lock_order_recording();
+    let config: &'static Mutex<Vec<u32>> =
+        Box::leak(Box::new(Mutex::with_manager(manager.clone(), vec![1])));
+    let other = Mutex::with_manager(manager.clone(), ());
+    let leaked = MutexGuard::leak(config.lock().unwrap());
+    leaked.push(2);
+    assert_eq!(leaked, &[1, 2]);
+    // The leaked lock doesn't order locks taken afterwards.
+    std::mem::drop(other.lock());
+    assert!(manager.lock_order_graph().successors(&0).next().is_none());
+    // Other threads waiting for it are blocked, not deadlocked.
+    let waiter = std::thread::spawn(move || {
+        matches!(
+            config.try_lock_for_checked(Duration::from_millis(50)),
+            Err(LockError::TimedOut)
+        )
+    });
+    assert!(waiter.join().unwrap());
+}
+
+#[test]
+fn poisoned_locks_are_listed() {
+    use crate::lock_manager::LockManager;
+    use std::sync::Arc;
+    let manager = Arc::new(LockManager::new());
+    let locks: Vec<_> = (0..3)
+        .map(|_| Mutex::with_manager(manager.clone(), ()))
+        .collect();
+    for lock in [&locks[0], &locks[2]] {
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            let _guard = lock.lock();
+            panic!("poisoning on purpose");
+        }));
+        assert!(result.is_err());
+    }
+    std::mem::drop(locks[1].lock());
+    assert!(locks[0].is_poisoned() && !locks[1].is_poisoned() && locks[2].is_poisoned());
+    assert_eq!(
+        manager.poisoned_locks(),
+        vec![locks[0].key().unwrap(), locks[2].key().unwrap()]
+    );
+}
+
+#[test]
+fn conditional_locking() {
+    let mutex = Mutex::new(3);
+    assert!(mutex.lock_if(|value| *value > 5).unwrap().is_none());
+    // The lock was released when the predicate failed.
+    assert!(mutex.try_lock().is_ok());
+    let mut guard = mutex.lock_if(|value| *value == 3).unwrap().unwrap();
+    *guard += 1;
+    assert!(mutex.try_lock().is_err());
+    std::mem::drop(guard);
+    assert_eq!(*mutex.lock().unwrap(), 4);
+}
+
+#[test]
+fn account_transfers_lock_two() {
+    use crate::lock_manager::LockManager;
+    use std::sync::Arc;
+    let manager = Arc::new(LockManager::with_analysis_timeout(Duration::from_millis(
+        10,
+    )));
+    let alice = Arc::new(Mutex::with_manager(manager.clone(), 1000i64));
+    let bob = Arc::new(Mutex::with_manager(manager, 1000i64));
+    let transfers: Vec<_> = vec![(alice.clone(), bob.clone()), (bob.clone(), alice.clone())]
+        .into_iter()
+        .map(|(from, to)| {
+            std::thread::spawn(move || {
+                for _ in 0..200 {
+                    let (mut from, mut to) = lock_two(&*from, &*to);
+                    *from -= 1;
+                    std::thread::yield_now();
+                    *to += 1;
+                }
+            })
+        })
+        .collect();
+    for transfer in transfers {
+        transfer.join().unwrap();
+    }
+    assert_eq!(*alice.lock().unwrap() + *bob.lock().unwrap(), 2000);
+    let same = std::panic::catch_unwind(|| {
+        let mutex = Mutex::new(());
+        std::mem::drop(lock_two(&mutex, &mutex));
+    });
+    assert!(same.is_err());
+}
+
+#[test]
+fn held_by_current_thread_only() {
+    let mutex = std::sync::Arc::new(Mutex::new(()));
+    assert!(!mutex.is_held_by_current_thread());
+    let guard = mutex.lock().unwrap();
+    assert!(mutex.is_held_by_current_thread());
+    let other = std::thread::spawn({
+        let mutex = mutex.clone();
+        move || mutex.is_held_by_current_thread()
+    });
+    assert!(!other.join().unwrap());
+    std::mem::drop(guard);
+    assert!(!mutex.is_held_by_current_thread());
+}
+
+#[test]
+fn cancellable_lock() {
+    use std::sync::Arc;
+    let mutex = Arc::new(Mutex::new(()));
+    let cancel = Arc::new(AtomicBool::new(false));
+    let _guard = mutex.lock().unwrap();
+    let waiter = std::thread::spawn({
+        let (mutex, cancel) = (mutex.clone(), cancel.clone());
+        move || {
+            let cancelled = matches!(mutex.lock_cancellable(&cancel), Err(LockError::Cancelled));
+            (cancelled, Instant::now())
+        }
+    });
+    std::thread::sleep(Duration::from_millis(20));
+    let flipped = Instant::now();
+    cancel.store(true, Ordering::Relaxed);
+    let (cancelled, returned) = waiter.join().unwrap();
+    assert!(cancelled);
+    assert!(returned.duration_since(flipped) < Duration::from_millis(500));
+}
+
+#[test]
+fn nesting_depth_stats() {
+    let (outer, middle, inner) = (Mutex::new(()), Mutex::new(()), Mutex::new(()));
+    {
+        let _outer = outer.lock().unwrap();
+        let _middle = middle.lock().unwrap();
+        let _inner = inner.lock().unwrap();
+    }
+    std::mem::drop(inner.lock().unwrap());
+    assert_eq!(outer.stats().max_nesting_depth, 0);
+    assert_eq!(middle.stats().max_nesting_depth, 1);
+    assert_eq!(inner.stats().max_nesting_depth, 2);
+    assert_eq!(inner.stats().acquisitions, 2);
+}
+
+#[test]
+fn leaf_lock_violations() {
+    let leaf = Mutex::new_leaf(());
+    leaf.set_name("stats");
+    let other = Mutex::new(());
+    {
+        // Leaves may still be taken last.
+        let _other = other.lock().unwrap();
+        let _leaf = leaf.lock().unwrap();
+    }
+    let violation = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        let _leaf = leaf.lock().unwrap();
+        std::mem::drop(other.lock());
+    }));
+    let message = violation.unwrap_err();
+    let message = message.downcast_ref::<String>().unwrap();
+    assert!(message.starts_with("LOCK DISCIPLINE VIOLATION"));
+    assert!(message.contains("while holding stats"));
+    assert!(message.contains("Leaf lock taken at:"));
+    assert!(other.try_lock().is_ok());
+}
+
+#[test]
+fn overlapping_lock_sets() {
+    use std::sync::Arc;
+    let accounts: Arc<Vec<Mutex<u64>>> = Arc::new((0..3).map(|_| Mutex::new(0)).collect());
+    let threads: Vec<_> = vec![[0, 1, 2], [2, 1, 0], [1, 2, 0]]
+        .into_iter()
+        .map(|set| {
+            let accounts = accounts.clone();
+            std::thread::spawn(move || {
+                for _ in 0..200 {
+                    let mutexes: Vec<&Mutex<u64>> = set.iter().map(|i| &accounts[*i]).collect();
+                    let mut guards = lock_all_or_retry(&mutexes);
+                    // Guards come back in argument order.
+                    *guards[0] += 1;
+                    for guard in guards.iter_mut() {
+                        **guard += 1;
+                    }
+                }
+                set[0]
+            })
+        })
+        .collect();
+    let mut first = Vec::new();
+    for thread in threads {
+        first.push(thread.join().unwrap());
+    }
+    for (i, account) in accounts.iter().enumerate() {
+        let bonus = first.iter().filter(|first| **first == i).count() as u64;
+        assert_eq!(*account.lock().unwrap(), 600 + 200 * bonus);
+    }
+}
+
+#[test]
+fn traced_and_untraced_locks() {
+    use std::sync::{Arc, Barrier};
+    let traced = Arc::new(Mutex::new_traced(()));
+    let untraced = Arc::new(Mutex::new_untraced(()));
+    for lock in [&traced, &untraced].iter() {
+        lock.set_analysis_timeout(Some(Duration::from_millis(10)));
+    }
+    let barrier = Arc::new(Barrier::new(2));
+    let threads: Vec<_> = vec![
+        (traced.clone(), untraced.clone()),
+        (untraced.clone(), traced.clone()),
+    ]
+    .into_iter()
+    .map(|(first, second)| {
+        let barrier = barrier.clone();
+        std::thread::spawn(move || {
+            let _first = first.lock();
+            barrier.wait();
+            match second.lock_checked() {
+                Err(LockError::Deadlock(report)) => Some(report.text),
+                _ => None,
+            }
+        })
+    })
+    .collect();
+    let reports: Vec<String> = threads
+        .into_iter()
+        .filter_map(|thread| thread.join().unwrap())
+        .collect();
+    assert_eq!(reports.len(), 1);
+    let report = &reports[0];
+    let section = |lock: &Mutex<()>| {
+        let start = report
+            .find(&format!("LOCK {}:", lock.key().unwrap()))
+            .unwrap();
+        let end = report[start + 1..]
+            .find("LOCK ")
+            .map_or(report.len(), |end| start + 1 + end);
+        &report[start..end]
+    };
+    let (traced_part, untraced_part) = (section(&traced), section(&untraced));
+    assert!(!traced_part.contains("backtrace capture disabled"));
+    assert!(traced_part.contains(" at "));
+    assert_eq!(
+        untraced_part
+            .matches("<backtrace capture disabled for this lock>")
+            .count(),
+        2
+    );
+}
+
+#[test]
+fn poison_taken_under_the_lock() {
+    use std::sync::Arc;
+    let mutex = Arc::new(Mutex::new(0));
+    std::thread::spawn({
+        let mutex = mutex.clone();
+        move || {
+            let _guard = mutex.lock().unwrap();
+            panic!("poisoning the lock");
+        }
+    })
+    .join()
+    .unwrap_err();
+    let mut guard = match mutex.lock() {
+        Err(poisoned) => poisoned.into_inner(),
+        Ok(_) => panic!("the lock should be poisoned"),
+    };
+    assert!(guard.take_poison());
+    assert!(!guard.take_poison());
+    *guard = 1;
+    std::mem::drop(guard);
+    assert!(!mutex.is_poisoned());
+    assert_eq!(*mutex.lock().unwrap(), 1);
+}
+
+#[test]
+fn lowest_priority_holder_breaks_deadlocks() {
+    use crate::lock_manager::LockManager;
+    use crate::LockError;
+    use std::sync::{Arc, Barrier};
+    let manager = Arc::new(LockManager::with_analysis_timeout(Duration::from_millis(
+        50,
+    )));
+    let low = Arc::new(Mutex::with_manager(manager.clone(), ()));
+    let high = Arc::new(Mutex::with_manager(manager, ()));
+    low.set_priority(1);
+    high.set_priority(5);
+    // Without priorities, the thread waiting for `low` would be the first to report.
+    low.set_analysis_timeout(Some(Duration::from_secs(0)));
+    let deadlock = |result: CheckedResult<MutexGuard<'_, ()>>| match result {
+        Err(LockError::Deadlock(report)) => Some(report),
+        _ => None,
+    };
+    let barrier = Arc::new(Barrier::new(2));
+    let holding_low = std::thread::spawn({
+        let (low, high, barrier) = (low.clone(), high.clone(), barrier.clone());
+        move || {
+            let _low = low.lock();
+            barrier.wait();
+            deadlock(high.lock_checked())
+        }
+    });
+    let holding_high = std::thread::spawn(move || {
+        let _high = high.lock();
+        barrier.wait();
+        deadlock(low.lock_checked())
+    });
+    let report = holding_low.join().unwrap().unwrap();
+    assert!(report.text.contains("[priority 1]"));
+    assert!(report.text.contains("[priority 5]"));
+    assert!(holding_high.join().unwrap().is_none());
+}
+
+#[test]
+fn deadline_warnings() {
+    use crate::lock_manager::LockManager;
+    use std::sync::Arc;
+    let manager = Arc::new(LockManager::new());
+    let warnings = Arc::new(std::sync::Mutex::new(Vec::<String>::new()));
+    manager.set_warning_hook({
+        let warnings = warnings.clone();
+        move |warning: &str| warnings.lock().unwrap().push(warning.to_owned())
+    });
+    let mutex = Arc::new(Mutex::with_manager(manager, 0));
+    mutex.set_name("slow");
+    let wait_for_warnings = |count: usize| {
+        let start = Instant::now();
+        while warnings.lock().unwrap().len() < count {
+            assert!(start.elapsed() < Duration::from_secs(5));
+            std::thread::sleep(Duration::from_millis(1));
+        }
+    };
+    let guard = mutex.lock_with_deadline(Duration::from_millis(10)).unwrap();
+    wait_for_warnings(1);
+    assert!(warnings.lock().unwrap()[0].starts_with("slow has been held for longer than 10ms"));
+    assert!(mutex.try_lock().is_err());
+    assert!(!guard.forcibly_released());
+    std::mem::drop(guard);
+    assert!(mutex.try_lock().is_ok());
+
+    let guard = unsafe { mutex.lock_with_forced_release(Duration::from_millis(10)) }.unwrap();
+    wait_for_warnings(2);
+    assert!(guard.forcibly_released());
+    let other = std::thread::spawn({
+        let mutex = mutex.clone();
+        move || *mutex.lock().unwrap() += 1
+    });
+    other.join().unwrap();
+    std::mem::drop(guard);
+    // The forced release went through the manager, which forgot the lock for this thread.
+    crate::assert_no_locks_held();
+    assert_eq!(*mutex.lock().unwrap(), 1);
+}
+
+#[test]
+fn wait_latency_percentiles() {
+    use crate::lock_manager::LockManager;
+    use std::sync::Arc;
+    let manager = Arc::new(LockManager::new());
+    let mutex = Arc::new(Mutex::with_manager(manager.clone(), ()));
+    for _ in 0..90 {
+        std::mem::drop(mutex.lock());
+    }
+    for hold in 1..=10 {
+        let guard = mutex.lock().unwrap();
+        let contentions = mutex.stats().contentions;
+        let waiter = std::thread::spawn({
+            let mutex = mutex.clone();
+            move || std::mem::drop(mutex.lock())
+        });
+        let start = Instant::now();
+        while mutex.stats().contentions == contentions {
+            assert!(start.elapsed() < Duration::from_secs(5));
+            std::thread::sleep(Duration::from_micros(100));
+        }
+        std::thread::sleep(Duration::from_millis(hold));
+        std::mem::drop(guard);
+        waiter.join().unwrap();
+    }
+    let latency = manager
+        .lock_latency_percentiles(mutex.key().unwrap())
+        .unwrap();
+    assert_eq!(latency.count, 110);
+    assert!(latency.p50 <= latency.p90);
+    assert!(latency.p90 <= latency.p99);
+    assert!(latency.p99 <= latency.max);
+    assert!(latency.p50 < Duration::from_millis(1));
+    assert!(latency.p99 >= Duration::from_millis(5));
+    assert!(latency.max >= Duration::from_millis(10));
+}
+
+#[test]
+fn unlock_then_relock() {
+    let mutex = Mutex::new(0);
+    let mut guard = mutex.lock().unwrap();
+    *guard += 1;
+    let mutex = MutexGuard::unlock(guard);
+    let mut guard = mutex.try_lock().unwrap();
+    *guard += 1;
+    assert_eq!(*guard, 2);
+    std::mem::drop(guard);
+    assert_eq!(mutex.stats().holds, 2);
+}
+
+#[test]
+fn transactions_roll_back_on_panic() {
+    let accounts = [Mutex::new(100), Mutex::new(0)];
+    let balances = |accounts: &[Mutex<i32>]| -> Vec<i32> {
+        accounts
+            .iter()
+            .map(|account| *account.lock().unwrap_or_else(PoisonError::into_inner))
+            .collect()
+    };
+    let transfer = |amount: i32, fail: bool| {
+        let before = balances(&accounts);
+        let mut transaction = LockTransaction::new(&[&accounts[0], &accounts[1]], move |guards| {
+            for (guard, balance) in guards.iter_mut().zip(before) {
+                **guard = balance;
+            }
+        });
+        *transaction.guards()[0] -= amount;
+        if fail {
+            panic!("transfer interrupted");
+        }
+        *transaction.guards()[1] += amount;
+        transaction.commit();
+    };
+    transfer(30, false);
+    assert_eq!(balances(&accounts), [70, 30]);
+    let interrupted = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| transfer(50, true)));
+    assert!(interrupted.is_err());
+    assert_eq!(balances(&accounts), [70, 30]);
+    assert!(accounts[0].is_poisoned());
+}
+
+#[test]
+fn std_like_guards_in_generic_code() {
+    fn push<G: std::ops::DerefMut<Target = Vec<u32>>>(mut guard: G, value: u32) -> usize {
+        guard.push(value);
+        guard.len()
+    }
+    let mutex = Mutex::new(Vec::new());
+    let std_mutex = std::sync::Mutex::new(Vec::new());
+    assert_eq!(push(mutex.lock_std_like(), 1), 1);
+    assert_eq!(push(std_mutex.lock().unwrap(), 1), 1);
+    assert_eq!(push(mutex.lock_std_like(), 2), 2);
+    assert_eq!(*mutex.lock().unwrap(), [1, 2]);
+}
+
+#[test]
+fn weak_try_lock() {
+    use std::sync::Arc;
+    let mutex = Arc::new(Mutex::new(0));
+    *mutex.try_lock_weak().unwrap() += 1;
+    let guard = mutex.lock().unwrap();
+    let contended = std::thread::spawn({
+        let mutex = mutex.clone();
+        move || mutex.try_lock_weak().is_none()
+    });
+    assert!(contended.join().unwrap());
+    std::mem::drop(guard);
+    // Busy bookkeeping makes it fail spuriously, even though the mutex is free.
+    let representation = mutex.representation.lock();
+    assert!(mutex.try_lock_weak().is_none());
+    std::mem::drop(representation);
+    assert_eq!(*mutex.try_lock_weak().unwrap(), 1);
+}
+
+#[test]
+fn panicking_holders_hand_over_poisoned_locks() {
+    use std::sync::{mpsc, Arc};
+    let mutex = Arc::new(Mutex::new(0));
+    let (locked, on_locked) = mpsc::channel();
+    let (panic, on_panic) = mpsc::channel::<()>();
+    let holder = std::thread::spawn({
+        let mutex = mutex.clone();
+        move || {
+            let _guard = mutex.lock().unwrap();
+            locked.send(()).unwrap();
+            on_panic.recv().unwrap();
+            panic!("poisoning");
         }
+    });
+    on_locked.recv().unwrap();
+    let waiter = std::thread::spawn({
+        let mutex = mutex.clone();
+        move || mutex.lock().is_err()
+    });
+    // Locks register when a thread first waits for them.
+    let start = Instant::now();
+    while mutex.key().is_none() {
+        assert!(start.elapsed() < Duration::from_secs(5));
+        std::thread::sleep(Duration::from_millis(1));
     }
+    panic.send(()).unwrap();
+    holder.join().unwrap_err();
+    assert!(
+        waiter.join().unwrap(),
+        "the waiter took the lock before it was poisoned"
+    );
 }
-unsafe impl<T: Send> Send for Mutex<T> {}
-unsafe impl<T: Send> Sync for Mutex<T> {}
@@ -0,0 +1,94 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use crate::Mutex;
+
+/// An instrumented version of `std::sync::Once`.
+///
+/// The initialization runs while holding an instrumented mutex: threads waiting for it take part in
+/// deadlock detection, and calling the same `Once` from its own initialization is reported as a reentrance.
+#[derive(Default)]
+pub struct Once {
+    done: AtomicBool,
+    running: Mutex<()>,
+}
+
+/// Passed to the closure of `Once::call_once_force`.
+#[derive(Debug)]
+pub struct OnceState {
+    poisoned: bool,
+}
+
+impl OnceState {
+    /// Whether a previous initialization panicked.
+    pub fn is_poisoned(&self) -> bool {
+        self.poisoned
+    }
+}
+
+impl Once {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn is_completed(&self) -> bool {
+        self.done.load(Ordering::Acquire)
+    }
+
+    /// Runs `f` if no initialization completed yet, panicking if a previous one panicked.
+    pub fn call_once<F: FnOnce()>(&self, f: F) {
+        self.call_once_force(|state| {
+            if state.is_poisoned() {
+                panic!("Once instance has previously been poisoned");
+            }
+            f()
+        })
+    }
+
+    /// Like `call_once`, but also runs `f` if a previous initialization panicked, which `f` can tell from its `OnceState`.
+    pub fn call_once_force<F: FnOnce(&OnceState)>(&self, f: F) {
+        if self.is_completed() {
+            return;
+        }
+        let (_guard, poisoned) = match self.running.lock() {
+            Ok(guard) => (guard, false),
+            Err(poisoned) => (poisoned.into_inner(), true),
+        };
+        if self.is_completed() {
+            return;
+        }
+        // If `f` panics, the guard poisons `running`, which the next call reports.
+        f(&OnceState { poisoned });
+        self.done.store(true, Ordering::Release);
+    }
+}
+
+impl std::fmt::Debug for Once {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Once")
+            .field("completed", &self.is_completed())
+            .finish()
+    }
+}
+
+#[test]
+fn forced_initialization_recovers_from_poison() {
+    use std::panic::{catch_unwind, AssertUnwindSafe};
+    let once = Once::new();
+    assert!(catch_unwind(AssertUnwindSafe(
+        || once.call_once(|| panic!("first init fails"))
+    ))
+    .is_err());
+    assert!(!once.is_completed());
+    assert!(catch_unwind(AssertUnwindSafe(|| once.call_once(|| {}))).is_err());
+    let mut recovered = None;
+    once.call_once_force(|state| recovered = Some(state.is_poisoned()));
+    assert_eq!(recovered, Some(true));
+    assert!(once.is_completed());
+    once.call_once_force(|_| unreachable!());
+    once.call_once(|| unreachable!());
+    let recursive = Once::new();
+    assert!(catch_unwind(AssertUnwindSafe(|| {
+        recursive.call_once(|| recursive.call_once(|| {}))
+    }))
+    .is_err());
+}
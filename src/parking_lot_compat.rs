@@ -0,0 +1,145 @@
+//! Locks with `parking_lot`'s signatures, to migrate code written against it with an import swap.
+//! Like `parking_lot`'s, these locks never poison: a guard is returned even if a thread panicked while holding it.
+
+use std::sync::{PoisonError, TryLockError, TryLockResult};
+use std::time::Duration;
+
+use crate::{LockError, MutexGuard, RwLockReadGuard, RwLockWriteGuard};
+
+fn ok_or_poisoned<Guard>(result: TryLockResult<Guard>) -> Option<Guard> {
+    match result {
+        Ok(guard) => Some(guard),
+        Err(TryLockError::Poisoned(poison)) => Some(poison.into_inner()),
+        Err(TryLockError::WouldBlock) => None,
+    }
+}
+
+/// A `crate::Mutex` whose acquisitions return guards directly, as `parking_lot::Mutex`'s do.
+#[derive(Default)]
+pub struct Mutex<T>(crate::Mutex<T>);
+
+impl<T> Mutex<T> {
+    pub fn new(inner: T) -> Self {
+        Mutex(crate::Mutex::new(inner))
+    }
+
+    pub fn into_inner(self) -> T {
+        self.0.into_inner().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        self.0.get_mut().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    #[track_caller]
+    pub fn lock(&self) -> MutexGuard<'_, T> {
+        self.0.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    pub fn try_lock(&self) -> Option<MutexGuard<'_, T>> {
+        ok_or_poisoned(self.0.try_lock())
+    }
+
+    /// Deadlocks found while waiting still panic, as `lock` would.
+    pub fn try_lock_for(&self, timeout: Duration) -> Option<MutexGuard<'_, T>> {
+        match self.0.try_lock_for_checked(timeout) {
+            Ok(guard) => Some(guard),
+            Err(LockError::Poisoned(poison)) => Some(poison.into_inner()),
+            Err(LockError::Deadlock(report)) => panic!("{}", report.text),
+            Err(_) => None,
+        }
+    }
+
+    /// The wrapped lock, for this crate's diagnostics.
+    pub fn inner(&self) -> &crate::Mutex<T> {
+        &self.0
+    }
+}
+
+impl<T> From<crate::Mutex<T>> for Mutex<T> {
+    fn from(mutex: crate::Mutex<T>) -> Self {
+        Mutex(mutex)
+    }
+}
+
+/// A `crate::RwLock` whose acquisitions return guards directly, as `parking_lot::RwLock`'s do.
+#[derive(Default)]
+pub struct RwLock<T>(crate::RwLock<T>);
+
+impl<T> RwLock<T> {
+    pub fn new(inner: T) -> Self {
+        RwLock(crate::RwLock::new(inner))
+    }
+
+    pub fn into_inner(self) -> T {
+        self.0.into_inner().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        self.0.get_mut()
+    }
+
+    #[track_caller]
+    pub fn read(&self) -> RwLockReadGuard<'_, T> {
+        self.0.read().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    #[track_caller]
+    pub fn write(&self) -> RwLockWriteGuard<'_, T> {
+        self.0.write().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    pub fn try_read(&self) -> Option<RwLockReadGuard<'_, T>> {
+        ok_or_poisoned(self.0.try_read())
+    }
+
+    pub fn try_write(&self) -> Option<RwLockWriteGuard<'_, T>> {
+        ok_or_poisoned(self.0.try_write())
+    }
+
+    /// The wrapped lock, for this crate's diagnostics.
+    pub fn inner(&self) -> &crate::RwLock<T> {
+        &self.0
+    }
+}
+
+impl<T> From<crate::RwLock<T>> for RwLock<T> {
+    fn from(rwlock: crate::RwLock<T>) -> Self {
+        RwLock(rwlock)
+    }
+}
+
+#[test]
+fn bare_guards() {
+    use std::sync::Arc;
+    let mutex = Arc::new(Mutex::new(0));
+    *mutex.lock() += 1;
+    std::thread::spawn({
+        let mutex = mutex.clone();
+        move || {
+            let _guard = mutex.lock();
+            panic!("poisoning");
+        }
+    })
+    .join()
+    .unwrap_err();
+    assert!(mutex.inner().is_poisoned());
+    let mut guard = mutex.lock();
+    *guard += 1;
+    assert!(mutex.try_lock().is_none());
+    std::thread::spawn({
+        let mutex = mutex.clone();
+        move || assert!(mutex.try_lock_for(Duration::from_millis(10)).is_none())
+    })
+    .join()
+    .unwrap();
+    std::mem::drop(guard);
+    assert_eq!(*mutex.try_lock().unwrap(), 2);
+    let rwlock = RwLock::new(1);
+    let read = rwlock.read();
+    assert_eq!(*read + *rwlock.try_read().unwrap(), 2);
+    assert!(rwlock.try_write().is_none());
+    std::mem::drop(read);
+    *rwlock.write() += 1;
+    assert_eq!(rwlock.into_inner(), 2);
+}
@@ -0,0 +1,126 @@
+use std::sync::{Arc, OnceLock};
+
+use lock_api::{GuardNoSend, RawMutex, RawRwLock};
+
+use crate::lock_manager::{LazyKey, LockManager, RepresentationCell, RequestType, WaitOptions};
+
+/// A raw lock for `lock_api`'s `Mutex` and `RwLock`, instrumented like this crate's own locks.
+///
+/// `lock_api` requires locks to be built in constant contexts, so the lock's state is only allocated,
+/// and attached to the global manager, on first use.
+pub struct RawNoDeadlocks {
+    key: LazyKey,
+    state: OnceLock<(Arc<LockManager>, Arc<RepresentationCell>)>,
+}
+
+impl RawNoDeadlocks {
+    fn state(&self) -> &(Arc<LockManager>, Arc<RepresentationCell>) {
+        self.state
+            .get_or_init(|| (LockManager::get_global_manager(), Default::default()))
+    }
+
+    fn acquire(&self, request: RequestType) {
+        let (manager, representation) = self.state();
+        manager
+            .acquire(&self.key, representation, request, &WaitOptions::default())
+            .ok();
+    }
+
+    fn try_acquire(&self, request: RequestType) -> bool {
+        let (manager, representation) = self.state();
+        let mut state = representation.lock();
+        if !state.try_lock(request) {
+            return false;
+        }
+        manager.on_acquire(&self.key, request);
+        std::mem::drop(state);
+        manager.after_acquire(&self.key, representation);
+        true
+    }
+
+    fn release(&self) {
+        let (manager, representation) = self.state();
+        let mut state = representation.lock();
+        state.unlock();
+        manager.on_release(&self.key, representation);
+    }
+}
+
+unsafe impl RawMutex for RawNoDeadlocks {
+    const INIT: Self = RawNoDeadlocks {
+        key: LazyKey::new(),
+        state: OnceLock::new(),
+    };
+    // Locks must be released by the thread that acquired them.
+    type GuardMarker = GuardNoSend;
+
+    fn lock(&self) {
+        self.acquire(RequestType::Write)
+    }
+
+    fn try_lock(&self) -> bool {
+        self.try_acquire(RequestType::Write)
+    }
+
+    unsafe fn unlock(&self) {
+        self.release()
+    }
+}
+
+unsafe impl RawRwLock for RawNoDeadlocks {
+    const INIT: Self = <Self as RawMutex>::INIT;
+    type GuardMarker = GuardNoSend;
+
+    fn lock_shared(&self) {
+        self.acquire(RequestType::Read)
+    }
+
+    fn try_lock_shared(&self) -> bool {
+        self.try_acquire(RequestType::Read)
+    }
+
+    unsafe fn unlock_shared(&self) {
+        self.release()
+    }
+
+    fn lock_exclusive(&self) {
+        self.acquire(RequestType::Write)
+    }
+
+    fn try_lock_exclusive(&self) -> bool {
+        self.try_acquire(RequestType::Write)
+    }
+
+    unsafe fn unlock_exclusive(&self) {
+        self.release()
+    }
+}
+
+impl Drop for RawNoDeadlocks {
+    fn drop(&mut self) {
+        if let (Some(key), Some((manager, _))) = (self.key.get(), self.state.get()) {
+            manager.remove_lock(&key)
+        }
+    }
+}
+
+#[test]
+fn lock_api_reentrance() {
+    let rwlock = lock_api::RwLock::<RawNoDeadlocks, u32>::new(0);
+    *rwlock.write() += 1;
+    assert_eq!(*rwlock.read() + *rwlock.read(), 2);
+    let mutex = lock_api::Mutex::<RawNoDeadlocks, u32>::new(0);
+    let mut guard = mutex.lock();
+    *guard += 1;
+    assert!(mutex.try_lock().is_none());
+    let payload = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        let _reentrance = mutex.lock();
+    }))
+    .unwrap_err();
+    assert!(payload
+        .downcast_ref::<String>()
+        .unwrap()
+        .starts_with("DEADLOCK DETECTED!"));
+    std::mem::drop(guard);
+    assert_eq!(*mutex.lock(), 1);
+}
@@ -1,13 +1,23 @@
 use std::cell::UnsafeCell;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{LockResult, PoisonError, TryLockError, TryLockResult};
-use std::time::Instant;
+use std::thread::ThreadId;
+use std::time::{Duration, Instant};
+
+use crate::lock_manager::{
+    at_caller, AcquireError, LazyKey, LockStats, NotSend, RepresentationCell, RequestType,
+    WaitOptions,
+};
+use crate::{CheckedResult, LockError};
 
 /// An instrumented version of `std::sync::RwLock`
 pub struct RwLock<T: ?Sized> {
-    key: usize,
+    key: LazyKey,
+    representation: std::sync::Arc<RepresentationCell>,
     poisoned: AtomicBool,
     manager: std::sync::Arc<crate::lock_manager::LockManager>,
+    /// Whether dropping the lock checks that no guard was leaked.
+    strict: bool,
     inner: UnsafeCell<T>,
 }
 impl<T: Default> Default for RwLock<T> {
@@ -17,50 +27,150 @@ impl<T: Default> Default for RwLock<T> {
 }
 impl<T> RwLock<T> {
     pub fn new(inner: T) -> Self {
-        let manager = crate::lock_manager::LockManager::get_global_manager();
-        let key = manager.create_lock();
-        RwLock {
-            inner: UnsafeCell::new(inner),
-            poisoned: AtomicBool::new(false),
-            manager,
-            key,
-        }
+        Self::with_manager(
+            crate::lock_manager::LockManager::get_global_manager(),
+            inner,
+        )
+    }
+
+    /// Creates a lock whose contention is analysed immediately, regardless of the manager's timeout.
+    pub fn new_eager(inner: T) -> Self {
+        let lock = Self::new(inner);
+        lock.set_analysis_timeout(Some(Duration::from_secs(0)));
+        lock
     }
 
     pub fn with_manager(
         manager: std::sync::Arc<crate::lock_manager::LockManager>,
         inner: T,
     ) -> Self {
-        let key = manager.create_lock();
-        RwLock {
+        let lock = RwLock {
             inner: UnsafeCell::new(inner),
             poisoned: AtomicBool::new(false),
             manager,
-            key,
-        }
+            representation: Default::default(),
+            key: LazyKey::new(),
+            strict: false,
+        };
+        lock.manager.on_create(&lock.key, &lock.representation);
+        lock
     }
 
-    pub fn into_inner(self) -> T {
-        let key = self.key;
+    /// Creates a lock that panics when dropped if guards are still outstanding, such as `mem::forget`ten ones,
+    /// listing where they were acquired. Leaked read guards block writers forever without forming any cycle.
+    pub fn new_strict(inner: T) -> Self {
+        let mut lock = Self::new(inner);
+        lock.strict = true;
+        lock
+    }
+
+    /// Creates a lock that lets at most `max_readers` threads read it at once: further readers wait,
+    /// and the first time each of them does, a warning lists where the current readers acquired it,
+    /// as readers that are never released are a common reason for hitting the cap.
+    pub fn new_bounded(max_readers: usize, inner: T) -> Self {
+        let lock = Self::new(inner);
+        lock.representation.lock().max_readers = Some(max_readers);
+        lock
+    }
+
+    pub fn into_inner(self) -> LockResult<T> {
+        self.check_leaks();
+        let key = self.key.get();
+        let poisoned = self.poisoned.load(Ordering::Relaxed);
         // Safety: the fields are read, but their container (self) is forgotten: no double frees.
         // The lock is removed from the manager as in the destructor.
         unsafe {
             let manager = core::ptr::read(&self.manager);
+            let representation = core::ptr::read(&self.representation);
             let value = core::ptr::read(&self.inner).into_inner();
             core::mem::forget(self);
-            manager.remove_lock(&key);
-            value
+            std::mem::drop(representation);
+            if let Some(key) = key {
+                manager.remove_lock(&key);
+            }
+            if poisoned {
+                Err(PoisonError::new(value))
+            } else {
+                Ok(value)
+            }
         }
     }
 }
 
 impl<T: ?Sized> Drop for RwLock<T> {
     fn drop(&mut self) {
-        self.manager.remove_lock(&self.key)
+        if let Some(key) = self.key.get() {
+            self.manager.remove_lock(&key)
+        }
+        self.check_leaks();
     }
 }
 
 impl<T: ?Sized> RwLock<T> {
+    /// Overrides the manager's analysis timeout for this lock; `None` restores the default.
+    pub fn set_analysis_timeout(&self, timeout: Option<Duration>) {
+        self.representation.lock().analysis_timeout = timeout;
+    }
+
+    /// The key under which this lock appears in reports, once it has been contended.
+    pub fn key(&self) -> Option<usize> {
+        self.key.get()
+    }
+
+    pub fn name(&self) -> Option<String> {
+        self.representation.lock().name.clone()
+    }
+
+    pub fn set_name<S: Into<String>>(&self, name: S) {
+        self.representation.lock().name = Some(name.into());
+    }
+
+    /// See `Mutex::set_priority`.
+    pub fn set_priority(&self, priority: u32) {
+        self.representation.lock().priority = priority;
+    }
+
+    pub fn stats(&self) -> LockStats {
+        self.representation.lock().stats.clone()
+    }
+
+    /// Zeroes this lock's counters, keeping its key, name and settings.
+    pub fn reset_diagnostics(&self) {
+        self.representation.lock().stats = LockStats::default();
+    }
+
+    /// How many read guards are currently alive.
+    pub fn reader_guard_count(&self) -> usize {
+        let representation = self.representation.lock();
+        if representation.is_write_locked() {
+            0
+        } else {
+            representation.readers.len()
+        }
+    }
+
+    /// Panics if this lock is strict and some guards were never released.
+    fn check_leaks(&self) {
+        if !self.strict || std::thread::panicking() {
+            return;
+        }
+        let representation = self.representation.lock();
+        if representation.readers.is_empty() {
+            return;
+        }
+        let mut message = format!(
+            "RwLock dropped with {} outstanding guard(s), acquired at:",
+            representation.readers.len()
+        );
+        for (thread, trace, _, _, _) in representation.readers.iter() {
+            let mut trace = trace.clone();
+            trace.resolve();
+            message.push_str(&format!("\n{:?}:\n{:?}", thread, trace));
+        }
+        std::mem::drop(representation);
+        panic!("{}", message);
+    }
+
     pub fn get_mut(&mut self) -> &mut T {
         unsafe { &mut *self.inner.get() }
     }
@@ -69,11 +179,21 @@ impl<T: ?Sized> RwLock<T> {
         self.poisoned.load(Ordering::Relaxed)
     }
 
-    pub fn try_read(&self) -> TryLockResult<RwLockReadGuard<T>> {
-        let mut guard = self.manager.write_lock();
-        let representation = guard.locks.get_mut(&self.key).unwrap();
+    /// Like `std::sync::RwLock::clear_poison`: marks the data as recovered, also for `LockManager::poisoned_locks`.
+    pub fn clear_poison(&self) {
+        self.poisoned.store(false, Ordering::Relaxed);
+        self.representation.lock().poisoned = false;
+    }
+
+    /// Like `std::sync::RwLock::try_read`, this only fails if a writer holds the lock:
+    /// writers that are merely waiting for it never make `try_read` fail.
+    pub fn try_read(&self) -> TryLockResult<RwLockReadGuard<'_, T>> {
+        let mut representation = self.representation.lock();
         if representation.try_read_lock() {
-            let returned_guard = RwLockReadGuard { inner: self };
+            self.manager.on_acquire(&self.key, RequestType::Read);
+            std::mem::drop(representation);
+            self.manager.after_acquire(&self.key, &self.representation);
+            let returned_guard = RwLockReadGuard::new(self);
             if self.is_poisoned() {
                 Err(TryLockError::Poisoned(PoisonError::new(returned_guard)))
             } else {
@@ -84,11 +204,13 @@ impl<T: ?Sized> RwLock<T> {
         }
     }
 
-    pub fn try_write(&self) -> TryLockResult<RwLockWriteGuard<T>> {
-        let mut guard = self.manager.write_lock();
-        let representation = guard.locks.get_mut(&self.key).unwrap();
+    pub fn try_write(&self) -> TryLockResult<RwLockWriteGuard<'_, T>> {
+        let mut representation = self.representation.lock();
         if representation.try_write_lock() {
-            let returned_guard = RwLockWriteGuard { inner: self };
+            self.manager.on_acquire(&self.key, RequestType::Write);
+            std::mem::drop(representation);
+            self.manager.after_acquire(&self.key, &self.representation);
+            let returned_guard = RwLockWriteGuard::new(self);
             if self.is_poisoned() {
                 Err(TryLockError::Poisoned(PoisonError::new(returned_guard)))
             } else {
@@ -99,57 +221,164 @@ impl<T: ?Sized> RwLock<T> {
         }
     }
 
-    pub fn read(&self) -> LockResult<RwLockReadGuard<T>> {
-        let timeout = self.manager.analysis_timeout();
+    /// Polls `try_write` for up to `window`, to seize the first instant no reader holds the lock.
+    /// Unlike `write`, this never subscribes to the lock nor runs a deadlock analysis.
+    pub fn try_write_opportunistic(
+        &self,
+        window: Duration,
+    ) -> TryLockResult<RwLockWriteGuard<'_, T>> {
         let start = Instant::now();
-
         loop {
-            let mut guard = self.manager.write_lock();
-            let representation = guard.locks.get_mut(&self.key).unwrap();
-
-            if representation.try_read_lock() {
-                let returned_guard = RwLockReadGuard { inner: self };
-                if self.is_poisoned() {
-                    return Err(PoisonError::new(returned_guard));
-                } else {
-                    return Ok(returned_guard);
+            match self.try_write() {
+                Err(TryLockError::WouldBlock) if start.elapsed() < window => {
+                    std::thread::yield_now()
                 }
-            } else if Instant::now().duration_since(start) > timeout {
-                representation.subscribe_read();
-                guard.analyse();
+                result => return result,
             }
+        }
+    }
 
-            std::thread::yield_now();
+    #[track_caller]
+    pub fn read(&self) -> LockResult<RwLockReadGuard<'_, T>> {
+        at_caller(&self.manager, std::panic::Location::caller(), || {
+            self.acquire_with(RequestType::Read, &WaitOptions::default())
+                .ok()
+        });
+        let returned_guard = RwLockReadGuard::new(self);
+        if self.is_poisoned() {
+            Err(PoisonError::new(returned_guard))
+        } else {
+            Ok(returned_guard)
         }
     }
 
-    pub fn write(&self) -> LockResult<RwLockWriteGuard<T>> {
-        let timeout = self.manager.analysis_timeout();
-        let start = Instant::now();
+    #[track_caller]
+    pub fn write(&self) -> LockResult<RwLockWriteGuard<'_, T>> {
+        at_caller(&self.manager, std::panic::Location::caller(), || {
+            self.acquire_with(RequestType::Write, &WaitOptions::default())
+                .ok()
+        });
+        let returned_guard = RwLockWriteGuard::new(self);
+        if self.is_poisoned() {
+            Err(PoisonError::new(returned_guard))
+        } else {
+            Ok(returned_guard)
+        }
+    }
 
-        loop {
-            let mut guard = self.manager.write_lock();
-            let representation = guard.locks.get_mut(&self.key).unwrap();
-
-            if representation.try_write_lock() {
-                let returned_guard = RwLockWriteGuard { inner: self };
-                if self.is_poisoned() {
-                    return Err(PoisonError::new(returned_guard));
-                } else {
-                    return Ok(returned_guard);
-                }
-            } else if Instant::now().duration_since(start) > timeout {
-                representation.subscribe_write();
-                guard.analyse();
-            }
+    /// Takes the write lock to run `f`, then keeps holding the lock for reading: no writer can
+    /// interleave between the update and the reads that follow it.
+    pub fn write_then_downgrade<R, F: FnOnce(&mut T) -> R>(
+        &self,
+        f: F,
+    ) -> LockResult<(R, RwLockReadGuard<'_, T>)> {
+        let mut guard = self.write().unwrap_or_else(PoisonError::into_inner);
+        let result = f(&mut guard);
+        let returned_guard = RwLockWriteGuard::downgrade(guard);
+        if self.is_poisoned() {
+            Err(PoisonError::new((result, returned_guard)))
+        } else {
+            Ok((result, returned_guard))
+        }
+    }
+
+    fn acquire_with(
+        &self,
+        request: RequestType,
+        options: &WaitOptions<'_>,
+    ) -> Result<(), AcquireError> {
+        self.manager
+            .acquire(&self.key, &self.representation, request, options)
+    }
+
+    /// Like `read`, but returns detected deadlocks instead of panicking.
+    pub fn read_checked(&self) -> CheckedResult<RwLockReadGuard<'_, T>> {
+        self.acquire_with(
+            RequestType::Read,
+            &WaitOptions {
+                return_deadlocks: true,
+                ..WaitOptions::default()
+            },
+        )?;
+        LockError::check_poison(RwLockReadGuard::new(self), self.is_poisoned())
+    }
 
-            std::thread::yield_now();
+    /// Like `write`, but returns detected deadlocks instead of panicking.
+    pub fn write_checked(&self) -> CheckedResult<RwLockWriteGuard<'_, T>> {
+        self.acquire_with(
+            RequestType::Write,
+            &WaitOptions {
+                return_deadlocks: true,
+                ..WaitOptions::default()
+            },
+        )?;
+        LockError::check_poison(RwLockWriteGuard::new(self), self.is_poisoned())
+    }
+
+    pub fn try_read_checked(&self) -> CheckedResult<RwLockReadGuard<'_, T>> {
+        match self.try_read() {
+            Err(TryLockError::WouldBlock) => Err(self
+                .manager
+                .reentrance(&self.representation, RequestType::Read)
+                .map_or(LockError::WouldBlock, LockError::WouldDeadlockReentrant)),
+            result => Ok(result?),
+        }
+    }
+
+    pub fn try_write_checked(&self) -> CheckedResult<RwLockWriteGuard<'_, T>> {
+        match self.try_write() {
+            Err(TryLockError::WouldBlock) => Err(self
+                .manager
+                .reentrance(&self.representation, RequestType::Write)
+                .map_or(LockError::WouldBlock, LockError::WouldDeadlockReentrant)),
+            result => Ok(result?),
         }
     }
+
+    /// Like `read_checked`, but gives up with `LockError::TimedOut` after `timeout`.
+    pub fn try_read_for_checked(&self, timeout: Duration) -> CheckedResult<RwLockReadGuard<'_, T>> {
+        self.acquire_with(
+            RequestType::Read,
+            &WaitOptions {
+                deadline: Some(Instant::now() + timeout),
+                return_deadlocks: true,
+                ..WaitOptions::default()
+            },
+        )?;
+        LockError::check_poison(RwLockReadGuard::new(self), self.is_poisoned())
+    }
+
+    /// Like `write_checked`, but gives up with `LockError::TimedOut` after `timeout`.
+    pub fn try_write_for_checked(
+        &self,
+        timeout: Duration,
+    ) -> CheckedResult<RwLockWriteGuard<'_, T>> {
+        self.acquire_with(
+            RequestType::Write,
+            &WaitOptions {
+                deadline: Some(Instant::now() + timeout),
+                return_deadlocks: true,
+                ..WaitOptions::default()
+            },
+        )?;
+        LockError::check_poison(RwLockWriteGuard::new(self), self.is_poisoned())
+    }
 }
 
 pub struct RwLockReadGuard<'l, T: ?Sized> {
     inner: &'l RwLock<T>,
+    /// The acquiring thread, see `LockManager::warn_on_cross_thread_release`.
+    holder: ThreadId,
+    _not_send: NotSend,
+}
+impl<'l, T: ?Sized> RwLockReadGuard<'l, T> {
+    fn new(inner: &'l RwLock<T>) -> Self {
+        RwLockReadGuard {
+            inner,
+            holder: std::thread::current().id(),
+            _not_send: NotSend::default(),
+        }
+    }
 }
 impl<'l, T: ?Sized> std::ops::Deref for RwLockReadGuard<'l, T> {
     type Target = T;
@@ -158,16 +387,35 @@ impl<'l, T: ?Sized> std::ops::Deref for RwLockReadGuard<'l, T> {
     }
 }
 impl<'l, T: ?Sized> Drop for RwLockReadGuard<'l, T> {
+    /// Like `std`'s, only writers poison the lock: readers can't leave the data half-modified.
     fn drop(&mut self) {
-        let mut guard = self.inner.manager.write_lock();
-        guard.locks.get_mut(&self.inner.key).unwrap().unlock();
-        if std::thread::panicking() {
-            self.inner.poisoned.store(true, Ordering::Relaxed);
-        }
+        self.inner
+            .manager
+            .release_for(&self.inner.key, &self.inner.representation, self.holder);
     }
 }
 pub struct RwLockWriteGuard<'l, T: ?Sized> {
     inner: &'l RwLock<T>,
+    /// The acquiring thread, see `LockManager::warn_on_cross_thread_release`.
+    holder: ThreadId,
+    _not_send: NotSend,
+}
+impl<'l, T: ?Sized> RwLockWriteGuard<'l, T> {
+    fn new(inner: &'l RwLock<T>) -> Self {
+        RwLockWriteGuard {
+            inner,
+            holder: std::thread::current().id(),
+            _not_send: NotSend::default(),
+        }
+    }
+
+    /// Atomically turns the write lock into a read lock, letting other readers, but no writer, in.
+    pub fn downgrade(guard: Self) -> RwLockReadGuard<'l, T> {
+        let inner = guard.inner;
+        std::mem::forget(guard);
+        inner.representation.lock().downgrade();
+        RwLockReadGuard::new(inner)
+    }
 }
 
 impl<'l, T: ?Sized> std::ops::Deref for RwLockWriteGuard<'l, T> {
@@ -183,13 +431,318 @@ impl<'l, T: ?Sized> std::ops::DerefMut for RwLockWriteGuard<'l, T> {
 }
 impl<'l, T: ?Sized> Drop for RwLockWriteGuard<'l, T> {
     fn drop(&mut self) {
-        let mut guard = self.inner.manager.write_lock();
-        guard.locks.get_mut(&self.inner.key).unwrap().unlock();
+        // Poisoned before the release, so that the next holder can't take the lock without seeing it.
         if std::thread::panicking() {
             self.inner.poisoned.store(true, Ordering::Relaxed);
+            self.inner
+                .manager
+                .on_poison(&self.inner.key, &self.inner.representation);
         }
+        self.inner
+            .manager
+            .release_for(&self.inner.key, &self.inner.representation, self.holder);
     }
 }
 
 unsafe impl<T: ?Sized + Send> Send for RwLock<T> {}
-unsafe impl<T: ?Sized + Send> Sync for RwLock<T> {}
+// Concurrent readers share `&T` across threads, which requires `T: Sync`.
+unsafe impl<T: ?Sized + Send + Sync> Sync for RwLock<T> {}
+unsafe impl<'l, T: ?Sized + Sync> Sync for RwLockReadGuard<'l, T> {}
+unsafe impl<'l, T: ?Sized + Sync> Sync for RwLockWriteGuard<'l, T> {}
+
+#[test]
+fn opportunistic_write_under_reader_churn() {
+    use std::sync::atomic::AtomicBool;
+    use std::sync::{Arc, Barrier};
+    let lock = Arc::new(RwLock::new(0));
+    let stop = Arc::new(AtomicBool::new(false));
+    let barrier = Arc::new(Barrier::new(2));
+    let reader = std::thread::spawn({
+        let lock = lock.clone();
+        let stop = stop.clone();
+        let barrier = barrier.clone();
+        move || {
+            let mut guard = Some(lock.read().unwrap());
+            barrier.wait();
+            while !stop.load(Ordering::Relaxed) {
+                std::thread::sleep(Duration::from_millis(5));
+                guard.take();
+                std::thread::sleep(Duration::from_millis(1));
+                guard = Some(lock.read().unwrap());
+            }
+        }
+    });
+    barrier.wait();
+    assert!(matches!(lock.try_write(), Err(TryLockError::WouldBlock)));
+    let mut guard = lock
+        .try_write_opportunistic(Duration::from_secs(1))
+        .unwrap();
+    *guard = 1;
+    std::mem::drop(guard);
+    stop.store(true, Ordering::Relaxed);
+    reader.join().unwrap();
+    assert_eq!(*lock.read().unwrap(), 1);
+}
+
+#[test]
+fn readers_bypass_the_manager() {
+    use crate::lock_manager::LockManager;
+    use std::sync::Arc;
+    let manager = Arc::new(LockManager::new());
+    let locks: Vec<_> = (0..8)
+        .map(|i| Arc::new(RwLock::with_manager(manager.clone(), i)))
+        .collect();
+    // Readers on distinct locks only touch their own lock's state: they must all
+    // complete while the manager is held exclusively.
+    let exclusive = manager.write_lock();
+    let readers: Vec<_> = locks
+        .iter()
+        .cloned()
+        .map(|lock| {
+            std::thread::spawn(move || {
+                let expected = *lock.read().unwrap();
+                for _ in 0..1_000 {
+                    let _shared = lock.read().unwrap();
+                    assert_eq!(*lock.read().unwrap(), expected);
+                }
+            })
+        })
+        .collect();
+    for reader in readers {
+        reader.join().unwrap();
+    }
+    std::mem::drop(exclusive);
+}
+
+#[test]
+fn try_read_ignores_pending_writers() {
+    use crate::lock_manager::LockManager;
+    use std::sync::Arc;
+    let manager = Arc::new(LockManager::with_analysis_timeout(Duration::from_millis(
+        10,
+    )));
+    let lock = Arc::new(RwLock::with_manager(manager.clone(), 0));
+    let reader = lock.read().unwrap();
+    let writer = std::thread::spawn({
+        let lock = lock.clone();
+        move || *lock.write().unwrap() += 1
+    });
+    let start = Instant::now();
+    while manager.blocked_threads().is_empty() {
+        assert!(start.elapsed() < Duration::from_secs(5));
+        std::thread::sleep(Duration::from_millis(1));
+    }
+    assert_eq!(*lock.try_read().unwrap(), 0);
+    assert_eq!(*lock.try_read_checked().unwrap(), 0);
+    std::mem::drop(reader);
+    writer.join().unwrap();
+    assert_eq!(*lock.try_read().unwrap(), 1);
+}
+
+#[test]
+fn strict_lock_reports_leaked_guards() {
+    let lock = RwLock::new_strict(0);
+    let first = lock.read().unwrap();
+    std::mem::forget(lock.read().unwrap());
+    assert_eq!(lock.reader_guard_count(), 2);
+    std::mem::drop(first);
+    assert_eq!(lock.reader_guard_count(), 1);
+    let payload = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| std::mem::drop(lock)))
+        .unwrap_err();
+    let message = payload.downcast_ref::<String>().unwrap();
+    assert!(message.starts_with("RwLock dropped with 1 outstanding guard(s)"));
+    assert!(message.contains("strict_lock_reports_leaked_guards"));
+}
+
+#[test]
+fn poisoned_try_locks_match_std() {
+    use std::sync::Arc;
+    let lock = Arc::new(RwLock::new(0));
+    std::thread::spawn({
+        let lock = lock.clone();
+        move || {
+            let _guard = lock.write().unwrap();
+            panic!("poisoning the lock");
+        }
+    })
+    .join()
+    .unwrap_err();
+    assert!(lock.is_poisoned());
+    // Poisoned guards still hold the lock, until they're dropped.
+    match lock.try_read() {
+        Err(TryLockError::Poisoned(poisoned)) => {
+            let guard = poisoned.into_inner();
+            assert!(matches!(lock.try_write(), Err(TryLockError::WouldBlock)));
+            assert_eq!(*guard, 0);
+        }
+        _ => panic!("try_read should report the poisoning"),
+    }
+    match lock.try_write() {
+        Err(TryLockError::Poisoned(poisoned)) => {
+            let mut guard = poisoned.into_inner();
+            assert!(matches!(lock.try_read(), Err(TryLockError::WouldBlock)));
+            *guard = 1;
+        }
+        _ => panic!("try_write should report the poisoning"),
+    }
+    lock.clear_poison();
+    assert!(!lock.is_poisoned());
+    assert_eq!(*lock.try_read().unwrap(), 1);
+    *lock.try_write().unwrap() += 1;
+    assert_eq!(*lock.read().unwrap(), 2);
+}
+
+#[test]
+fn panicking_readers_dont_poison() {
+    use std::sync::Arc;
+    let lock = Arc::new(RwLock::new(0));
+    std::thread::spawn({
+        let lock = lock.clone();
+        move || {
+            let _guard = lock.read().unwrap();
+            panic!("reading doesn't poison");
+        }
+    })
+    .join()
+    .unwrap_err();
+    assert!(!lock.is_poisoned());
+    assert!(lock.try_read().is_ok());
+    assert!(lock.try_write().is_ok());
+}
+
+#[test]
+fn no_writer_between_write_and_downgrade() {
+    use std::sync::Arc;
+    let lock = Arc::new(RwLock::new(0));
+    let mut writer = None;
+    let (_, guard) = lock
+        .write_then_downgrade(|value| {
+            *value = 1;
+            // Already waiting when the lock is downgraded.
+            writer = Some(std::thread::spawn({
+                let lock = lock.clone();
+                move || *lock.write().unwrap() = 2
+            }));
+            std::thread::sleep(Duration::from_millis(20));
+        })
+        .unwrap();
+    let reader = std::thread::spawn({
+        let lock = lock.clone();
+        move || *lock.read().unwrap()
+    });
+    assert_eq!(reader.join().unwrap(), 1);
+    assert_eq!(*guard, 1);
+    assert!(matches!(lock.try_write(), Err(TryLockError::WouldBlock)));
+    std::mem::drop(guard);
+    writer.unwrap().join().unwrap();
+    assert_eq!(*lock.read().unwrap(), 2);
+}
+
+#[test]
+fn bounded_readers() {
+    let lock = RwLock::new_bounded(2, 0);
+    let first = lock.read().unwrap();
+    let second = lock.read().unwrap();
+    assert!(matches!(lock.try_read(), Err(TryLockError::WouldBlock)));
+    std::mem::drop(first);
+    let third = lock.try_read().unwrap();
+    assert_eq!(*second + *third, 0);
+    assert!(matches!(lock.try_read(), Err(TryLockError::WouldBlock)));
+}
+
+#[test]
+fn reader_cap_warnings() {
+    use crate::lock_manager::LockManager;
+    use std::sync::Arc;
+    let manager = Arc::new(LockManager::new());
+    let warnings = Arc::new(std::sync::Mutex::new(Vec::new()));
+    manager.set_warning_hook({
+        let warnings = warnings.clone();
+        move |message| warnings.lock().unwrap().push(message.to_owned())
+    });
+    let lock = Arc::new(RwLock::with_manager(manager, 0));
+    lock.representation.lock().max_readers = Some(1);
+    lock.set_name("pool");
+    let guard = lock.read().unwrap();
+    let reader = std::thread::spawn({
+        let lock = lock.clone();
+        move || *lock.read().unwrap()
+    });
+    let start = Instant::now();
+    while warnings.lock().unwrap().is_empty() {
+        assert!(start.elapsed() < Duration::from_secs(5));
+        std::thread::sleep(Duration::from_millis(1));
+    }
+    std::mem::drop(guard);
+    assert_eq!(reader.join().unwrap(), 0);
+    let warnings = warnings.lock().unwrap();
+    assert!(warnings[0].starts_with("pool has reached its maximum of 1 readers"));
+    assert!(warnings[0].contains("acquired it at:"));
+}
+
+#[test]
+fn poisoned_into_inner() {
+    use std::sync::Arc;
+    let lock = Arc::new(RwLock::new(0));
+    let guard = lock.write().unwrap();
+    let writer = std::thread::spawn({
+        let lock = lock.clone();
+        move || {
+            let mut guard = lock.write().unwrap();
+            *guard = 42;
+            panic!("poisoning the lock");
+        }
+    });
+    // Contention registers the lock with the global manager.
+    let start = Instant::now();
+    while lock.key().is_none() {
+        assert!(start.elapsed() < Duration::from_secs(5));
+        std::thread::sleep(Duration::from_millis(1));
+    }
+    let key = lock.key().unwrap();
+    std::mem::drop(guard);
+    writer.join().unwrap_err();
+    let manager = crate::lock_manager::LockManager::get_global_manager();
+    assert!(manager.lock_stats(key).is_some());
+    let lock = Arc::try_unwrap(lock).ok().unwrap();
+    match lock.into_inner() {
+        Err(poisoned) => assert_eq!(poisoned.into_inner(), 42),
+        Ok(_) => panic!("into_inner should report the poisoning"),
+    }
+    assert!(manager.lock_stats(key).is_none());
+    assert_eq!(RwLock::new(1).into_inner().unwrap(), 1);
+}
+
+#[test]
+fn panicking_holders_hand_over_poisoned_locks() {
+    use std::sync::{mpsc, Arc};
+    let rwlock = Arc::new(RwLock::new(0));
+    let (locked, on_locked) = mpsc::channel();
+    let (panic, on_panic) = mpsc::channel::<()>();
+    let holder = std::thread::spawn({
+        let rwlock = rwlock.clone();
+        move || {
+            let _guard = rwlock.write().unwrap();
+            locked.send(()).unwrap();
+            on_panic.recv().unwrap();
+            panic!("poisoning");
+        }
+    });
+    on_locked.recv().unwrap();
+    let waiter = std::thread::spawn({
+        let rwlock = rwlock.clone();
+        move || rwlock.write().is_err()
+    });
+    // Locks register when a thread first waits for them.
+    let start = Instant::now();
+    while rwlock.key().is_none() {
+        assert!(start.elapsed() < Duration::from_secs(5));
+        std::thread::sleep(Duration::from_millis(1));
+    }
+    panic.send(()).unwrap();
+    holder.join().unwrap_err();
+    assert!(
+        waiter.join().unwrap(),
+        "the waiter took the lock before it was poisoned"
+    );
+}
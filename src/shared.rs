@@ -0,0 +1,380 @@
+use std::fs::OpenOptions;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use memmap2::MmapMut;
+
+use crate::event_log::thread_number;
+use crate::graphs::Graph;
+
+const MAGIC: u64 = u64::from_le_bytes(*b"nodlck01");
+const SLOT_COUNT: usize = 1024;
+const HOLDS: u64 = 1;
+const WAITS: u64 = 2;
+/// How long to wait for the table's spinlock before checking whether its holder is still alive.
+const STALE_SPINLOCK_CHECK: Duration = Duration::from_millis(10);
+
+/// The mapped region: a header followed by a fixed table of hold/wait records.
+/// A zeroed region is a valid empty table, so a freshly created file needs no initialization.
+#[repr(C)]
+struct Table {
+    magic: AtomicU64,
+    /// Process id of the process currently editing the table, 0 when free.
+    spinlock: AtomicU64,
+    slots: [Slot; SLOT_COUNT],
+}
+
+/// `lock` is the lock id + 1, so that 0 marks an empty slot.
+#[repr(C)]
+struct Slot {
+    lock: AtomicU64,
+    process: AtomicU64,
+    thread: AtomicU64,
+    kind: AtomicU64,
+}
+
+/// A node of the cross-process dependency graph.
+#[derive(Eq, PartialEq, Copy, Clone, Debug, Hash)]
+pub enum SharedNode {
+    Thread { process: u64, thread: u64 },
+    Lock(u64),
+}
+
+/// Why a `SharedMutex` couldn't be acquired, see `SharedMutex::lock_checked`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum SharedLockError {
+    /// Every slot of the table is taken by live processes' holds and waits.
+    TableFull,
+    /// Waiting would never end, because of this dependence cycle.
+    Deadlock(Vec<SharedNode>),
+}
+
+impl std::fmt::Display for SharedLockError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SharedLockError::TableFull => write!(f, "the shared lock table is full"),
+            SharedLockError::Deadlock(cycle) => write!(
+                f,
+                "DEADLOCK DETECTED ACROSS PROCESSES! dependence cycle: {:?}",
+                cycle
+            ),
+        }
+    }
+}
+
+impl std::error::Error for SharedLockError {}
+
+/// A lock manager whose state lives in a memory-mapped file, so that several processes
+/// opening the same file can detect deadlocks spanning all of them.
+pub struct SharedLockManager {
+    map: MmapMut,
+    analysis_timeout: Duration,
+}
+
+impl SharedLockManager {
+    pub fn open<P: AsRef<Path>>(path: P) -> std::io::Result<Arc<Self>> {
+        Self::with_analysis_timeout(path, Duration::from_secs(1))
+    }
+
+    pub fn with_analysis_timeout<P: AsRef<Path>>(
+        path: P,
+        analysis_timeout: Duration,
+    ) -> std::io::Result<Arc<Self>> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?;
+        let size = std::mem::size_of::<Table>() as u64;
+        if file.metadata()?.len() < size {
+            file.set_len(size)?;
+        }
+        // Safety: the file is only ever accessed through the atomics of `Table`.
+        let map = unsafe { MmapMut::map_mut(&file)? };
+        let manager = SharedLockManager {
+            map,
+            analysis_timeout,
+        };
+        match manager
+            .table()
+            .magic
+            .compare_exchange(0, MAGIC, Ordering::AcqRel, Ordering::Acquire)
+        {
+            Ok(_) => {}
+            Err(MAGIC) => {}
+            Err(_) => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    "the file isn't a no_deadlocks shared lock table",
+                ))
+            }
+        }
+        Ok(Arc::new(manager))
+    }
+
+    fn table(&self) -> &Table {
+        // Safety: the map is page-aligned and at least `size_of::<Table>()` long.
+        unsafe { &*(self.map.as_ptr() as *const Table) }
+    }
+
+    /// Runs `f` with the table to itself. A spinlock left taken by a process that died is taken over.
+    fn with_table<R, F: FnOnce(&[Slot]) -> R>(&self, f: F) -> R {
+        /// Frees the spinlock even if `f` panics, which would otherwise block every process.
+        struct Unlock<'a>(&'a AtomicU64);
+        impl Drop for Unlock<'_> {
+            fn drop(&mut self) {
+                self.0.store(0, Ordering::Release);
+            }
+        }
+        let table = self.table();
+        let pid = std::process::id() as u64;
+        let start = Instant::now();
+        let mut checked = start;
+        loop {
+            match table
+                .spinlock
+                .compare_exchange_weak(0, pid, Ordering::Acquire, Ordering::Relaxed)
+            {
+                Ok(_) => break,
+                // Checking whether the holder lives is costly: only do it once in a while.
+                Err(holder) if holder != 0 && checked.elapsed() >= STALE_SPINLOCK_CHECK => {
+                    checked = Instant::now();
+                    if !process_alive(holder)
+                        && table
+                            .spinlock
+                            .compare_exchange(holder, pid, Ordering::Acquire, Ordering::Relaxed)
+                            .is_ok()
+                    {
+                        break;
+                    }
+                }
+                Err(_) => std::thread::yield_now(),
+            }
+        }
+        let _unlock = Unlock(&table.spinlock);
+        f(&table.slots)
+    }
+
+    fn acquire(&self, id: u64) -> Result<(), SharedLockError> {
+        let me = current_node();
+        let start = Instant::now();
+        loop {
+            let outcome = self.with_table(|slots| {
+                if slots.iter().any(|slot| slot.is(id, HOLDS)) {
+                    reclaim_dead(slots, |slot| slot.is(id, HOLDS));
+                }
+                if !slots.iter().any(|slot| slot.is(id, HOLDS)) {
+                    remove(slots, id, me, WAITS);
+                    insert(slots, id, me, HOLDS)?;
+                    return Ok(true);
+                }
+                if start.elapsed() < self.analysis_timeout {
+                    return Ok(false);
+                }
+                if !slots.iter().any(|slot| slot.is_from(id, me, WAITS)) {
+                    insert(slots, id, me, WAITS)?;
+                }
+                match find_cycle(slots, me) {
+                    Some(cycle) => {
+                        remove(slots, id, me, WAITS);
+                        Err(SharedLockError::Deadlock(cycle))
+                    }
+                    None => Ok(false),
+                }
+            })?;
+            if outcome {
+                return Ok(());
+            }
+            std::thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    fn release(&self, id: u64) {
+        self.with_table(|slots| remove(slots, id, current_node(), HOLDS))
+    }
+}
+
+fn current_node() -> SharedNode {
+    SharedNode::Thread {
+        process: std::process::id() as u64,
+        thread: thread_number(std::thread::current().id()),
+    }
+}
+
+impl Slot {
+    fn is(&self, id: u64, kind: u64) -> bool {
+        self.lock.load(Ordering::Relaxed) == id + 1 && self.kind.load(Ordering::Relaxed) == kind
+    }
+
+    fn is_from(&self, id: u64, node: SharedNode, kind: u64) -> bool {
+        self.is(id, kind) && self.owner() == node
+    }
+
+    fn owner(&self) -> SharedNode {
+        SharedNode::Thread {
+            process: self.process.load(Ordering::Relaxed),
+            thread: self.thread.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Whether the process `pid` still runs. Only Linux can tell: elsewhere, every process is assumed alive.
+fn process_alive(pid: u64) -> bool {
+    if cfg!(target_os = "linux") {
+        Path::new(&format!("/proc/{}", pid)).exists()
+    } else {
+        true
+    }
+}
+
+/// Frees the used slots matching `filter` whose process died without releasing them.
+fn reclaim_dead<F: Fn(&Slot) -> bool>(slots: &[Slot], filter: F) {
+    let pid = std::process::id() as u64;
+    for slot in slots {
+        if slot.lock.load(Ordering::Relaxed) == 0 || !filter(slot) {
+            continue;
+        }
+        let process = slot.process.load(Ordering::Relaxed);
+        if process != pid && !process_alive(process) {
+            slot.lock.store(0, Ordering::Relaxed);
+        }
+    }
+}
+
+fn insert(slots: &[Slot], id: u64, node: SharedNode, kind: u64) -> Result<(), SharedLockError> {
+    let free = || {
+        slots
+            .iter()
+            .find(|slot| slot.lock.load(Ordering::Relaxed) == 0)
+    };
+    let slot = match free() {
+        Some(slot) => slot,
+        None => {
+            reclaim_dead(slots, |_| true);
+            free().ok_or(SharedLockError::TableFull)?
+        }
+    };
+    if let SharedNode::Thread { process, thread } = node {
+        slot.process.store(process, Ordering::Relaxed);
+        slot.thread.store(thread, Ordering::Relaxed);
+    }
+    slot.kind.store(kind, Ordering::Relaxed);
+    slot.lock.store(id + 1, Ordering::Relaxed);
+    Ok(())
+}
+
+fn remove(slots: &[Slot], id: u64, node: SharedNode, kind: u64) {
+    if let Some(slot) = slots.iter().find(|slot| slot.is_from(id, node, kind)) {
+        slot.lock.store(0, Ordering::Relaxed);
+    }
+}
+
+fn find_cycle(slots: &[Slot], me: SharedNode) -> Option<Vec<SharedNode>> {
+    let mut graph = Graph::new();
+    for slot in slots {
+        let lock = slot.lock.load(Ordering::Relaxed);
+        if lock == 0 {
+            continue;
+        }
+        let lock = SharedNode::Lock(lock - 1);
+        if slot.kind.load(Ordering::Relaxed) == HOLDS {
+            graph.add_edge_and_nodes(lock, slot.owner());
+        } else {
+            graph.add_edge_and_nodes(slot.owner(), lock);
+        }
+    }
+    graph
+        .find_loop()
+        .filter(|cycle| cycle.contains(&&me))
+        .map(|cycle| cycle.into_iter().cloned().collect())
+}
+
+/// A mutual exclusion lock shared by every process using the same `SharedLockManager`, identified by `id`.
+/// It protects no data of its own: use it to guard shared resources such as files or shared memory.
+pub struct SharedMutex {
+    manager: Arc<SharedLockManager>,
+    id: u64,
+}
+
+impl SharedMutex {
+    pub fn new(manager: Arc<SharedLockManager>, id: u64) -> Self {
+        SharedMutex { manager, id }
+    }
+
+    /// Blocks until the lock is acquired, panicking if a dependence cycle, even across processes, prevents it,
+    /// or if the table has no room left to record the acquisition.
+    pub fn lock(&self) -> SharedMutexGuard<'_> {
+        match self.lock_checked() {
+            Ok(guard) => guard,
+            Err(error) => panic!("{}", error),
+        }
+    }
+
+    /// Like `lock`, but returns deadlocks and a full table instead of panicking.
+    pub fn lock_checked(&self) -> Result<SharedMutexGuard<'_>, SharedLockError> {
+        self.manager.acquire(self.id)?;
+        Ok(SharedMutexGuard { mutex: self })
+    }
+}
+
+pub struct SharedMutexGuard<'l> {
+    mutex: &'l SharedMutex,
+}
+
+impl<'l> Drop for SharedMutexGuard<'l> {
+    fn drop(&mut self) {
+        self.mutex.manager.release(self.mutex.id)
+    }
+}
+
+#[test]
+fn full_table_errors_without_blocking() {
+    let path = std::env::temp_dir().join(format!("no_deadlocks_full_{}", std::process::id()));
+    let manager = SharedLockManager::open(&path).unwrap();
+    let mutexes: Vec<_> = (0..SLOT_COUNT as u64 + 1)
+        .map(|id| SharedMutex::new(manager.clone(), id))
+        .collect();
+    let mut guards: Vec<_> = mutexes[..SLOT_COUNT]
+        .iter()
+        .map(|mutex| mutex.lock())
+        .collect();
+    assert_eq!(
+        mutexes[SLOT_COUNT].lock_checked().err(),
+        Some(SharedLockError::TableFull)
+    );
+    // The spinlock was released: the table is still usable once a slot frees up.
+    guards.pop();
+    std::mem::drop(mutexes[SLOT_COUNT].lock());
+    std::mem::drop(guards);
+    let _ = std::fs::remove_file(path);
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn dead_processes_are_reclaimed() {
+    let path = std::env::temp_dir().join(format!("no_deadlocks_dead_{}", std::process::id()));
+    let manager = SharedLockManager::open(&path).unwrap();
+    // Running no test, the child exits right away: its pid is then that of a dead process.
+    let mut child = std::process::Command::new(std::env::current_exe().unwrap())
+        .args(["--exact", "no_such_test"])
+        .stdout(std::process::Stdio::null())
+        .spawn()
+        .unwrap();
+    child.wait().unwrap();
+    let dead = child.id() as u64;
+    // A process died holding lock 7, and another while editing the table.
+    let slot = &manager.table().slots[0];
+    slot.process.store(dead, Ordering::Relaxed);
+    slot.thread.store(1, Ordering::Relaxed);
+    slot.kind.store(HOLDS, Ordering::Relaxed);
+    slot.lock.store(7 + 1, Ordering::Relaxed);
+    manager.table().spinlock.store(dead, Ordering::Relaxed);
+    let mutex = SharedMutex::new(manager.clone(), 7);
+    let guard = mutex.lock_checked().unwrap();
+    assert_eq!(manager.table().spinlock.load(Ordering::Relaxed), 0);
+    std::mem::drop(guard);
+    let _ = std::fs::remove_file(path);
+}
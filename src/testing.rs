@@ -0,0 +1,76 @@
+//! Helpers for asserting on deadlock reports in tests.
+
+use std::cell::RefCell;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::Once;
+
+thread_local! {
+    /// `Some` while the current thread runs `capture_deadlock_report`, holding the report once written.
+    static CAPTURED: RefCell<Option<Option<String>>> = const { RefCell::new(None) };
+}
+
+static HOOK: Once = Once::new();
+
+/// Runs `f`, catching the panic of a deadlock it causes, and returns the report that would have been written.
+/// Only deadlocks detected by the calling thread, such as reentrances, are captured.
+/// Panics that don't follow a report are resumed.
+///
+/// ```
+/// let mutex = no_deadlocks::Mutex::new(());
+/// let _guard = mutex.lock();
+/// let report = no_deadlocks::testing::capture_deadlock_report(|| {
+///     let _reentrance = mutex.lock();
+/// });
+/// assert!(report.unwrap().contains("Reentrace at:"));
+/// ```
+pub fn capture_deadlock_report<F: FnOnce()>(f: F) -> Option<String> {
+    HOOK.call_once(|| {
+        let previous = panic::take_hook();
+        panic::set_hook(Box::new(move |info| {
+            // The report was captured: the panic that follows it is expected.
+            let expected = CAPTURED.with(|captured| matches!(&*captured.borrow(), Some(Some(_))));
+            if !expected {
+                previous(info)
+            }
+        }));
+    });
+    let outer = CAPTURED.with(|captured| captured.replace(Some(None)));
+    let result = panic::catch_unwind(AssertUnwindSafe(f));
+    let report = CAPTURED.with(|captured| captured.replace(outer)).flatten();
+    match result {
+        Err(payload) if report.is_none() => panic::resume_unwind(payload),
+        _ => report,
+    }
+}
+
+/// Whether reports written by the current thread should go to `capture`.
+pub(crate) fn capturing() -> bool {
+    CAPTURED.with(|captured| captured.borrow().is_some())
+}
+
+pub(crate) fn capture(report: String) {
+    CAPTURED.with(|captured| {
+        if let Some(captured) = &mut *captured.borrow_mut() {
+            captured.get_or_insert(report);
+        }
+    })
+}
+
+#[test]
+fn captured_reentrance_report() {
+    use crate::lock_manager::LockManager;
+    use crate::Mutex;
+    use std::sync::Arc;
+    let manager = Arc::new(LockManager::new());
+    let mutex = Mutex::with_manager(manager, ());
+    let _guard = mutex.lock();
+    let report = capture_deadlock_report(|| {
+        let _reentrance = mutex.lock();
+    })
+    .unwrap();
+    assert!(report.contains("A reentrance has been attempted"));
+    assert!(report.contains("Lock taken at:"));
+    assert!(report.contains("Reentrace at:"));
+    assert!(capture_deadlock_report(|| {}).is_none());
+    assert!(!capturing());
+}
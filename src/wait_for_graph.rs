@@ -0,0 +1,244 @@
+use crate::lock_manager::{DependencyNode, RequestType, WaitReason};
+
+/// A node of a `WaitForGraph`, with the name of the lock or thread it stands for, if known.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct WaitForNode {
+    pub node: DependencyNode,
+    pub name: Option<String>,
+}
+
+/// An edge of a `WaitForGraph`: either a lock held by a thread (`Lock -> Thread`),
+/// or a thread waiting for a lock (`Thread -> Lock`), which also tells why it waits.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+pub struct WaitForEdge {
+    pub from: DependencyNode,
+    pub to: DependencyNode,
+    pub request: RequestType,
+    pub reason: Option<WaitReason>,
+}
+
+/// A snapshot of the dependency graph, see `LockManager::snapshot_graph`.
+#[derive(Clone, Debug, Default)]
+pub struct WaitForGraph {
+    pub nodes: Vec<WaitForNode>,
+    pub edges: Vec<WaitForEdge>,
+}
+
+/// The changes between two `WaitForGraph`s, see `WaitForGraph::diff`.
+#[derive(Clone, Debug, Default)]
+pub struct GraphDiff {
+    pub added_nodes: Vec<WaitForNode>,
+    pub removed_nodes: Vec<WaitForNode>,
+    pub added_edges: Vec<WaitForEdge>,
+    pub removed_edges: Vec<WaitForEdge>,
+}
+
+impl GraphDiff {
+    pub fn is_empty(&self) -> bool {
+        self.added_nodes.is_empty()
+            && self.removed_nodes.is_empty()
+            && self.added_edges.is_empty()
+            && self.removed_edges.is_empty()
+    }
+}
+
+impl WaitForGraph {
+    /// What changed from `self` to the later snapshot `other`. Nodes are compared regardless of their names.
+    pub fn diff(&self, other: &WaitForGraph) -> GraphDiff {
+        let missing_nodes = |from: &WaitForGraph, to: &WaitForGraph| -> Vec<WaitForNode> {
+            from.nodes
+                .iter()
+                .filter(|node| !to.nodes.iter().any(|known| known.node == node.node))
+                .cloned()
+                .collect()
+        };
+        let missing_edges = |from: &WaitForGraph, to: &WaitForGraph| -> Vec<WaitForEdge> {
+            from.edges
+                .iter()
+                .filter(|edge| !to.edges.contains(edge))
+                .cloned()
+                .collect()
+        };
+        GraphDiff {
+            added_nodes: missing_nodes(other, self),
+            removed_nodes: missing_nodes(self, other),
+            added_edges: missing_edges(other, self),
+            removed_edges: missing_edges(self, other),
+        }
+    }
+
+    pub(crate) fn add_node(&mut self, node: DependencyNode, name: Option<String>) {
+        match self.nodes.iter_mut().find(|known| known.node == node) {
+            Some(known) => {
+                if known.name.is_none() {
+                    known.name = name;
+                }
+            }
+            None => self.nodes.push(WaitForNode { node, name }),
+        }
+    }
+
+    pub(crate) fn add_edge(
+        &mut self,
+        from: DependencyNode,
+        to: DependencyNode,
+        request: RequestType,
+    ) {
+        self.add_node(from, None);
+        self.add_node(to, None);
+        self.edges.push(WaitForEdge {
+            from,
+            to,
+            request,
+            reason: None,
+        });
+    }
+
+    pub(crate) fn add_wait(
+        &mut self,
+        thread: DependencyNode,
+        lock: DependencyNode,
+        request: RequestType,
+        reason: WaitReason,
+    ) {
+        self.add_edge(thread, lock, request);
+        if let Some(edge) = self.edges.last_mut() {
+            edge.reason = Some(reason);
+        }
+    }
+
+    /// Exports the graph in the JSON Graph Format, readable by Cytoscape, D3 or Gephi.
+    #[cfg(feature = "serde")]
+    pub fn to_jgf(&self) -> serde_json::Value {
+        use serde_json::json;
+        let id = |node: &DependencyNode| match node {
+            DependencyNode::Thread(thread) => {
+                format!("thread-{}", crate::event_log::thread_number(*thread))
+            }
+            DependencyNode::Lock(key) => format!("lock-{}", key),
+        };
+        let nodes: Vec<_> = self
+            .nodes
+            .iter()
+            .map(|node| {
+                json!({
+                    "id": id(&node.node),
+                    "label": node.name.clone().unwrap_or_else(|| node.node.to_string()),
+                    "metadata": {
+                        "type": match node.node {
+                            DependencyNode::Thread(_) => "thread",
+                            DependencyNode::Lock(_) => "lock",
+                        },
+                        "name": node.name,
+                    },
+                })
+            })
+            .collect();
+        let edges: Vec<_> = self
+            .edges
+            .iter()
+            .map(|edge| {
+                json!({
+                    "source": id(&edge.from),
+                    "target": id(&edge.to),
+                    "relation": match edge.from {
+                        DependencyNode::Lock(_) => "held-by",
+                        DependencyNode::Thread(_) => "waits-for",
+                    },
+                    "metadata": {
+                        "request": edge.request.to_string(),
+                        "reason": edge.reason.map(|reason| reason.to_string()),
+                    },
+                })
+            })
+            .collect();
+        json!({
+            "graph": {
+                "directed": true,
+                "type": "wait-for",
+                "nodes": nodes,
+                "edges": edges,
+            }
+        })
+    }
+}
+
+#[test]
+fn snapshot_diff() {
+    use crate::lock_manager::LockManager;
+    use crate::Mutex;
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+    let manager = Arc::new(LockManager::new());
+    let mutex = Arc::new(Mutex::with_manager(manager.clone(), ()));
+    mutex.set_analysis_timeout(Some(Duration::from_secs(0)));
+    let guard = mutex.lock();
+    let before = manager.snapshot_graph();
+    let waiter = std::thread::spawn({
+        let mutex = mutex.clone();
+        move || std::mem::drop(mutex.lock())
+    });
+    let start = Instant::now();
+    let mut after = manager.snapshot_graph();
+    while after.edges.len() < 2 {
+        assert!(start.elapsed() < Duration::from_secs(5));
+        std::thread::sleep(Duration::from_millis(1));
+        after = manager.snapshot_graph();
+    }
+    let waiter_id = waiter.thread().id();
+    std::mem::drop(guard);
+    waiter.join().unwrap();
+    let diff = before.diff(&after);
+    let lock = DependencyNode::Lock(mutex.key().unwrap());
+    // The lock is only registered once contended, so the edge to its holder is new too.
+    assert_eq!(diff.added_edges.len(), 2);
+    assert!(diff.added_edges.contains(&WaitForEdge {
+        from: DependencyNode::Thread(waiter_id),
+        to: lock,
+        request: RequestType::Write,
+        reason: Some(WaitReason::WaitingForWriter),
+    }));
+    assert!(diff.removed_edges.is_empty());
+    assert!(diff
+        .added_nodes
+        .iter()
+        .any(|node| node.node == DependencyNode::Thread(waiter_id)));
+    assert!(after.diff(&after).is_empty());
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn jgf_export() {
+    use crate::lock_manager::LockManager;
+    use crate::Mutex;
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+    let manager = Arc::new(LockManager::with_analysis_timeout(Duration::from_millis(
+        10,
+    )));
+    let mutex = Arc::new(Mutex::with_manager(manager.clone(), ()));
+    mutex.set_name("state");
+    let guard = mutex.lock();
+    let waiter = std::thread::spawn({
+        let mutex = mutex.clone();
+        move || std::mem::drop(mutex.lock())
+    });
+    let start = Instant::now();
+    let mut graph = manager.snapshot_graph();
+    while graph.edges.len() < 2 {
+        assert!(start.elapsed() < Duration::from_secs(5));
+        std::thread::sleep(Duration::from_millis(5));
+        graph = manager.snapshot_graph();
+    }
+    let jgf = graph.to_jgf();
+    std::mem::drop(guard);
+    waiter.join().unwrap();
+    let nodes = jgf["graph"]["nodes"].as_array().unwrap();
+    let edges = jgf["graph"]["edges"].as_array().unwrap();
+    assert_eq!(nodes.len(), 3);
+    assert_eq!(edges.len(), 2);
+    assert!(nodes.iter().any(|node| node["label"] == "state"));
+    assert!(edges
+        .iter()
+        .all(|edge| edge["metadata"]["request"] == "write"));
+}
@@ -0,0 +1,29 @@
+use std::sync::{Arc, Barrier};
+
+use no_deadlocks::lock_manager::LockManager;
+
+/// Runs in its own test binary, so that the global manager is still uninitialized when the threads race.
+#[test]
+fn racing_global_initialization() {
+    let barrier = Arc::new(Barrier::new(32));
+    let threads: Vec<_> = (0..32)
+        .map(|_| {
+            let barrier = barrier.clone();
+            std::thread::spawn(move || {
+                barrier.wait();
+                let manager = LockManager::get_global_manager();
+                assert_eq!(manager.analysis_timeout().as_secs(), 1);
+                manager
+            })
+        })
+        .collect();
+    let managers: Vec<_> = threads
+        .into_iter()
+        .map(|thread| thread.join().unwrap())
+        .collect();
+    assert!(managers
+        .iter()
+        .all(|manager| Arc::ptr_eq(manager, &managers[0])));
+    // The global manager, plus one reference per thread.
+    assert_eq!(Arc::strong_count(&managers[0]), 33);
+}
@@ -0,0 +1,34 @@
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use no_deadlocks::lock_manager::LockManager;
+use no_deadlocks::{RequestType, RwLock};
+
+/// Checks that queries can be used from outside the crate, without reaching for its internals.
+#[test]
+fn matching_request_types() {
+    let manager = Arc::new(LockManager::with_analysis_timeout(Duration::from_millis(
+        10,
+    )));
+    let rwlock = Arc::new(RwLock::with_manager(manager.clone(), ()));
+    let guard = rwlock.write();
+    let reader = std::thread::spawn({
+        let rwlock = rwlock.clone();
+        move || std::mem::drop(rwlock.read())
+    });
+    let start = Instant::now();
+    let mut blocked = manager.blocked_threads();
+    while blocked.is_empty() {
+        assert!(start.elapsed() < Duration::from_secs(5));
+        std::thread::sleep(Duration::from_millis(10));
+        blocked = manager.blocked_threads();
+    }
+    let description = match blocked[0].request {
+        RequestType::Read => format!("waiting to {}", blocked[0].request),
+        RequestType::Write => panic!("the reader requested write rights"),
+    };
+    assert_eq!(description, "waiting to read");
+    assert_eq!(RequestType::Write.to_string(), "write");
+    std::mem::drop(guard);
+    reader.join().unwrap();
+}
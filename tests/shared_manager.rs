@@ -0,0 +1,72 @@
+#![cfg(feature = "shared_memory")]
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+use std::time::{Duration, Instant};
+
+use no_deadlocks::{SharedLockManager, SharedMutex};
+
+const ROLE: &str = "NO_DEADLOCKS_SHARED_ROLE";
+const TABLE: &str = "NO_DEADLOCKS_SHARED_TABLE";
+
+fn ready_marker(table: &str, role: u64) -> PathBuf {
+    PathBuf::from(format!("{}.ready{}", table, role))
+}
+
+/// Only does something when spawned by `two_process_deadlock`.
+#[test]
+fn shared_child() {
+    let (role, table) = match (std::env::var(ROLE), std::env::var(TABLE)) {
+        (Ok(role), Ok(table)) => (role.parse::<u64>().unwrap(), table),
+        _ => return,
+    };
+    let manager =
+        SharedLockManager::with_analysis_timeout(&table, Duration::from_millis(100)).unwrap();
+    let first = SharedMutex::new(manager.clone(), role);
+    let second = SharedMutex::new(manager, 1 - role);
+    let _first = first.lock();
+    std::fs::write(ready_marker(&table, role), b"").unwrap();
+    let start = Instant::now();
+    while !ready_marker(&table, 1 - role).exists() {
+        assert!(start.elapsed() < Duration::from_secs(10));
+        std::thread::sleep(Duration::from_millis(1));
+    }
+    let _second = second.lock();
+}
+
+#[test]
+fn two_process_deadlock() {
+    let table = std::env::temp_dir().join(format!("no_deadlocks_shared_{}", std::process::id()));
+    let table = table.to_str().unwrap().to_owned();
+    let children: Vec<_> = (0..2)
+        .map(|role| {
+            Command::new(std::env::current_exe().unwrap())
+                .args(["shared_child", "--exact", "--nocapture"])
+                .env(ROLE, role.to_string())
+                .env(TABLE, &table)
+                .stdout(Stdio::piped())
+                .stderr(Stdio::piped())
+                .spawn()
+                .unwrap()
+        })
+        .collect();
+    let outputs: Vec<_> = children
+        .into_iter()
+        .map(|child| child.wait_with_output().unwrap())
+        .collect();
+    for path in [
+        PathBuf::from(&table),
+        ready_marker(&table, 0),
+        ready_marker(&table, 1),
+    ]
+    .iter()
+    {
+        let _ = std::fs::remove_file(path);
+    }
+    let failures: Vec<_> = outputs
+        .iter()
+        .filter(|output| !output.status.success())
+        .collect();
+    assert_eq!(failures.len(), 1);
+    assert!(String::from_utf8_lossy(&failures[0].stderr)
+        .contains("DEADLOCK DETECTED ACROSS PROCESSES! dependence cycle: "));
+}
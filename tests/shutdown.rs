@@ -0,0 +1,49 @@
+use std::sync::Arc;
+
+use no_deadlocks::lock_manager::LockManager;
+use no_deadlocks::{shutdown, Mutex, RwLock};
+
+/// Runs in its own test binary, so that no other test's locks keep the global manager alive.
+#[test]
+fn shutdown_frees_the_global_manager() {
+    assert!(shutdown().is_ok());
+    let mutex = Mutex::new(0);
+    let rwlock = RwLock::new(());
+    *mutex.lock().unwrap() += 1;
+    mutex.set_name("counter");
+    let manager = Arc::downgrade(&LockManager::get_global_manager());
+    let error = shutdown().unwrap_err();
+    assert_eq!(error.references, 2);
+    assert!(manager.upgrade().is_some());
+    std::mem::drop((mutex, rwlock));
+    assert!(shutdown().is_ok());
+    assert!(manager.upgrade().is_none());
+    assert!(shutdown().is_ok());
+    // A later lock sets up a new global manager, referenced by the lock and the global pointer only.
+    let mutex = Mutex::new(());
+    std::mem::drop(mutex.lock());
+    assert_eq!(Arc::strong_count(&LockManager::get_global_manager()), 3);
+    // Refused shutdowns racing with new locks leave every lock on the same manager.
+    let manager = LockManager::get_global_manager();
+    let threads: Vec<_> = (0..4)
+        .map(|_| {
+            std::thread::spawn(|| {
+                (0..1000)
+                    .map(|_| LockManager::get_global_manager())
+                    .collect::<Vec<_>>()
+            })
+        })
+        .collect();
+    for _ in 0..1000 {
+        assert!(shutdown().is_err());
+    }
+    for thread in threads {
+        assert!(thread
+            .join()
+            .unwrap()
+            .iter()
+            .all(|other| Arc::ptr_eq(other, &manager)));
+    }
+    std::mem::drop((mutex, manager));
+    assert!(shutdown().is_ok());
+}